@@ -0,0 +1 @@
+ALTER TABLE releases ADD COLUMN gapless BOOL NOT NULL DEFAULT 0;
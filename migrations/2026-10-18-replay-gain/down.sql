@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tracks DROP COLUMN replay_gain;
+ALTER TABLE tracks DROP COLUMN replay_peak;
+ALTER TABLE releases DROP COLUMN replay_gain;
+ALTER TABLE releases DROP COLUMN replay_peak;
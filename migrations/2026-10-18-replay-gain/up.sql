@@ -0,0 +1,4 @@
+ALTER TABLE tracks ADD COLUMN replay_gain FLOAT;
+ALTER TABLE tracks ADD COLUMN replay_peak FLOAT;
+ALTER TABLE releases ADD COLUMN replay_gain FLOAT;
+ALTER TABLE releases ADD COLUMN replay_peak FLOAT;
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE tracks DROP COLUMN loudness;
+ALTER TABLE tracks DROP COLUMN loudness_range;
+ALTER TABLE tracks DROP COLUMN true_peak;
+ALTER TABLE releases DROP COLUMN loudness;
+ALTER TABLE releases DROP COLUMN loudness_range;
+ALTER TABLE releases DROP COLUMN true_peak;
@@ -0,0 +1,6 @@
+ALTER TABLE tracks ADD COLUMN loudness FLOAT;
+ALTER TABLE tracks ADD COLUMN loudness_range FLOAT;
+ALTER TABLE tracks ADD COLUMN true_peak FLOAT;
+ALTER TABLE releases ADD COLUMN loudness FLOAT;
+ALTER TABLE releases ADD COLUMN loudness_range FLOAT;
+ALTER TABLE releases ADD COLUMN true_peak FLOAT;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE saved_queue_items;
+DROP TABLE saved_queue;
+DROP TABLE saved_queue_options;
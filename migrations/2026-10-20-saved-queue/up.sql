@@ -0,0 +1,41 @@
+CREATE TABLE saved_queue_options(
+    id INTEGER NOT NULL PRIMARY KEY,
+    shuffle_type INTEGER NOT NULL,
+    weights TEXT NOT NULL,
+    default_weights TEXT NOT NULL,
+    stop_condition INTEGER NOT NULL,
+    stop_value BIGINT NOT NULL,
+    selected INTEGER,
+    progress_tracks INTEGER NOT NULL,
+    progress_time BIGINT NOT NULL,
+    progress_picked INTEGER NOT NULL
+);
+
+CREATE TABLE saved_queue(
+    id INTEGER NOT NULL PRIMARY KEY,
+    options_id INTEGER NOT NULL,
+    repeat_current BOOL NOT NULL,
+    max_history INTEGER NOT NULL,
+    next_id BIGINT NOT NULL,
+    current_track TEXT,
+    position BIGINT NOT NULL,
+    FOREIGN KEY (options_id) REFERENCES saved_queue_options(id)
+);
+
+CREATE TABLE saved_queue_items(
+    id INTEGER NOT NULL PRIMARY KEY,
+    list INTEGER NOT NULL,
+    parent_id INTEGER,
+    number INTEGER NOT NULL,
+    item_type INTEGER NOT NULL,
+    queue_id BIGINT NOT NULL,
+    track_id INTEGER,
+    release_id INTEGER,
+    playlist_id INTEGER,
+    options_id INTEGER,
+    FOREIGN KEY (parent_id) REFERENCES saved_queue_items(id),
+    FOREIGN KEY (track_id) REFERENCES tracks(id),
+    FOREIGN KEY (release_id) REFERENCES releases(id),
+    FOREIGN KEY (playlist_id) REFERENCES playlists(id),
+    FOREIGN KEY (options_id) REFERENCES saved_queue_options(id)
+);
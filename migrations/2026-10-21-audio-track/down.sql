@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE track_locations_old(
+    path TEXT NOT NULL PRIMARY KEY,
+    track_id INTEGER NOT NULL,
+    FOREIGN KEY (track_id) REFERENCES tracks(id)
+);
+INSERT INTO track_locations_old(path, track_id)
+    SELECT path, track_id FROM track_locations WHERE audio_track = 0;
+DROP TABLE track_locations;
+ALTER TABLE track_locations_old RENAME TO track_locations;
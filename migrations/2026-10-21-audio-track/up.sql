@@ -0,0 +1,11 @@
+CREATE TABLE track_locations_new(
+    path TEXT NOT NULL,
+    audio_track INTEGER NOT NULL DEFAULT 0,
+    track_id INTEGER NOT NULL,
+    PRIMARY KEY (path, audio_track),
+    FOREIGN KEY (track_id) REFERENCES tracks(id)
+);
+INSERT INTO track_locations_new(path, audio_track, track_id)
+    SELECT path, 0, track_id FROM track_locations;
+DROP TABLE track_locations;
+ALTER TABLE track_locations_new RENAME TO track_locations;
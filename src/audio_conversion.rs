@@ -1,3 +1,5 @@
+use cpal::{FromSample, SizedSample};
+
 use crate::BuF;
 
 pub fn planar_to_interleaved(input: &[Vec<BuF>], output: &mut [BuF], channels: usize) {
@@ -15,3 +17,446 @@ pub fn interleaved_to_planar(input: &[BuF], output: &mut [Vec<BuF>], channels: u
         }
     }
 }
+
+/// Gain used when folding a centre or surround channel into the front channels
+const FOLD_GAIN: BuF = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Mixes interleaved audio from one channel layout to another.
+///
+/// Channels are expected in the WAV/SMPTE order (FL, FR, FC, LFE, BL, BR, SL, SR),
+/// which is also what symphonia gives us
+pub struct ChannelMixer {
+    channels_input: usize,
+    channels_output: usize,
+    /// Row major, one row of `channels_input` gains per output channel
+    matrix: Vec<BuF>,
+}
+
+impl ChannelMixer {
+    pub fn new(channels_input: usize, channels_output: usize) -> ChannelMixer {
+        ChannelMixer {
+            channels_input,
+            channels_output,
+            matrix: mix_matrix(channels_input, channels_output),
+        }
+    }
+
+    pub fn channels_input(&self) -> usize {
+        self.channels_input
+    }
+
+    pub fn channels_output(&self) -> usize {
+        self.channels_output
+    }
+
+    /// If the mixer would just copy the input
+    pub fn is_identity(&self) -> bool {
+        self.channels_input == self.channels_output
+    }
+
+    /// Mix the input into the output,
+    /// the output should be big enough to hold the same amount of frames as the input
+    pub fn mix(&self, input: &[BuF], output: &mut [BuF]) {
+        if self.channels_input == 0 {
+            return;
+        }
+        if self.is_identity() {
+            output[..input.len()].copy_from_slice(input);
+            return;
+        }
+        for (frame_in, frame_out) in input
+            .chunks_exact(self.channels_input)
+            .zip(output.chunks_exact_mut(self.channels_output))
+        {
+            for (sample, row) in frame_out
+                .iter_mut()
+                .zip(self.matrix.chunks_exact(self.channels_input))
+            {
+                *sample = frame_in.iter().zip(row).map(|(s, g)| s * g).sum();
+            }
+        }
+    }
+}
+
+/// Create the gains to go from one layout to the other
+fn mix_matrix(channels_input: usize, channels_output: usize) -> Vec<BuF> {
+    let mut matrix = vec![0.0; channels_input * channels_output];
+    if channels_input == 0 || channels_output == 0 {
+        return matrix;
+    }
+    let mut set = |output: usize, input: usize, gain: BuF| {
+        matrix[output * channels_input + input] = gain;
+    };
+    match (channels_input, channels_output) {
+        (i, o) if i == o => (0..i).for_each(|c| set(c, c, 1.0)),
+        // Mono goes to the front channels
+        (1, _) => {
+            set(0, 0, 1.0);
+            set(1, 0, 1.0);
+        }
+        // Everything to mono, average of the stereo down mix
+        (i, 1) => {
+            let stereo = stereo_down_mix(i);
+            for (c, (l, r)) in stereo.iter().enumerate() {
+                set(0, c, (l + r) / 2.0);
+            }
+        }
+        // Keep the front channels, don't invent surround channels
+        (2, _) => {
+            set(0, 0, 1.0);
+            set(1, 1, 1.0);
+        }
+        (i, 2) => {
+            let stereo = stereo_down_mix(i);
+            for (c, (l, r)) in stereo.iter().enumerate() {
+                set(0, c, *l);
+                set(1, c, *r);
+            }
+        }
+        (i, o) => match (layout(i), layout(o)) {
+            (Some(input), Some(output)) => {
+                // Keep the channels that both layouts have,
+                // fold the rest into the nearest channels the output has
+                for (c, role) in input.iter().enumerate() {
+                    let same = [(*role, 1.0)];
+                    let targets = std::iter::once(&same[..])
+                        .chain(role.fold_targets().iter().copied())
+                        .find(|targets| targets.iter().all(|(x, _)| output.contains(x)))
+                        .unwrap_or_default();
+                    for (target, gain) in targets {
+                        let o = output.iter().position(|x| x == target).unwrap_or_default();
+                        matrix[o * i + c] += gain;
+                    }
+                }
+                // Lower everything the same amount when a channel would clip
+                let norm = matrix
+                    .chunks_exact(i)
+                    .map(|row| row.iter().sum::<BuF>())
+                    .fold(1.0, BuF::max);
+                matrix.iter_mut().for_each(|x| *x /= norm);
+            }
+            _ => {
+                // Unknown layout, keep the channels that both have,
+                // fold the rest into the front channels
+                let stereo = stereo_down_mix(i);
+                for (c, (l, r)) in stereo.iter().enumerate() {
+                    if c < o {
+                        set(c, c, 1.0);
+                    } else {
+                        set(0, c, *l);
+                        set(1, c, *r);
+                    }
+                }
+            }
+        },
+    }
+    matrix
+}
+
+/// Speaker position of a channel
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Role {
+    FrontLeft,
+    FrontRight,
+    Centre,
+    Lfe,
+    BackLeft,
+    BackRight,
+    BackCentre,
+    SideLeft,
+    SideRight,
+}
+
+impl Role {
+    /// Where the channel goes when the output does not have it,
+    /// the first group of which the output has every channel is used
+    fn fold_targets(self) -> &'static [&'static [(Role, BuF)]] {
+        use Role::*;
+        match self {
+            // Never missing from a layout with more than one channel
+            FrontLeft | FrontRight => &[],
+            Centre => &[&[(FrontLeft, FOLD_GAIN), (FrontRight, FOLD_GAIN)]],
+            // The output can't play it on its own, so it is dropped like in the stereo down mix
+            Lfe => &[],
+            BackLeft => &[
+                &[(SideLeft, 1.0)],
+                &[(BackCentre, FOLD_GAIN)],
+                &[(FrontLeft, FOLD_GAIN)],
+            ],
+            BackRight => &[
+                &[(SideRight, 1.0)],
+                &[(BackCentre, FOLD_GAIN)],
+                &[(FrontRight, FOLD_GAIN)],
+            ],
+            SideLeft => &[&[(BackLeft, 1.0)], &[(FrontLeft, FOLD_GAIN)]],
+            SideRight => &[&[(BackRight, 1.0)], &[(FrontRight, FOLD_GAIN)]],
+            BackCentre => &[
+                &[(BackLeft, FOLD_GAIN), (BackRight, FOLD_GAIN)],
+                &[(SideLeft, FOLD_GAIN), (SideRight, FOLD_GAIN)],
+                &[(FrontLeft, 0.5), (FrontRight, 0.5)],
+            ],
+        }
+    }
+}
+
+/// The position of every channel in the WAV/SMPTE order, `None` if the layout is unknown
+fn layout(channels: usize) -> Option<&'static [Role]> {
+    use Role::*;
+    let layout: &[Role] = match channels {
+        2 => &[FrontLeft, FrontRight],
+        3 => &[FrontLeft, FrontRight, Centre],
+        4 => &[FrontLeft, FrontRight, BackLeft, BackRight],
+        5 => &[FrontLeft, FrontRight, Centre, BackLeft, BackRight],
+        6 => &[FrontLeft, FrontRight, Centre, Lfe, BackLeft, BackRight],
+        7 => &[
+            FrontLeft, FrontRight, Centre, Lfe, BackCentre, SideLeft, SideRight,
+        ],
+        8 => &[
+            FrontLeft, FrontRight, Centre, Lfe, BackLeft, BackRight, SideLeft, SideRight,
+        ],
+        _ => return None,
+    };
+    Some(layout)
+}
+
+/// Returns the (left, right) gains for every input channel,
+/// normalised so the down mix does not clip
+fn stereo_down_mix(channels_input: usize) -> Vec<(BuF, BuF)> {
+    // WAV/SMPTE order, LFE is dropped
+    let layout: &[(BuF, BuF)] = match channels_input {
+        2 => &[(1.0, 0.0), (0.0, 1.0)],
+        // L R C
+        3 => &[(1.0, 0.0), (0.0, 1.0), (FOLD_GAIN, FOLD_GAIN)],
+        // L R BL BR
+        4 => &[(1.0, 0.0), (0.0, 1.0), (FOLD_GAIN, 0.0), (0.0, FOLD_GAIN)],
+        // L R C BL BR
+        5 => &[
+            (1.0, 0.0),
+            (0.0, 1.0),
+            (FOLD_GAIN, FOLD_GAIN),
+            (FOLD_GAIN, 0.0),
+            (0.0, FOLD_GAIN),
+        ],
+        // L R C LFE BL BR
+        6 => &[
+            (1.0, 0.0),
+            (0.0, 1.0),
+            (FOLD_GAIN, FOLD_GAIN),
+            (0.0, 0.0),
+            (FOLD_GAIN, 0.0),
+            (0.0, FOLD_GAIN),
+        ],
+        // L R C LFE BC SL SR
+        7 => &[
+            (1.0, 0.0),
+            (0.0, 1.0),
+            (FOLD_GAIN, FOLD_GAIN),
+            (0.0, 0.0),
+            (0.5, 0.5),
+            (FOLD_GAIN, 0.0),
+            (0.0, FOLD_GAIN),
+        ],
+        // L R C LFE BL BR SL SR
+        8 => &[
+            (1.0, 0.0),
+            (0.0, 1.0),
+            (FOLD_GAIN, FOLD_GAIN),
+            (0.0, 0.0),
+            (FOLD_GAIN, 0.0),
+            (0.0, FOLD_GAIN),
+            (FOLD_GAIN, 0.0),
+            (0.0, FOLD_GAIN),
+        ],
+        _ => &[],
+    };
+    let mut gains: Vec<(BuF, BuF)> = if layout.is_empty() {
+        // Unknown layout, alternate between left and right
+        (0..channels_input)
+            .map(|c| if c % 2 == 0 { (1.0, 0.0) } else { (0.0, 1.0) })
+            .collect()
+    } else {
+        layout.to_vec()
+    };
+    let sum_left: BuF = gains.iter().map(|(l, _)| l).sum();
+    let sum_right: BuF = gains.iter().map(|(_, r)| r).sum();
+    let norm = sum_left.max(sum_right).max(1.0);
+    gains.iter_mut().for_each(|(l, r)| {
+        *l /= norm;
+        *r /= norm;
+    });
+    gains
+}
+
+/// Integer formats with more bits than this hold every float sample, they are not dithered
+const MAX_DITHER_BITS: usize = 16;
+
+/// Converts float samples to the sample format of the output stream.
+///
+/// Integer formats of up to 16 bits get TPDF dither, with optional first order noise shaping.
+/// Converting never allocates
+pub struct OutputConverter {
+    channels: usize,
+    /// Without dither the samples are only rounded, samples of the output format stay the same
+    dither: bool,
+    noise_shaping: bool,
+    /// Quantisation error of the last sample of every channel, in steps of the output
+    errors: Vec<BuF>,
+    /// State of the xorshift generator of the dither
+    random: u32,
+}
+
+impl OutputConverter {
+    pub fn new(channels: usize) -> OutputConverter {
+        OutputConverter {
+            channels: channels.max(1),
+            dither: true,
+            noise_shaping: false,
+            errors: vec![0.0; channels.max(1)],
+            random: 0x9e37_79b9,
+        }
+    }
+
+    /// Turn the dither off for bit-perfect output, when the samples were not changed
+    pub fn set_dither(&mut self, dither: bool) {
+        self.dither = dither;
+    }
+
+    /// Move the quantisation noise to high frequencies, where it is heard less
+    pub fn set_noise_shaping(&mut self, noise_shaping: bool) {
+        self.noise_shaping = noise_shaping;
+    }
+
+    /// Convert the interleaved input into the output, which has the same length
+    pub fn convert<T: SizedSample + FromSample<BuF>>(&mut self, input: &[BuF], output: &mut [T]) {
+        let bits = T::FORMAT.sample_size() * 8;
+        if T::FORMAT.is_float() || bits > MAX_DITHER_BITS {
+            for (sample, out) in input.iter().zip(output.iter_mut()) {
+                *out = T::from_sample(*sample);
+            }
+            return;
+        }
+        // Steps of the output per unit of the input, a power of two so the result is exact
+        let scale = (1u32 << (bits - 1)) as BuF;
+        for (frame, frame_out) in input
+            .chunks_exact(self.channels)
+            .zip(output.chunks_exact_mut(self.channels))
+        {
+            for ((sample, out), error) in frame
+                .iter()
+                .zip(frame_out.iter_mut())
+                .zip(self.errors.iter_mut())
+            {
+                let mut value = sample * scale;
+                if !self.dither {
+                    *out = T::from_sample(value.round().clamp(-scale, scale - 1.0) / scale);
+                    continue;
+                }
+                if self.noise_shaping {
+                    value -= *error;
+                }
+                // Triangular noise of one step on each side
+                let dither = next_random(&mut self.random) - next_random(&mut self.random);
+                let quantized = (value + dither).round().clamp(-scale, scale - 1.0);
+                *error = (quantized - value).clamp(-1.0, 1.0);
+                *out = T::from_sample(quantized / scale);
+            }
+        }
+    }
+}
+
+/// A uniform random value from 0 to 1, xorshift32
+fn next_random(state: &mut u32) -> BuF {
+    *state ^= *state << 13;
+    *state ^= *state >> 17;
+    *state ^= *state << 5;
+    (*state >> 8) as BuF / (1u32 << 24) as BuF
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The gain from the input channel to the output channel
+    fn gain(mixer: &ChannelMixer, output: usize, input: usize) -> BuF {
+        mixer.matrix[output * mixer.channels_input + input]
+    }
+
+    /// The output channels the input channel is heard on
+    fn heard_on(mixer: &ChannelMixer, input: usize) -> Vec<usize> {
+        (0..mixer.channels_output)
+            .filter(|o| gain(mixer, *o, input) > 0.0)
+            .collect()
+    }
+
+    #[test]
+    fn mono_and_stereo() {
+        let mixer = ChannelMixer::new(1, 2);
+        assert_eq!((gain(&mixer, 0, 0), gain(&mixer, 1, 0)), (1.0, 1.0));
+        let mixer = ChannelMixer::new(2, 1);
+        assert_eq!((gain(&mixer, 0, 0), gain(&mixer, 0, 1)), (0.5, 0.5));
+        let mixer = ChannelMixer::new(1, 6);
+        assert_eq!(heard_on(&mixer, 0), [0, 1]);
+        let mixer = ChannelMixer::new(2, 8);
+        assert_eq!(heard_on(&mixer, 0), [0]);
+        assert_eq!(heard_on(&mixer, 1), [1]);
+    }
+
+    #[test]
+    fn down_mix_to_stereo_drops_lfe() {
+        let mixer = ChannelMixer::new(6, 2);
+        assert_eq!(heard_on(&mixer, 2), [0, 1]);
+        assert!(heard_on(&mixer, 3).is_empty());
+        assert_eq!(heard_on(&mixer, 4), [0]);
+        assert_eq!(heard_on(&mixer, 5), [1]);
+    }
+
+    #[test]
+    fn quad_to_surround_keeps_the_back() {
+        let mixer = ChannelMixer::new(4, 6);
+        assert_eq!(heard_on(&mixer, 0), [0]);
+        assert_eq!(heard_on(&mixer, 1), [1]);
+        assert_eq!(heard_on(&mixer, 2), [4]);
+        assert_eq!(heard_on(&mixer, 3), [5]);
+        assert_eq!(gain(&mixer, 4, 2), 1.0);
+    }
+
+    #[test]
+    fn surround_to_quad_folds_the_centre() {
+        let mixer = ChannelMixer::new(6, 4);
+        assert_eq!(heard_on(&mixer, 2), [0, 1]);
+        assert!(heard_on(&mixer, 3).is_empty());
+        assert_eq!(heard_on(&mixer, 4), [2]);
+        assert_eq!(heard_on(&mixer, 5), [3]);
+    }
+
+    #[test]
+    fn surround_and_7_1() {
+        let mixer = ChannelMixer::new(6, 8);
+        (0..6).for_each(|c| assert_eq!(heard_on(&mixer, c), [c]));
+        let mixer = ChannelMixer::new(8, 6);
+        (0..6).for_each(|c| assert_eq!(heard_on(&mixer, c), [c]));
+        assert_eq!(heard_on(&mixer, 6), [4]);
+        assert_eq!(heard_on(&mixer, 7), [5]);
+        let mixer = ChannelMixer::new(7, 8);
+        assert_eq!(heard_on(&mixer, 4), [4, 5]);
+        assert_eq!(heard_on(&mixer, 5), [6]);
+    }
+
+    #[test]
+    fn known_layouts_never_clip_and_keep_every_channel() {
+        for channels_input in 1..=8 {
+            for channels_output in 1..=8 {
+                let mixer = ChannelMixer::new(channels_input, channels_output);
+                for row in mixer.matrix.chunks_exact(channels_input) {
+                    assert!(row.iter().sum::<BuF>() <= 1.0 + 1e-6);
+                }
+                let lfe = layout(channels_input)
+                    .and_then(|x| x.iter().position(|role| *role == Role::Lfe))
+                    .filter(|_| channels_input != channels_output);
+                for input in (0..channels_input).filter(|x| Some(*x) != lfe) {
+                    assert!(!heard_on(&mixer, input).is_empty());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use diesel::{associations::HasTable, prelude::*};
+use log::{error, info, warn};
+use std::{
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicU8, Arc},
+    time::Instant,
+};
+
+use crate::{
+    loudness::{LoudnessMeter, Measurement},
+    models::{Release, Track, TrackLocation},
+    playback::match_audio_track,
+    schema::releases,
+};
+
+use super::{files::AddFileError, Library, MusicFileError};
+
+/// Frames decoded at once while measuring
+const ANALYSIS_FRAMES: usize = 4096;
+
+/// Decode an audio track of the file and measure the loudness
+fn measure_file(path: &Path, audio_track: usize) -> Result<Measurement, MusicFileError> {
+    let Some(mut decoder) = match_audio_track(path, audio_track) else {
+        return Err(MusicFileError::NoDecoder);
+    };
+    let mut meter = LoudnessMeter::new(decoder.sample_rate(), decoder.channels());
+    let mut buffer = vec![0.0; ANALYSIS_FRAMES * decoder.channels().max(1)];
+    // A short fill is not the end, the decoder can stop at the end of a packet
+    while !decoder.finished() {
+        let written = decoder
+            .fill_available(&mut buffer)
+            .map_err(MusicFileError::DecodeError)?;
+        meter.process(&buffer[..written]);
+    }
+    Ok(meter.measurement().clone())
+}
+
+impl Library {
+    /// Measure the loudness (EBU R128) of every release that has not been measured yet,
+    /// with all of its tracks.
+    ///
+    /// A release is only marked as measured after all its tracks are done,
+    /// so an interrupted analysis continues with the releases that are left.
+    ///
+    /// Decodes every file, this takes a long time, add it to a background task or something
+    pub async fn analyse_loudness(&mut self, per_done: &Arc<AtomicU8>) -> Result<(), AddFileError> {
+        per_done.store(0, std::sync::atomic::Ordering::Relaxed);
+        let now = Instant::now();
+        let unmeasured = Release::table()
+            // The range is always set, a silent release has no loudness
+            .filter(releases::loudness_range.is_null())
+            .select(Release::as_select())
+            .load(&mut self.database)?;
+        let total = unmeasured.len();
+        info!("Analysing loudness of {total} releases");
+
+        for (i, release) in unmeasured.into_iter().enumerate() {
+            let mut album = Measurement::default();
+            let mut measured = false;
+            for track in self.models_related::<Release, Track>(&release)? {
+                let Some((path, audio_track)) = self.track_file(&track)? else {
+                    warn!("Could not find a file for track: \"{}\"", track.name);
+                    continue;
+                };
+                match measure_file(&path, audio_track) {
+                    Ok(measurement) => {
+                        self.set_track_loudness(track.id, measurement.loudness())?;
+                        album.extend(&measurement);
+                        measured = true;
+                    }
+                    Err(err) => error!("Error analysing \"{}\": {}", path.display(), err),
+                }
+            }
+            if measured {
+                self.set_release_loudness(release.id, album.loudness())?;
+            } else {
+                warn!(
+                    "Could not analyse any track of release: \"{}\"",
+                    release.name
+                );
+            }
+            per_done.store(
+                ((i + 1) * 100 / total) as u8,
+                std::sync::atomic::Ordering::Relaxed,
+            );
+        }
+        per_done.store(100, std::sync::atomic::Ordering::Relaxed);
+
+        info!(target: "rmusic::speed", "Analysing loudness took {} sec", now.elapsed().as_secs());
+        Ok(())
+    }
+
+    /// The first location of the track that is still a file, with the audio track in it
+    fn track_file(&mut self, track: &Track) -> QueryResult<Option<(PathBuf, usize)>> {
+        Ok(self
+            .models_related::<Track, TrackLocation>(track)?
+            .into_iter()
+            .map(|location| (PathBuf::from(location.path), location.audio_track as usize))
+            .find(|(path, _)| path.is_file()))
+    }
+}
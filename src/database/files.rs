@@ -10,14 +10,61 @@ use std::{
     time::Instant,
 };
 
-use crate::{models::TrackLocation, playback::match_decoder, schema::track_locations};
+use crate::{
+    decoders::{probe::probe_file, AudioTrack, Decoder},
+    models::TrackLocation,
+    playback::{match_decoder, replay_gain::ReplayGain},
+    schema::track_locations,
+};
 
 use super::{
-    date_from_tag, get_tag, multiple_string_from_tag, number_from_tag, parse_date, string_from_tag,
-    Library, MusicFileError,
+    date_from_tag, get_tag, multiple_string_from_tag, number_from_tag, parse_date,
+    replay_gain_from_tag, string_from_tag, Library, MusicFileError,
 };
 
-pub const MEDIAEXTENSIONS: [&str; 4] = ["opus", "mp3", "flac", "wav"];
+/// Frames decoded at once while measuring the duration
+const DURATION_FRAMES: usize = 4096;
+
+/// Length in frames of a file that does not have its length, by decoding all of it
+fn decoded_length(decoder: &mut Decoder) -> Result<u64, MusicFileError> {
+    let channels = decoder.channels().max(1);
+    let mut buffer = vec![0.0; DURATION_FRAMES * channels];
+    let mut length = 0;
+    loop {
+        let written = decoder
+            .fill_available(&mut buffer)
+            .map_err(MusicFileError::DecodeError)?;
+        length += (written / channels) as u64;
+        if written < buffer.len() {
+            break;
+        }
+    }
+    Ok(length)
+}
+
+/// Duration in seconds, files without their length are decoded to measure it
+fn duration(decoder: &mut Decoder) -> Result<i32, MusicFileError> {
+    let length = match decoder.length_is_estimate() {
+        true => decoded_length(decoder)?,
+        false => decoder.length(),
+    };
+    #[allow(clippy::cast_possible_truncation)]
+    let duration = (length / decoder.sample_rate() as u64) as i32;
+    Ok(duration)
+}
+
+/// Title of an audio track, the tracks after the first get their number and language
+fn audio_track_title(title: &str, audio_track: Option<&AudioTrack>) -> String {
+    match audio_track {
+        None | Some(AudioTrack { index: 0, .. }) => title.to_string(),
+        Some(AudioTrack {
+            index,
+            language: Some(language),
+            ..
+        }) => format!("{title} (audio track {}, {language})", index + 1),
+        Some(AudioTrack { index, .. }) => format!("{title} (audio track {})", index + 1),
+    }
+}
 
 struct CheckedFile {
     path: PathBuf,
@@ -46,6 +93,7 @@ impl CheckedFile {
     }
 }
 
+#[derive(Clone)]
 struct MusicFileInsert {
     artist_tag: String,
     title_tag: String,
@@ -59,6 +107,9 @@ struct MusicFileInsert {
     genres: Vec<String>,
     duration: i32,
     file_location: String,
+    audio_track: i32,
+    track_gain: Option<ReplayGain>,
+    album_gain: Option<ReplayGain>,
 }
 
 macro_rules! no_tag {
@@ -68,7 +119,8 @@ macro_rules! no_tag {
 }
 
 impl MusicFileInsert {
-    fn new(file: CheckedFile) -> Result<MusicFileInsert, MusicFileError> {
+    /// One insert for every audio track of the file
+    fn new(file: CheckedFile) -> Result<Vec<MusicFileInsert>, MusicFileError> {
         let tag = get_tag(&file.path)?;
 
         // Artist Name
@@ -123,15 +175,36 @@ impl MusicFileInsert {
             info!("Could not find album artist tag");
         }
 
-        let Some(decoder) = match_decoder(&file.path) else {
+        let Some(mut decoder) = match_decoder(&file.path) else {
             return Err(MusicFileError::NoDecoder);
         };
-        #[allow(clippy::cast_possible_truncation)]
-        let duration = (decoder.length() / decoder.sample_rate() as u64) as i32;
+        // Every audio track becomes a track of its own
+        let audio_tracks = decoder.audio_tracks();
+        let mut durations = vec![];
+        for index in 0..audio_tracks.len().max(1) {
+            decoder
+                .select_audio_track(index)
+                .map_err(MusicFileError::DecodeError)?;
+            durations.push(duration(&mut decoder)?);
+        }
 
         let publisher_tag = string_from_tag(&tag, &ItemKey::Publisher);
 
-        Ok(MusicFileInsert {
+        // ReplayGain
+        let track_gain = replay_gain_from_tag(
+            &tag,
+            &ItemKey::ReplayGainTrackGain,
+            &ItemKey::ReplayGainTrackPeak,
+            "R128_TRACK_GAIN",
+        );
+        let album_gain = replay_gain_from_tag(
+            &tag,
+            &ItemKey::ReplayGainAlbumGain,
+            &ItemKey::ReplayGainAlbumPeak,
+            "R128_ALBUM_GAIN",
+        );
+
+        let insert = MusicFileInsert {
             artist_tag: artist_tag.to_string(),
             title_tag: title_tag.to_string(),
             album_tag: album_tag.to_string(),
@@ -141,10 +214,23 @@ impl MusicFileInsert {
             album_date,
             genres,
             album_artist_tag,
-            duration,
+            duration: 0,
             publisher_tag,
             file_location: file.full_path,
-        })
+            audio_track: 0,
+            track_gain,
+            album_gain,
+        };
+        Ok(durations
+            .into_iter()
+            .enumerate()
+            .map(|(index, duration)| MusicFileInsert {
+                title_tag: audio_track_title(&insert.title_tag, audio_tracks.get(index)),
+                duration,
+                audio_track: index as i32,
+                ..insert.clone()
+            })
+            .collect())
     }
 }
 
@@ -177,7 +263,9 @@ impl Library {
     pub fn add_file(&mut self, file: &Path) -> Result<(), AddFileError> {
         info!("Adding file: \"{}\"", file.display());
         let checked_file = CheckedFile::new(file)?;
-        self.insert_music_file(MusicFileInsert::new(checked_file)?)?;
+        for insert in MusicFileInsert::new(checked_file)? {
+            self.insert_music_file(insert)?;
+        }
         info!("Successfully added file: \"{}\"", file.display());
         Ok(())
     }
@@ -217,7 +305,12 @@ impl Library {
             artist_id,
             release_id,
         )?;
-        self.insert_track_location_if_not_exist(insert.file_location.clone(), track_id)?;
+        self.insert_replay_gain(insert.track_gain, insert.album_gain, track_id, release_id)?;
+        self.insert_track_location_if_not_exist(
+            insert.file_location.clone(),
+            insert.audio_track,
+            track_id,
+        )?;
         for genre in insert.genres {
             if self
                 .insert_genres_if_not_exist(genre.clone(), track_id)
@@ -229,6 +322,22 @@ impl Library {
         Ok(())
     }
 
+    fn insert_replay_gain(
+        &mut self,
+        track_gain: Option<ReplayGain>,
+        album_gain: Option<ReplayGain>,
+        track_id: i32,
+        release_id: i32,
+    ) -> QueryResult<()> {
+        if let Some(track_gain) = track_gain {
+            self.set_track_replay_gain(track_id, track_gain)?;
+        }
+        if let Some(album_gain) = album_gain {
+            self.set_release_replay_gain(release_id, album_gain)?;
+        }
+        Ok(())
+    }
+
     /// This function can take up to a minute to complete, add it to a background task or something
     pub async fn add_folder_rec(
         &mut self,
@@ -278,7 +387,7 @@ impl Library {
                 Err(err) => {
                     error!("Error getting tags of file: {}", err);
                 }
-                Ok(insert) => inserts.push(insert),
+                Ok(file_inserts) => inserts.extend(file_inserts),
             }
             let progress_amount = ((i + 1) / total) as u8 / 10;
             if progress_amount > progress {
@@ -355,8 +464,10 @@ impl Library {
                 artist_id,
                 release_id,
             )?;
+            self.insert_replay_gain(insert.track_gain, insert.album_gain, track_id, release_id)?;
             track_locations.push((
                 track_locations::path.eq(insert.file_location),
+                track_locations::audio_track.eq(insert.audio_track),
                 track_locations::track_id.eq(track_id),
             ));
             let progress_amount = ((i + 1) / total) as u8 / 89 + 10;
@@ -386,9 +497,7 @@ impl Library {
             let item = item?.path();
             if item.is_dir() {
                 result.append(&mut Self::find_files(&item.clone())?);
-            } else if item.extension().is_some_and(|x| {
-                MEDIAEXTENSIONS.contains(&x.to_string_lossy().into_owned().as_str())
-            }) {
+            } else if probe_file(&item).is_ok_and(|x| x.is_some()) {
                 result.push(item.clone());
             }
         }
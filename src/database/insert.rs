@@ -2,7 +2,11 @@ use chrono::NaiveDate;
 use diesel::{prelude::*, result::Error};
 use log::info;
 
-use crate::schema::{artists, genres, publishers, releases, track_locations, tracks};
+use crate::{
+    loudness::Loudness,
+    playback::replay_gain::ReplayGain,
+    schema::{artists, genres, publishers, releases, track_locations, tracks},
+};
 
 use super::Library;
 
@@ -105,6 +109,65 @@ impl Library {
         }
     }
 
+    /// Mark a release as gapless, its tracks will never be crossfaded
+    pub fn set_release_gapless(&mut self, release_id: i32, gapless: bool) -> QueryResult<()> {
+        diesel::update(releases::table.find(release_id))
+            .set(releases::gapless.eq(gapless))
+            .execute(&mut self.database)?;
+        info!("Set gapless of release {release_id} to {gapless}");
+        Ok(())
+    }
+
+    pub fn set_release_replay_gain(
+        &mut self,
+        release_id: i32,
+        replay_gain: ReplayGain,
+    ) -> QueryResult<()> {
+        diesel::update(releases::table.find(release_id))
+            .set((
+                releases::replay_gain.eq(replay_gain.gain),
+                releases::replay_peak.eq(replay_gain.peak),
+            ))
+            .execute(&mut self.database)?;
+        Ok(())
+    }
+
+    pub fn set_track_replay_gain(
+        &mut self,
+        track_id: i32,
+        replay_gain: ReplayGain,
+    ) -> QueryResult<()> {
+        diesel::update(tracks::table.find(track_id))
+            .set((
+                tracks::replay_gain.eq(replay_gain.gain),
+                tracks::replay_peak.eq(replay_gain.peak),
+            ))
+            .execute(&mut self.database)?;
+        Ok(())
+    }
+
+    pub fn set_release_loudness(&mut self, release_id: i32, loudness: Loudness) -> QueryResult<()> {
+        diesel::update(releases::table.find(release_id))
+            .set((
+                releases::loudness.eq(loudness.integrated),
+                releases::loudness_range.eq(loudness.range),
+                releases::true_peak.eq(loudness.true_peak),
+            ))
+            .execute(&mut self.database)?;
+        Ok(())
+    }
+
+    pub fn set_track_loudness(&mut self, track_id: i32, loudness: Loudness) -> QueryResult<()> {
+        diesel::update(tracks::table.find(track_id))
+            .set((
+                tracks::loudness.eq(loudness.integrated),
+                tracks::loudness_range.eq(loudness.range),
+                tracks::true_peak.eq(loudness.true_peak),
+            ))
+            .execute(&mut self.database)?;
+        Ok(())
+    }
+
     pub fn insert_track_if_not_exist(
         &mut self,
         name: String,
@@ -131,14 +194,16 @@ impl Library {
     pub fn insert_track_location_if_not_exist(
         &mut self,
         path: String,
+        audio_track: i32,
         track_id: i32,
     ) -> QueryResult<()> {
         let opt = diesel::insert_into(track_locations::table)
             .values((
                 track_locations::path.eq(&path),
+                track_locations::audio_track.eq(audio_track),
                 track_locations::track_id.eq(track_id),
             ))
-            .on_conflict(track_locations::track_id)
+            .on_conflict((track_locations::path, track_locations::audio_track))
             .do_update()
             .set(track_locations::track_id.eq(track_id))
             .execute(&mut self.database)
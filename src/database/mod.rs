@@ -9,22 +9,34 @@ use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 #[cfg(not(debug_assertions))]
 use directories::{self, ProjectDirs};
 use lofty::{read_from_path, ItemKey, Tag, TaggedFileExt};
-use log::debug;
+use log::{debug, info};
 #[cfg(not(debug_assertions))]
 use std::fs;
 use std::{fmt::Display, path::Path};
 
+use crate::playback::replay_gain::ReplayGain;
+
+pub mod analysis;
 pub mod context;
 pub mod files;
 pub mod insert;
 pub mod library_view;
+pub mod playlist;
+pub mod saved_queue;
 pub mod select;
 
-type DB = diesel::sqlite::Sqlite;
 type Conn = diesel::sqlite::SqliteConnection;
 
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
 
+/// R128 gains are relative to -23 LUFS, ReplayGain to -18 LUFS
+const R128_TO_REPLAY_GAIN: f32 = 5.0;
+
+// The item types of playlist items and saved queue items
+const TYPE_TRACK: i32 = 0;
+const TYPE_RELEASE: i32 = 1;
+const TYPE_PLAYLIST: i32 = 2;
+
 /// Main db struct
 pub struct Library {
     connection_pool: Pool<ConnectionManager<Conn>>,
@@ -107,6 +119,8 @@ pub enum MusicFileError {
     /// A check failed while trying to open the file
     FileCheck(String),
     IOError(std::io::Error),
+    /// The decoder gave an error while reading the audio
+    DecodeError(anyhow::Error),
 }
 
 impl Display for MusicFileError {
@@ -121,6 +135,7 @@ impl Display for MusicFileError {
             MusicFileError::NoTag => write!(f, "No tag found on file"),
             MusicFileError::FileCheck(text) => write!(f, "Error while getting the file: {text}"),
             MusicFileError::IOError(error) => write!(f, "IOError: {error}"),
+            MusicFileError::DecodeError(error) => write!(f, "Error while decoding: {error}"),
         }
     }
 }
@@ -190,6 +205,35 @@ fn number_from_tag(tag: &Tag, item_key: &ItemKey) -> Result<i32, MusicFileError>
     Ok(number)
 }
 
+/// Get the ReplayGain from the tag,
+/// falls back on the R128 tag that Opus files use
+fn replay_gain_from_tag(
+    tag: &Tag,
+    gain_key: &ItemKey,
+    peak_key: &ItemKey,
+    r128_key: &str,
+) -> Option<ReplayGain> {
+    let peak = string_from_tag(tag, peak_key).and_then(|x| x.trim().parse::<f32>().ok());
+    if let Some(gain_tag) = string_from_tag(tag, gain_key) {
+        // Looks like "-6.50 dB"
+        let gain = gain_tag
+            .trim()
+            .trim_end_matches(|c: char| c.is_alphabetic())
+            .trim();
+        match gain.parse::<f32>() {
+            Ok(gain) => return Some(ReplayGain::new(gain, peak)),
+            Err(_) => info!("Could not parse \"{gain_tag}\" as ReplayGain"),
+        }
+    }
+    // Q7.8 fixed point number
+    let r128 = string_from_tag(tag, &ItemKey::Unknown(r128_key.to_string()))?;
+    let r128 = r128.trim().parse::<i16>().ok()?;
+    Some(ReplayGain::new(
+        r128 as f32 / 256.0 + R128_TO_REPLAY_GAIN,
+        None,
+    ))
+}
+
 fn date_from_tag(tag: &Tag, item_key: &ItemKey) -> Result<NaiveDate, MusicFileError> {
     let Some(date_tag) = string_from_tag(tag, item_key) else {
         return Err(MusicFileError::MissingTag(format!("{:?}", item_key)));
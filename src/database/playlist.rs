@@ -0,0 +1,246 @@
+use chrono::Local;
+use diesel::prelude::*;
+use log::info;
+use std::{collections::HashSet, fmt::Display};
+
+use crate::{
+    models::{Playlist, PlaylistItem},
+    schema::{playlist_items, playlists},
+};
+
+use super::{select::PlaylistItemType, Conn, Library, TYPE_PLAYLIST, TYPE_RELEASE, TYPE_TRACK};
+
+#[derive(Debug)]
+pub enum PlaylistError {
+    /// The playlist would end up containing itself
+    Cycle,
+    /// The playlist or playlist item doesn't exist
+    NotFound,
+    /// The database gave an error
+    DbErr(diesel::result::Error),
+}
+
+impl Display for PlaylistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PlaylistError::Cycle => write!(f, "The playlist would contain itself"),
+            PlaylistError::NotFound => write!(f, "Playlist not found"),
+            PlaylistError::DbErr(db_err) => write!(f, "DataBase Error: \"{db_err}\""),
+        }
+    }
+}
+
+impl std::error::Error for PlaylistError {}
+
+impl From<diesel::result::Error> for PlaylistError {
+    fn from(value: diesel::result::Error) -> Self {
+        PlaylistError::DbErr(value)
+    }
+}
+
+/// Check that an update changed a row
+fn found(rows: usize) -> Result<(), PlaylistError> {
+    match rows {
+        0 => Err(PlaylistError::NotFound),
+        _ => Ok(()),
+    }
+}
+
+/// The playlist ids of the items that are not deleted
+fn child_playlists(conn: &mut Conn, playlist_id: i32) -> QueryResult<Vec<i32>> {
+    Ok(playlist_items::table
+        .filter(playlist_items::playlist_id.eq(playlist_id))
+        .filter(playlist_items::deleted.eq(false))
+        .select(playlist_items::item_playlist_id)
+        .load::<Option<i32>>(conn)?
+        .into_iter()
+        .flatten()
+        .collect())
+}
+
+impl Library {
+    /// Create an empty playlist, returns the id
+    pub fn create_playlist(&mut self, name: String, description: String) -> QueryResult<i32> {
+        let id = diesel::insert_into(playlists::table)
+            .values((
+                playlists::name.eq(&name),
+                playlists::description.eq(&description),
+            ))
+            .returning(playlists::id)
+            .get_result(&mut self.database)?;
+        info!("Created playlist: {name}");
+        Ok(id)
+    }
+
+    pub fn rename_playlist(&mut self, playlist_id: i32, name: String) -> Result<(), PlaylistError> {
+        found(
+            diesel::update(playlists::table.find(playlist_id))
+                .set(playlists::name.eq(name))
+                .execute(&mut self.database)?,
+        )
+    }
+
+    pub fn describe_playlist(
+        &mut self,
+        playlist_id: i32,
+        description: String,
+    ) -> Result<(), PlaylistError> {
+        found(
+            diesel::update(playlists::table.find(playlist_id))
+                .set(playlists::description.eq(description))
+                .execute(&mut self.database)?,
+        )
+    }
+
+    /// The items of the playlist in order, without the deleted items
+    pub fn playlist_items(&mut self, playlist: &Playlist) -> QueryResult<Vec<PlaylistItem>> {
+        PlaylistItem::belonging_to(playlist)
+            .filter(playlist_items::deleted.eq(false))
+            .order(playlist_items::number)
+            .select(PlaylistItem::as_select())
+            .load(&mut self.database)
+    }
+
+    /// The deleted items of the playlist, they can be restored
+    pub fn deleted_playlist_items(
+        &mut self,
+        playlist: &Playlist,
+    ) -> QueryResult<Vec<PlaylistItem>> {
+        PlaylistItem::belonging_to(playlist)
+            .filter(playlist_items::deleted.eq(true))
+            .order(playlist_items::number)
+            .select(PlaylistItem::as_select())
+            .load(&mut self.database)
+    }
+
+    /// Add the item at the end of the playlist, returns the id of the playlist item
+    ///
+    /// A playlist can't be added to itself, or to a playlist inside of it
+    pub fn append_to_playlist(
+        &mut self,
+        playlist_id: i32,
+        item: &PlaylistItemType,
+    ) -> Result<i32, PlaylistError> {
+        let (item_type, track_id, release_id, item_playlist_id) = match item {
+            PlaylistItemType::Track(track) => (TYPE_TRACK, Some(track.id), None, None),
+            PlaylistItemType::Release(release) => (TYPE_RELEASE, None, Some(release.id), None),
+            PlaylistItemType::Playlist(playlist) => (TYPE_PLAYLIST, None, None, Some(playlist.id)),
+        };
+        let conn: &mut Conn = &mut self.database;
+        conn.transaction(|conn| {
+            playlists::table
+                .find(playlist_id)
+                .select(playlists::id)
+                .first::<i32>(conn)
+                .optional()?
+                .ok_or(PlaylistError::NotFound)?;
+            if let Some(child) = item_playlist_id {
+                if contains_playlist(conn, child, playlist_id)? {
+                    return Err(PlaylistError::Cycle);
+                }
+            }
+            // Deleted items keep their number, so they can be restored in the same place
+            let number = playlist_items::table
+                .filter(playlist_items::playlist_id.eq(playlist_id))
+                .select(diesel::dsl::max(playlist_items::number))
+                .first::<Option<i32>>(conn)?
+                .map_or(0, |x| x + 1);
+            Ok(diesel::insert_into(playlist_items::table)
+                .values((
+                    playlist_items::date.eq(Local::now().date_naive()),
+                    playlist_items::number.eq(number),
+                    playlist_items::item_type.eq(item_type),
+                    playlist_items::deleted.eq(false),
+                    playlist_items::playlist_id.eq(playlist_id),
+                    playlist_items::item_playlist_id.eq(item_playlist_id),
+                    playlist_items::item_release_id.eq(release_id),
+                    playlist_items::item_track_id.eq(track_id),
+                ))
+                .returning(playlist_items::id)
+                .get_result(conn)?)
+        })
+    }
+
+    /// Move the playlist item so it ends up at `index` of the items that are not deleted
+    ///
+    /// Renumbers all items of the playlist, deleted items stay next to the item they were after
+    pub fn move_playlist_item(&mut self, item_id: i32, index: usize) -> Result<(), PlaylistError> {
+        let conn: &mut Conn = &mut self.database;
+        conn.transaction(|conn| {
+            let playlist_id = playlist_items::table
+                .find(item_id)
+                .select(playlist_items::playlist_id)
+                .first::<i32>(conn)
+                .optional()?
+                .ok_or(PlaylistError::NotFound)?;
+            let mut items: Vec<(i32, bool)> = playlist_items::table
+                .filter(playlist_items::playlist_id.eq(playlist_id))
+                .filter(playlist_items::id.ne(item_id))
+                .order(playlist_items::number)
+                .select((playlist_items::id, playlist_items::deleted))
+                .load(conn)?;
+            // Insert before the item that is now at the index, or at the end
+            let position = items
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, deleted))| !deleted)
+                .nth(index)
+                .map_or(items.len(), |(i, _)| i);
+            items.insert(position, (item_id, false));
+            for (number, (id, _)) in items.into_iter().enumerate() {
+                diesel::update(playlist_items::table.find(id))
+                    .set(playlist_items::number.eq(number as i32))
+                    .execute(conn)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Hide the item from the playlist, it can be restored with `restore_playlist_item`
+    pub fn delete_playlist_item(&mut self, item_id: i32) -> Result<(), PlaylistError> {
+        found(
+            diesel::update(playlist_items::table.find(item_id))
+                .set(playlist_items::deleted.eq(true))
+                .execute(&mut self.database)?,
+        )
+    }
+
+    /// Put a deleted item back in its place
+    ///
+    /// Fails when the item is a playlist that now contains the playlist it was in
+    pub fn restore_playlist_item(&mut self, item_id: i32) -> Result<(), PlaylistError> {
+        let conn: &mut Conn = &mut self.database;
+        conn.transaction(|conn| {
+            let item = playlist_items::table
+                .find(item_id)
+                .select(PlaylistItem::as_select())
+                .first(conn)
+                .optional()?
+                .ok_or(PlaylistError::NotFound)?;
+            if let Some(child) = item.item_playlist_id {
+                if contains_playlist(conn, child, item.playlist_id)? {
+                    return Err(PlaylistError::Cycle);
+                }
+            }
+            diesel::update(playlist_items::table.find(item_id))
+                .set(playlist_items::deleted.eq(false))
+                .execute(conn)?;
+            Ok(())
+        })
+    }
+}
+
+/// The playlist is `target` or has `target` somewhere inside of it
+fn contains_playlist(conn: &mut Conn, playlist_id: i32, target: i32) -> QueryResult<bool> {
+    let mut visited = HashSet::new();
+    let mut stack = vec![playlist_id];
+    while let Some(id) = stack.pop() {
+        if id == target {
+            return Ok(true);
+        }
+        if visited.insert(id) {
+            stack.extend(child_playlists(conn, id)?);
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,381 @@
+use diesel::prelude::*;
+use log::warn;
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+};
+
+use crate::{
+    models::{Playlist, Release, SavedQueue, SavedQueueItem, SavedQueueOptions, Track},
+    playback::playback_context::PlaybackContext,
+    queue::{
+        queue_items::{FromDB, QueueAlbum, QueueItem, QueuePlaylist, QueueTrack},
+        Queue, QueueOptions, ShuffleType, StopCondition, StopProgress, DEPTH_LIMIT,
+    },
+    schema::{saved_queue, saved_queue_items, saved_queue_options},
+};
+
+use super::{context::ContextError, Conn, Library, TYPE_PLAYLIST, TYPE_RELEASE, TYPE_TRACK};
+
+// The lists of the queue the items are saved in
+const LIST_QUEUE: i32 = 0;
+const LIST_NEXT_UP: i32 = 1;
+const LIST_HISTORY: i32 = 2;
+const LIST_FORWARD: i32 = 3;
+
+/// Saved items, grouped by the item they are in and sorted by number
+type Children = HashMap<Option<i32>, Vec<SavedQueueItem>>;
+
+impl Library {
+    /// Save the queue of the playback with the position in the current track
+    pub fn save_playback(&mut self, context: &PlaybackContext) -> QueryResult<()> {
+        // Don't keep the playback waiting on the database
+        let queue = context.queue_snapshot();
+        self.save_queue(&queue, context.played())
+    }
+
+    /// Save the queue and the samples played of the current track,
+    /// this replaces the queue that was saved before
+    pub fn save_queue(&mut self, queue: &Queue, position: u64) -> QueryResult<()> {
+        let conn: &mut Conn = &mut self.database;
+        conn.transaction(|conn| {
+            diesel::delete(saved_queue::table).execute(conn)?;
+            diesel::delete(saved_queue_items::table).execute(conn)?;
+            diesel::delete(saved_queue_options::table).execute(conn)?;
+
+            let options_id = insert_options(conn, &queue.queue_options)?;
+            diesel::insert_into(saved_queue::table)
+                .values((
+                    saved_queue::options_id.eq(options_id),
+                    saved_queue::repeat_current.eq(queue.repeat_current),
+                    saved_queue::max_history.eq(queue.max_history as i32),
+                    saved_queue::next_id.eq(queue.next_id as i64),
+                    saved_queue::current_track.eq(queue
+                        .current_track
+                        .as_ref()
+                        .map(|x| x.to_string_lossy().to_string())),
+                    saved_queue::position.eq(position as i64),
+                ))
+                .execute(conn)?;
+
+            insert_items(conn, &queue.queue_items, LIST_QUEUE, None)?;
+            insert_items(conn, &queue.next_up, LIST_NEXT_UP, None)?;
+            insert_items(conn, &queue.played_items, LIST_HISTORY, None)?;
+            for (number, track) in queue.forward.iter().enumerate() {
+                insert_track(conn, track, LIST_FORWARD, None, number)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Load the saved queue with the samples played of the current track
+    ///
+    /// Items that are no longer in the library are left out
+    pub fn load_queue(&mut self) -> Result<Option<(Queue, u64)>, ContextError> {
+        let Some(saved) = saved_queue::table
+            .select(SavedQueue::as_select())
+            .first(&mut self.database)
+            .optional()?
+        else {
+            return Ok(None);
+        };
+        let options: HashMap<i32, QueueOptions> = saved_queue_options::table
+            .select(SavedQueueOptions::as_select())
+            .load(&mut self.database)?
+            .into_iter()
+            .map(|x| (x.id, x.into()))
+            .collect();
+        let mut children = Children::new();
+        for item in saved_queue_items::table
+            .order(saved_queue_items::number)
+            .select(SavedQueueItem::as_select())
+            .load(&mut self.database)?
+        {
+            children.entry(item.parent_id).or_default().push(item);
+        }
+        let top_level = children.get(&None).cloned().unwrap_or_default();
+        let in_list = |list: i32| top_level.iter().filter(move |x| x.list == list);
+
+        let mut queue = Queue::new();
+        queue.queue_options = options.get(&saved.options_id).cloned().unwrap_or_default();
+        queue.repeat_current = saved.repeat_current;
+        queue.max_history = saved.max_history.max(0) as usize;
+        queue.next_id = saved.next_id as usize;
+        queue.current_track = saved.current_track.map(PathBuf::from);
+
+        for item in in_list(LIST_QUEUE) {
+            if let Some(item) = self.restore_item(item, &children, &options, 0)? {
+                queue.queue_items.push_back(item);
+            }
+        }
+        if queue.queue_items.len() != in_list(LIST_QUEUE).count() {
+            queue.queue_options.fit_to(queue.queue_items.len());
+        }
+        for item in in_list(LIST_NEXT_UP) {
+            if let Some(item) = self.restore_item(item, &children, &options, 0)? {
+                queue.next_up.push_back(item);
+            }
+        }
+        for item in in_list(LIST_HISTORY) {
+            if let Some(item) = self.restore_item(item, &children, &options, 0)? {
+                queue.played_items.push_back(item);
+            }
+        }
+        for item in in_list(LIST_FORWARD) {
+            if let Some(QueueItem::Track(track)) =
+                self.restore_item(item, &children, &options, 0)?
+            {
+                queue.forward.push_back(track);
+            }
+        }
+        Ok(Some((queue, saved.position.max(0) as u64)))
+    }
+
+    fn restore_item(
+        &mut self,
+        item: &SavedQueueItem,
+        children: &Children,
+        options: &HashMap<i32, QueueOptions>,
+        depth: usize,
+    ) -> Result<Option<QueueItem>, ContextError> {
+        if depth > DEPTH_LIMIT {
+            return Err(ContextError::MaxDepthReached);
+        }
+        let saved_children = children.get(&Some(item.id)).map_or(&[][..], |x| &x[..]);
+        let mut item_options = item
+            .options_id
+            .and_then(|x| options.get(&x))
+            .cloned()
+            .unwrap_or_default();
+
+        let mut restored = match item.item_type {
+            TYPE_TRACK => {
+                let Some(track) = self.model_from_id::<Track>(item.track_id.unwrap_or(-1))? else {
+                    warn!("Saved track {:?} is not in the library", item.track_id);
+                    return Ok(None);
+                };
+                return match QueueTrack::from_db(track, self) {
+                    Ok(track) => Ok(Some(track.into())),
+                    Err(ContextError::NoResult) => {
+                        warn!("Saved track {:?} has no file", item.track_id);
+                        Ok(None)
+                    }
+                    Err(err) => Err(err),
+                };
+            }
+            TYPE_RELEASE => {
+                let Some(release) = self.model_from_id::<Release>(item.release_id.unwrap_or(-1))?
+                else {
+                    warn!("Saved release {:?} is not in the library", item.release_id);
+                    return Ok(None);
+                };
+                let mut tracks = VecDeque::new();
+                for child in saved_children {
+                    if let Some(QueueItem::Track(track)) =
+                        self.restore_item(child, children, options, depth + 1)?
+                    {
+                        tracks.push_back(track);
+                    }
+                }
+                if tracks.len() != saved_children.len() {
+                    item_options.fit_to(tracks.len());
+                }
+                QueueItem::Album(QueueAlbum::new(release, tracks, item_options))
+            }
+            TYPE_PLAYLIST => {
+                let playlist = match item.playlist_id {
+                    Some(id) => self.model_from_id::<Playlist>(id)?,
+                    None => None,
+                };
+                let mut items = VecDeque::new();
+                for child in saved_children {
+                    if let Some(child) = self.restore_item(child, children, options, depth + 1)? {
+                        items.push_back(child);
+                    }
+                }
+                if items.len() != saved_children.len() {
+                    item_options.fit_to(items.len());
+                }
+                QueueItem::Playlist(QueuePlaylist::new(playlist, items, item_options))
+            }
+            item_type => {
+                warn!("Unknown saved queue item type: {item_type}");
+                return Ok(None);
+            }
+        };
+        restored.set_id(item.queue_id as usize);
+        Ok(Some(restored))
+    }
+}
+
+fn insert_items(
+    conn: &mut Conn,
+    items: &VecDeque<QueueItem>,
+    list: i32,
+    parent_id: Option<i32>,
+) -> QueryResult<()> {
+    for (number, item) in items.iter().enumerate() {
+        match item {
+            QueueItem::Track(track) => insert_track(conn, track, list, parent_id, number)?,
+            QueueItem::Album(album) => {
+                let options_id = insert_options(conn, album.queue_option())?;
+                let id = insert_item(
+                    conn,
+                    SavedQueueItem {
+                        id: 0,
+                        list,
+                        parent_id,
+                        number: number as i32,
+                        item_type: TYPE_RELEASE,
+                        queue_id: album.id() as i64,
+                        track_id: None,
+                        release_id: Some(album.album().id),
+                        playlist_id: None,
+                        options_id: Some(options_id),
+                    },
+                )?;
+                for (number, track) in album.tracks().iter().enumerate() {
+                    insert_track(conn, track, list, Some(id), number)?;
+                }
+            }
+            QueueItem::Playlist(playlist) => {
+                let options_id = insert_options(conn, playlist.queue_option())?;
+                let id = insert_item(
+                    conn,
+                    SavedQueueItem {
+                        id: 0,
+                        list,
+                        parent_id,
+                        number: number as i32,
+                        item_type: TYPE_PLAYLIST,
+                        queue_id: playlist.id() as i64,
+                        track_id: None,
+                        release_id: None,
+                        playlist_id: playlist.playlist().as_ref().map(|x| x.id),
+                        options_id: Some(options_id),
+                    },
+                )?;
+                insert_items(conn, playlist.i(), list, Some(id))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn insert_track(
+    conn: &mut Conn,
+    track: &QueueTrack,
+    list: i32,
+    parent_id: Option<i32>,
+    number: usize,
+) -> QueryResult<()> {
+    insert_item(
+        conn,
+        SavedQueueItem {
+            id: 0,
+            list,
+            parent_id,
+            number: number as i32,
+            item_type: TYPE_TRACK,
+            queue_id: 0,
+            track_id: Some(track.track().id),
+            release_id: None,
+            playlist_id: None,
+            options_id: None,
+        },
+    )?;
+    Ok(())
+}
+
+fn insert_item(conn: &mut Conn, item: SavedQueueItem) -> QueryResult<i32> {
+    diesel::insert_into(saved_queue_items::table)
+        .values(item)
+        .returning(saved_queue_items::id)
+        .get_result(conn)
+}
+
+fn insert_options(conn: &mut Conn, options: &QueueOptions) -> QueryResult<i32> {
+    diesel::insert_into(saved_queue_options::table)
+        .values(SavedQueueOptions::from(options))
+        .returning(saved_queue_options::id)
+        .get_result(conn)
+}
+
+fn weights_to_text(weights: &[usize]) -> String {
+    weights
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn weights_from_text(text: &str) -> Vec<usize> {
+    text.split(',').filter_map(|x| x.parse().ok()).collect()
+}
+
+impl From<&QueueOptions> for SavedQueueOptions {
+    fn from(options: &QueueOptions) -> Self {
+        let (shuffle_type, weights, default_weights) = match &options.shuffle_type {
+            ShuffleType::None => (0, String::new(), String::new()),
+            ShuffleType::TrueRandom => (1, String::new(), String::new()),
+            ShuffleType::WeightedRandom(weights) => (2, weights_to_text(weights), String::new()),
+            ShuffleType::WeightedDefault(default_weights) => {
+                (3, String::new(), weights_to_text(default_weights))
+            }
+            ShuffleType::WeightedRandomWithDefault(weights, default_weights) => (
+                4,
+                weights_to_text(weights),
+                weights_to_text(default_weights),
+            ),
+        };
+        let (stop_condition, stop_value) = match options.stop_condition {
+            StopCondition::EndOfList => (0, 0),
+            StopCondition::None => (1, 0),
+            StopCondition::AmountTracks(amount) => (2, amount as i64),
+            StopCondition::Time(time) => (3, time as i64),
+        };
+        SavedQueueOptions {
+            id: 0,
+            shuffle_type,
+            weights,
+            default_weights,
+            stop_condition,
+            stop_value,
+            selected: options.selected.map(|x| x as i32),
+            progress_tracks: options.progress.tracks as i32,
+            progress_time: options.progress.time as i64,
+            progress_picked: options.progress.picked as i32,
+        }
+    }
+}
+
+impl From<SavedQueueOptions> for QueueOptions {
+    fn from(saved: SavedQueueOptions) -> Self {
+        let weights = weights_from_text(&saved.weights);
+        let default_weights = weights_from_text(&saved.default_weights);
+        let shuffle_type = match saved.shuffle_type {
+            1 => ShuffleType::TrueRandom,
+            2 => ShuffleType::WeightedRandom(weights),
+            3 => ShuffleType::WeightedDefault(default_weights),
+            4 => ShuffleType::WeightedRandomWithDefault(weights, default_weights),
+            _ => ShuffleType::None,
+        };
+        let stop_value = saved.stop_value.max(0);
+        let stop_condition = match saved.stop_condition {
+            1 => StopCondition::None,
+            2 => StopCondition::AmountTracks(stop_value as usize),
+            3 => StopCondition::Time(stop_value as u64),
+            _ => StopCondition::EndOfList,
+        };
+        QueueOptions {
+            shuffle_type,
+            stop_condition,
+            selected: saved.selected.map(|x| x.max(0) as usize),
+            progress: StopProgress {
+                tracks: saved.progress_tracks.max(0) as usize,
+                time: saved.progress_time.max(0) as u64,
+                picked: saved.progress_picked.max(0) as usize,
+            },
+        }
+    }
+}
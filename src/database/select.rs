@@ -10,7 +10,7 @@ use anyhow::{Context, Result};
 use log::error;
 use log::warn;
 
-use super::{Conn, Library};
+use super::{Conn, Library, TYPE_PLAYLIST, TYPE_RELEASE, TYPE_TRACK};
 use diesel::{associations::HasTable, prelude::*};
 
 pub trait RelatedMany<C> {
@@ -144,7 +144,7 @@ impl Library {
     /// require that you transform it to your own types.
     /// Because of this you need to implement the recursive part yourself
     pub fn playlist(&mut self, playlist: &Playlist) -> Result<Vec<PlaylistItemType>> {
-        let pl_items = self.models_related::<_, PlaylistItem>(playlist)?;
+        let pl_items = self.playlist_items(playlist)?;
 
         let mut items = vec![];
 
@@ -160,12 +160,9 @@ impl Library {
             }
 
             let item: PlaylistItemType = match item.item_type {
-                // Track
-                0 => get_model!(item.item_track_id, Track, "track"),
-                // Release
-                1 => get_model!(item.item_release_id, Release, "release"),
-                // Playlist
-                2 => get_model!(item.item_playlist_id, Playlist, "playlist"),
+                TYPE_TRACK => get_model!(item.item_track_id, Track, "track"),
+                TYPE_RELEASE => get_model!(item.item_release_id, Release, "release"),
+                TYPE_PLAYLIST => get_model!(item.item_playlist_id, Playlist, "playlist"),
                 _ => {
                     error!(
                         "Wrong type playlist item in database, type:{}, id:{}",
@@ -1,83 +1,214 @@
-use crate::{decoders::opus_decoder::OpusReader, BuF};
-use anyhow::{Ok, Result};
+use crate::BuF;
+use anyhow::{anyhow, Ok, Result};
 
-use self::symphonia_wrap::SymphoniaWrapper;
 use cpal::Sample;
 
 mod ogg_demuxer;
 pub mod opus_decoder;
+mod opus_multistream;
+pub mod probe;
+pub mod registry;
+pub mod source;
 pub mod symphonia_wrap;
 
 const MAXERROR: u8 = 20;
 
-pub enum Decoder {
-    Opus(OpusReader),
-    Symphonia(SymphoniaWrapper),
-    None,
+/// A part of a track, like a link of a chained Ogg file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Chapter {
+    /// The first sample of the chapter
+    pub start: u64,
+    /// Length in samples
+    pub length: u64,
 }
 
+/// An audio track of a file, containers like Matroska and MP4 can have more than one
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioTrack {
+    /// Index among the audio tracks of the file, the first is 0
+    pub index: usize,
+    pub channels: Option<usize>,
+    pub sample_rate: Option<usize>,
+    /// Language code, when the container has it
+    pub language: Option<String>,
+}
+
+/// A decoder of one audio format, playback only uses decoders through this trait
+///
+/// Decoders of other formats can be added with [`registry::register_decoder`]
+pub trait AudioDecoder: Send {
+    /// Fill data with interleaved samples, as many as there are left,
+    /// returns the amount of samples written
+    ///
+    /// Stops early when the amount of channels changes,
+    /// [`AudioDecoder::channels`] gives the new amount after that
+    fn fill_available(&mut self, data: &mut [BuF]) -> Result<usize>;
+
+    /// Continue decoding from the target frame
+    fn goto(&mut self, target: u64) -> Result<()>;
+
+    /// Length in frames, see [`AudioDecoder::length_is_estimate`]
+    fn length(&self) -> u64;
+
+    /// The length is not in the file and was estimated, it is refined while decoding
+    fn length_is_estimate(&self) -> bool {
+        false
+    }
+
+    /// Frames that were not given out yet
+    fn left(&self) -> u64;
+
+    fn channels(&self) -> usize;
+
+    fn sample_rate(&self) -> usize;
+
+    /// All samples were given out, or the decoder gave up
+    fn finished(&self) -> bool;
+
+    /// Name of the format or decoder, for logging
+    fn name(&self) -> &str;
+
+    /// The chapters of the track, empty when the track has none
+    fn chapters(&self) -> Vec<Chapter> {
+        vec![]
+    }
+
+    /// Tags in the file as key and value, the keys are as they are in the file
+    fn tags(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+
+    /// The audio tracks of the file, empty for formats that only have one
+    fn audio_tracks(&self) -> Vec<AudioTrack> {
+        vec![]
+    }
+
+    /// Decode another audio track, from its start when the input can seek
+    fn select_audio_track(&mut self, index: usize) -> Result<()> {
+        match index {
+            0 => Ok(()),
+            _ => Err(anyhow!("There is no audio track {index}")),
+        }
+    }
+}
+
+/// Silence, used while no track is open
+struct NoDecoder;
+
+impl AudioDecoder for NoDecoder {
+    fn fill_available(&mut self, _data: &mut [BuF]) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn goto(&mut self, _target: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn length(&self) -> u64 {
+        0
+    }
+
+    fn left(&self) -> u64 {
+        0
+    }
+
+    fn channels(&self) -> usize {
+        0
+    }
+
+    fn sample_rate(&self) -> usize {
+        1
+    }
+
+    fn finished(&self) -> bool {
+        true
+    }
+
+    fn name(&self) -> &str {
+        "None"
+    }
+}
+
+/// The decoder of a track, of any format
+pub struct Decoder(Box<dyn AudioDecoder>);
+
 impl std::fmt::Debug for Decoder {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::Opus(_) => write!(f, "Opus"),
-            Self::Symphonia(_) => write!(f, "Symphonia"),
-            Self::None => write!(f, "None"),
-        }
+        write!(f, "{}", self.0.name())
     }
 }
 
 impl Decoder {
+    pub fn new(decoder: Box<dyn AudioDecoder>) -> Decoder {
+        Decoder(decoder)
+    }
+
+    /// A decoder without a track, it only gives silence
+    pub fn none() -> Decoder {
+        Decoder(Box::new(NoDecoder))
+    }
+
     /// Returns the number of samples left in the song
     pub fn fill(&mut self, data: &mut [BuF]) -> Result<u64> {
-        match self {
-            Decoder::Opus(opus) => opus.fill(data),
-            Decoder::Symphonia(symp) => symp.fill(data),
-            Decoder::None => {
-                for i in data.iter_mut() {
-                    *i = Sample::EQUILIBRIUM
-                }
-                Ok(0)
-            }
+        let written = self.0.fill_available(data)?;
+        for i in data[written..].iter_mut() {
+            *i = Sample::EQUILIBRIUM
         }
+        Ok(self.0.left())
+    }
+
+    /// Fill data with as many samples as there are left,
+    /// returns the amount of samples written
+    pub fn fill_available(&mut self, data: &mut [BuF]) -> Result<usize> {
+        self.0.fill_available(data)
+    }
+
+    /// Returns the number of samples left in the song
+    pub fn left(&self) -> u64 {
+        self.0.left()
     }
 
     pub fn channels(&self) -> usize {
-        match self {
-            Decoder::Opus(opus) => opus.opus_header.channels as usize,
-            Decoder::Symphonia(symp) => symp.channels(),
-            Decoder::None => 0,
-        }
+        self.0.channels()
     }
 
     pub fn sample_rate(&self) -> usize {
-        match self {
-            Decoder::Opus(_) => 48000,
-            Decoder::Symphonia(symp) => symp.sample_rate(),
-            Decoder::None => 1,
-        }
+        self.0.sample_rate()
     }
 
     pub fn length(&self) -> u64 {
-        match self {
-            Decoder::Opus(opus) => opus.length,
-            Decoder::Symphonia(symp) => symp.length(),
-            Decoder::None => 0,
-        }
+        self.0.length()
+    }
+
+    /// The length is an estimate, it changes while decoding
+    pub fn length_is_estimate(&self) -> bool {
+        self.0.length_is_estimate()
     }
 
     pub fn finished(&self) -> bool {
-        match self {
-            Decoder::Opus(opus_reader) => opus_reader.finished,
-            Decoder::Symphonia(symphonia_wrapper) => symphonia_wrapper.finished(),
-            Decoder::None => true,
-        }
+        self.0.finished()
+    }
+
+    /// The chapters of the track, empty when the track has none
+    pub fn chapters(&self) -> Vec<Chapter> {
+        self.0.chapters()
+    }
+
+    pub fn tags(&self) -> Vec<(String, String)> {
+        self.0.tags()
+    }
+
+    /// The audio tracks of the file, empty for formats that only have one
+    pub fn audio_tracks(&self) -> Vec<AudioTrack> {
+        self.0.audio_tracks()
+    }
+
+    /// Decode another audio track, the decoder starts with the first one
+    pub fn select_audio_track(&mut self, index: usize) -> Result<()> {
+        self.0.select_audio_track(index)
     }
 
     pub fn goto(&mut self, target: u64) -> Result<()> {
-        match self {
-            Decoder::Opus(opus) => opus.goto(target),
-            Decoder::Symphonia(symp) => symp.goto(target),
-            Decoder::None => Ok(()),
-        }
+        self.0.goto(target)
     }
 }
@@ -1,24 +1,138 @@
-use std::fs::File;
 use std::io::SeekFrom::Start;
-use std::io::{BufReader, ErrorKind, Result, Seek, SeekFrom};
+use std::io::{BufReader, ErrorKind, Read, Result, Seek, SeekFrom};
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use log::warn;
 
 const _OGGMAXPAGESIZE: u16 = 65307;
+/// Length of a page header without the segment table
+const HEADER_LENGTH: usize = 27;
+/// Generator polynomial of the Ogg CRC32
+const CRC_POLYNOMIAL: u32 = 0x04c1_1db7;
+const CRC_TABLE: [u32; 256] = crc_table();
+/// When the part of the link left to bisect is this small, it is read page by page
+const SEEK_WINDOW: u64 = 64 * 1024;
 
-pub struct OggReader {
-    file_reader: BufReader<File>,
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u32) << 24;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ CRC_POLYNOMIAL
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC32 as used by Ogg, not reflected and without a final xor
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    data.iter().fold(crc, |crc, byte| {
+        (crc << 8) ^ CRC_TABLE[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+pub struct OggReader<R> {
+    file_reader: BufReader<R>,
+    page: OggPage,
+    /// The logical stream that is being read, pages of other streams are skipped
     bitstream: u32,
-    _sequence: u32,
-    _checksum: u32,
-    page_segments: Vec<u8>,
+    /// The End page of the logical stream has been read
+    stream_ended: bool,
+    /// The next packet is the first packet of a new logical stream
+    stream_started: bool,
+    /// Granule position of the page before the current page
+    previous_granule: u64,
+    /// Pages that were skipped because they were corrupt
+    dropped_pages: u64,
+    result_buffer: Vec<u8>,
+    /// Buffers the next page is read into
+    spare_segments: Vec<u8>,
+    spare_data: Vec<u8>,
+}
+
+/// The page that is being read, the whole page is read and checked at once
+#[derive(Clone)]
+struct OggPage {
     header_type: OggHeaderType,
-    header_position: u64,
+    /// The first packet of the page started on the previous page
+    continued: bool,
+    /// Position of the page in the file
+    position: u64,
+    /// Position right after the page
+    end: u64,
     granule_position: u64,
-    result_buffer: Vec<u8>,
+    bitstream: u32,
+    _sequence: u32,
+    /// Stored in reverse order
+    segments: Vec<u8>,
+    data: Vec<u8>,
+    /// Read position in data
+    data_position: usize,
+}
+
+/// A packet read from the Ogg stream
+pub struct OggPacket<'a> {
+    pub data: &'a [u8],
+    /// This is the last packet of the logical stream
+    pub last: bool,
+    /// This is the first packet of a logical stream,
+    /// in a chained file this starts the next link
+    pub first: bool,
+    /// Granule position of the page, when this is the last packet that ends on it
+    pub granule: Option<u64>,
+}
+
+/// A logical stream of a chained Ogg file, the links are played one after another
+#[derive(Clone, Debug)]
+pub struct OggLink {
+    pub bitstream: u32,
+    /// Position of the first page in the file
+    pub position: u64,
+    /// Position right after the last page
+    pub end: u64,
+    /// Granule position of the last page
+    pub last_granule: u64,
+    /// The first packet, which identifies the codec
+    pub head: Vec<u8>,
 }
 
-#[derive(Ord, PartialOrd, Eq, PartialEq)]
+impl OggPage {
+    /// The link that starts with this page, its end is not known yet
+    fn link(&self) -> OggLink {
+        // The identification packet is the only packet of the first page
+        let length =
+            self.segments
+                .iter()
+                .rev()
+                .position(|x| *x != 255)
+                .map_or(self.data.len(), |i| {
+                    self.segments
+                        .iter()
+                        .rev()
+                        .take(i + 1)
+                        .map(|x| *x as usize)
+                        .sum()
+                });
+        OggLink {
+            bitstream: self.bitstream,
+            position: self.position,
+            end: self.end,
+            last_granule: 0,
+            head: self.data[..length].to_vec(),
+        }
+    }
+}
+
+#[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Copy, Debug)]
 pub enum OggHeaderType {
     None,
     Continuation,
@@ -26,322 +140,611 @@ pub enum OggHeaderType {
     End,
 }
 
-impl OggReader {
-    pub fn try_new(file_reader: BufReader<File>) -> Result<OggReader> {
+impl<R: Read + Seek> OggReader<R> {
+    pub fn try_new(file_reader: BufReader<R>) -> Result<OggReader<R>> {
         let mut reader = OggReader {
             file_reader,
+            page: OggPage {
+                header_type: OggHeaderType::End,
+                continued: false,
+                position: 0,
+                end: 0,
+                granule_position: 0,
+                bitstream: 0,
+                _sequence: 0,
+                segments: vec![],
+                data: vec![],
+                data_position: 0,
+            },
             bitstream: 0,
-            _sequence: 0,
-            _checksum: 0,
-            // Stored in reverse order
-            page_segments: vec![],
-            header_type: OggHeaderType::End,
-            header_position: 0,
-            granule_position: 0,
+            stream_ended: false,
+            stream_started: true,
+            previous_granule: 0,
+            dropped_pages: 0,
             result_buffer: vec![],
+            spare_segments: vec![],
+            spare_data: vec![],
         };
-        reader.read_page_header_expect()?;
+        reader.read_page()?;
+        reader.bitstream = reader.page.bitstream;
+        reader.stream_ended = reader.page.header_type == OggHeaderType::End;
         Ok(reader)
     }
 
-    pub fn read_page_header_expect(&mut self) -> Result<()> {
-        self.read_page_header_try().and_then(|x| match x {
-            None => Err(std::io::Error::new(
-                ErrorKind::NotFound,
-                "Did not find page header",
-            )),
-            Some(_) => Ok(()),
-        })
+    /// Read the next page of the logical stream that is being read.
+    /// A Start page that does not directly follow another Start page begins the next link,
+    /// pages of other streams are skipped
+    ///
+    /// Returns true when the next link was started
+    fn next_page(&mut self) -> Result<bool> {
+        if self.page.bitstream == self.bitstream && self.page.granule_position != u64::MAX {
+            self.previous_granule = self.page.granule_position;
+        }
+        loop {
+            let after_start = self.page.header_type == OggHeaderType::Start;
+            self.read_page()?;
+            if self.page.header_type == OggHeaderType::Start && !after_start {
+                self.bitstream = self.page.bitstream;
+                self.stream_ended = false;
+                self.stream_started = true;
+                self.previous_granule = 0;
+                return Ok(true);
+            }
+            if self.page.bitstream == self.bitstream {
+                self.stream_ended = self.page.header_type == OggHeaderType::End;
+                return Ok(false);
+            }
+        }
     }
 
-    fn read_page_header_try(&mut self) -> Result<Option<()>> {
-        let file_reader = &mut self.file_reader;
-        let start_pos = file_reader.stream_position()?;
-        if file_reader.read_u8()? != b'O'
-            || file_reader.read_u8()? != b'g'
-            || file_reader.read_u8()? != b'g'
-            || file_reader.read_u8()? != b'S'
-        {
-            return Ok(None);
+    /// Read the next page that is not corrupt
+    ///
+    /// Corrupt pages are skipped by searching for the next capture pattern "OggS",
+    /// the rest of a packet that was started on a skipped page is skipped as well
+    fn read_page(&mut self) -> Result<()> {
+        let mut resynced = false;
+        loop {
+            let start_pos = self.file_reader.stream_position()?;
+            if self.read_page_try(start_pos)? {
+                if resynced {
+                    self.dropped_pages += 1;
+                    self.skip_continued();
+                }
+                return Ok(());
+            }
+            self.file_reader.seek(Start(start_pos + 1))?;
+            self.find_capture_pattern()?;
+            resynced = true;
         }
-        if file_reader.read_u8()? != 0 {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidInput,
-                "Unsupported Ogg version",
-            ));
+    }
+
+    /// Read the page at the current position, returns false if the page is corrupt.
+    /// The current page is only changed when the page is valid
+    fn read_page_try(&mut self, start_pos: u64) -> Result<bool> {
+        let mut header = [0; HEADER_LENGTH];
+        self.file_reader.read_exact(&mut header)?;
+        // Capture pattern and version
+        if &header[0..4] != b"OggS" || header[4] != 0 {
+            return Ok(false);
         }
-        self.header_type = match file_reader.read_u8()? {
-            0 => OggHeaderType::None,
-            1 => OggHeaderType::Continuation,
-            2 => OggHeaderType::Start,
-            4 => OggHeaderType::End,
-            _ => return Ok(None),
+        // Read into the spare buffers, so the current page is kept when this one is corrupt
+        self.spare_segments.resize(header[26] as usize, 0);
+        self.file_reader.read_exact(&mut self.spare_segments)?;
+        let length = self.spare_segments.iter().map(|x| *x as usize).sum();
+        self.spare_data.resize(length, 0);
+        self.file_reader.read_exact(&mut self.spare_data)?;
+
+        let checksum = LittleEndian::read_u32(&header[22..26]);
+        header[22..26].fill(0);
+        let crc = crc32(crc32(0, &header), &self.spare_segments);
+        if crc32(crc, &self.spare_data) != checksum {
+            return Ok(false);
+        }
+
+        let flags = header[5];
+        self.spare_segments.reverse();
+        let page = &mut self.page;
+        std::mem::swap(&mut page.segments, &mut self.spare_segments);
+        std::mem::swap(&mut page.data, &mut self.spare_data);
+        page.data_position = 0;
+        page.header_type = if flags & 4 != 0 {
+            OggHeaderType::End
+        } else if flags & 2 != 0 {
+            OggHeaderType::Start
+        } else if flags & 1 != 0 {
+            OggHeaderType::Continuation
+        } else {
+            OggHeaderType::None
         };
-        // Get page info
-        self.header_position = start_pos;
-        self.granule_position = file_reader.read_u64::<LittleEndian>()?;
-        self.bitstream = file_reader.read_u32::<LittleEndian>()?;
-        self._sequence = file_reader.read_u32::<LittleEndian>()?;
-        self._checksum = file_reader.read_u32::<LittleEndian>()?;
-        // Read page segments
-        let page_amount = file_reader.read_u8()?;
-        self.page_segments.clear();
-        for _ in 0..page_amount {
-            self.page_segments.push(file_reader.read_u8()?)
+        page.continued = flags & 1 != 0;
+        page.position = start_pos;
+        page.end = start_pos + (HEADER_LENGTH + page.segments.len() + page.data.len()) as u64;
+        page.granule_position = LittleEndian::read_u64(&header[6..14]);
+        page.bitstream = LittleEndian::read_u32(&header[14..18]);
+        page._sequence = LittleEndian::read_u32(&header[18..22]);
+        Ok(true)
+    }
+
+    /// Move the reader to the next capture pattern
+    fn find_capture_pattern(&mut self) -> Result<()> {
+        let mut last = [0; 4];
+        loop {
+            last.copy_within(1.., 0);
+            last[3] = self.file_reader.read_u8()?;
+            if &last == b"OggS" {
+                self.file_reader.seek(SeekFrom::Current(-4))?;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Skip the segments that belong to a packet of the previous page
+    fn skip_continued(&mut self) {
+        if !self.page.continued {
+            return;
         }
-        self.page_segments.reverse();
-        if page_amount == 0 {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidData,
-                "This is a page with no segments, I don't know if this is a thing so I hope it never happens",
-            ));
+        while let Some(segment) = self.page.segments.pop() {
+            self.page.data_position += segment as usize;
+            if segment != 255 {
+                break;
+            }
         }
-        Ok(Some(()))
     }
 
-    /// Return the granular position of the current page
-    pub fn granular_position(&self) -> u64 {
-        self.granule_position
+    /// Amount of corrupt pages that were skipped
+    pub fn dropped_pages(&self) -> u64 {
+        self.dropped_pages
+    }
+
+    /// The logical stream that is being read
+    pub fn bitstream(&self) -> u32 {
+        self.bitstream
     }
 
     /// Read the next packet
-    pub fn read_packet(&mut self) -> Result<(&Vec<u8>, bool)> {
-        let segment = match self.page_segments.pop() {
-            Some(s) => s,
-            None => {
-                return Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Found no segment when expecting one",
-                ))
-            }
-        };
+    ///
+    /// Returns an error when the packet is missing a part because a page was corrupt,
+    /// the next packet can be read after that
+    pub fn read_packet(&mut self) -> Result<OggPacket<'_>> {
+        if self.page.segments.is_empty() && !self.next_page()? {
+            self.skip_continued();
+        }
         self.result_buffer.clear();
-        if segment == 255 {
-            for _ in 0..255 {
-                self.result_buffer.push(self.file_reader.read_u8()?);
-            }
-            loop {
-                let segment = match self.page_segments.pop() {
-                    Some(s) => s,
-                    None => match self.read_page_header_expect() {
-                        Ok(_) => match self.page_segments.pop() {
-                            Some(s) => s,
-                            None => {
-                                return Err(std::io::Error::new(
-                                    ErrorKind::InvalidData,
-                                    "Found no segment when expecting one",
-                                ));
-                            }
-                        },
-                        Err(err) => {
-                            if err.kind() == ErrorKind::UnexpectedEof
-                                && self.header_type == OggHeaderType::End
-                            {
-                                return Err(std::io::Error::new(
-                                    ErrorKind::UnexpectedEof,
-                                    "End of Ogg File",
-                                ));
-                            }
+        loop {
+            let segment = match self.page.segments.pop() {
+                Some(s) => s,
+                None => {
+                    // The packet continues on the next page
+                    let dropped = self.dropped_pages;
+                    let started = match self.next_page() {
+                        Ok(started) => started,
+                        Err(_) => {
                             return Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                "Found no segment when expecting one",
-                            ));
+                                ErrorKind::UnexpectedEof,
+                                "End of Ogg File",
+                            ))
                         }
-                    },
-                };
-                if segment != 255 {
-                    for _ in 0..segment {
-                        self.result_buffer.push(self.file_reader.read_u8()?)
+                    };
+                    if started || self.dropped_pages != dropped || !self.page.continued {
+                        return Err(std::io::Error::new(
+                            ErrorKind::InvalidData,
+                            "Packet is missing a page",
+                        ));
                     }
-                    break;
+                    continue;
                 }
-                for _ in 0..255 {
-                    self.result_buffer.push(self.file_reader.read_u8()?);
-                }
-            }
-        } else {
-            for _ in 0..segment {
-                self.result_buffer.push(self.file_reader.read_u8()?)
-            }
-        }
-        if self.page_segments.is_empty() && self.read_page_header_try().is_err() {
-            if self.header_type == OggHeaderType::End {
-                Ok((&self.result_buffer, true))
-            } else {
-                Err(std::io::Error::new(
-                    ErrorKind::InvalidData,
-                    "Found no segment when expecting one",
-                ))
+            };
+            let end = self.page.data_position + segment as usize;
+            self.result_buffer
+                .extend_from_slice(&self.page.data[self.page.data_position..end]);
+            self.page.data_position = end;
+            if segment != 255 {
+                break;
             }
-        } else {
-            Ok((&self.result_buffer, false))
         }
+        let first = std::mem::take(&mut self.stream_started);
+        let granule = match self.page.segments.iter().any(|x| *x != 255) {
+            false if self.page.granule_position != u64::MAX => Some(self.page.granule_position),
+            _ => None,
+        };
+        // The stream ends with this packet when there is no next page, or the next link starts
+        let last = self.page.segments.is_empty()
+            && (self.stream_ended
+                || self.next_page().map_or(true, |started| {
+                    // An End page without packets
+                    started || (self.stream_ended && self.page.segments.is_empty())
+                }));
+        Ok(OggPacket {
+            data: &self.result_buffer,
+            last,
+            first,
+            granule,
+        })
     }
 
-    /// Find the last granular positions from the current stream
+    /// Find all links of the chain by reading the whole file,
+    /// the reader continues from the same place afterwards
     ///
-    /// May loop endlessly if the file is infinitely long
-    pub fn last_granular_position(&mut self) -> Result<u64> {
+    /// When the end of the last stream is missing, the last granular position in the file is used
+    pub fn links(&mut self) -> Result<Vec<OggLink>> {
         let safe_pos = self.file_reader.stream_position()?;
-        let current_segments = self.page_segments.clone();
-        let current_granular = self.granule_position;
-        self.skip_page()?;
-        let length = loop {
-            // loop until we find the length, 🙏 let's hope it does not loop endlessly.
-            if self.read_page_header_try()?.is_some() {
-                match self.header_type {
-                    OggHeaderType::End => break Ok(self.granule_position),
-
-                    OggHeaderType::Continuation | OggHeaderType::None => {
-                        self.skip_page()?;
+        let current_page = self.page.clone();
+        let dropped_pages = self.dropped_pages;
+        self.file_reader.seek(Start(0))?;
+
+        let mut links: Vec<OggLink> = vec![];
+        let mut after_start = false;
+        let mut ended = false;
+        let result = loop {
+            if let Err(err) = self.read_page() {
+                if err.kind() == ErrorKind::UnexpectedEof {
+                    if !ended {
+                        warn!("Ogg stream has no end, the file might be cut off");
                     }
-                    OggHeaderType::Start => {
-                        // We have skipped passed an End Page and now find a new Stream ???
-                        // Just throw an Error
-                        return Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            "Found no End of Stream",
-                        ));
+                    break Ok(());
+                }
+                break Err(err);
+            }
+            let page = &self.page;
+            if page.header_type == OggHeaderType::Start && !after_start {
+                links.push(page.link());
+                ended = false;
+            }
+            after_start = page.header_type == OggHeaderType::Start;
+            if let Some(link) = links.last_mut() {
+                if link.bitstream == page.bitstream {
+                    link.end = page.end;
+                    // Pages where no packet ends have no position
+                    if page.granule_position != u64::MAX {
+                        link.last_granule = page.granule_position;
                     }
+                    ended = page.header_type == OggHeaderType::End;
                 }
             }
         };
         self.file_reader.seek(Start(safe_pos))?;
-        self.granule_position = current_granular;
-        self.page_segments = current_segments;
-        length
+        self.page = current_page;
+        self.dropped_pages = dropped_pages;
+        result.map(|_| links)
+    }
+
+    /// The link of the current page, which has to be the first page of the link.
+    /// For streams where the other links can't be searched for
+    pub fn current_link(&self) -> OggLink {
+        self.page.link()
     }
 
-    /// Finds the page that has contains the target granular.
-    /// Where the granular position gives the last thing of the page
+    /// Continue reading in the link from the packet that starts at the last page boundary
+    /// at or before the target granule position, headers are not read again
+    ///
+    /// Bisects on the position in the file using the granule positions of the pages,
+    /// the last part of the search is read page by page (RFC 7845, section 6.1)
     /// ```text
-    /// [---------gran][---target---gran]
-    ///           ^     ^
-    /// (return value)(continue reading from here)
+    /// [---------gran][------gran][---target---gran]
+    ///                         ^
+    ///          (the next packet starts here)
     /// ```
-    /// Return the granular position of the previous page
-    /// The Ogg reader will continue reading form the found page
-    pub fn find_granular_position_last(&mut self, target: u64, start_current: bool) -> Result<u64> {
-        if !start_current {
-            self.find_start_stream(self.bitstream)?;
+    /// Returns the granule position where the next packet starts
+    pub fn seek_granule(&mut self, link: &OggLink, target: u64) -> Result<u64> {
+        // Capture patterns inside of packets look like corrupt pages, those are not counted
+        let dropped_pages = self.dropped_pages;
+        // The first page has granule position 0, so it is never after the target
+        let mut low = link.position;
+        let mut high = link.end;
+        while high - low > SEEK_WINDOW {
+            let middle = low + (high - low) / 2;
+            match self.first_page_from(middle, high, link.bitstream)? {
+                Some((position, granule)) if granule <= target => low = position,
+                _ => high = middle,
+            }
         }
-        let mut last_granular = self.granule_position;
-        self.skip_page()?;
+
+        // The last page where a packet ends at or before the target
+        self.file_reader.seek(Start(low))?;
+        let mut found = low;
         loop {
-            if self.read_page_header_try()?.is_some() {
-                match self.header_type {
-                    // We did not find the right page
-                    OggHeaderType::End => {
-                        return Err(std::io::Error::new(
-                            ErrorKind::NotFound,
-                            "Could not find the value in Stream",
-                        ));
-                    }
-                    OggHeaderType::Continuation | OggHeaderType::None => {
-                        // Is our target in the last page
-                        if (last_granular..=self.granule_position).contains(&target) {
-                            return Ok(last_granular);
-                        }
-                        self.skip_page()?;
-                        last_granular = self.granule_position;
-                    }
-                    OggHeaderType::Start => {
-                        return Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            "Found no End of Stream",
-                        ));
-                    }
-                }
+            match self.read_page() {
+                Ok(()) => (),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => return Err(err),
+            }
+            let page = &self.page;
+            if page.position >= link.end {
+                break;
+            }
+            if page.bitstream != link.bitstream || page.granule_position == u64::MAX {
+                continue;
             }
+            if page.granule_position > target {
+                break;
+            }
+            found = page.position;
+        }
+        self.dropped_pages = dropped_pages;
+
+        // Skip the packets that end on the found page, the next packet ends after it
+        self.file_reader.seek(Start(found))?;
+        self.read_page()?;
+        self.bitstream = link.bitstream;
+        self.stream_ended = self.page.header_type == OggHeaderType::End;
+        self.stream_started = false;
+        // Segments are stored in reverse, the first one that ends a packet ends the last packet
+        if let Some(end) = self.page.segments.iter().position(|x| *x != 255) {
+            let length: usize = self.page.segments[end..].iter().map(|x| *x as usize).sum();
+            self.page.segments.truncate(end);
+            self.page.data_position += length;
         }
+        self.previous_granule = self.page.granule_position;
+        Ok(self.page.granule_position)
     }
 
-    /// Finds the page that has contains the target granular.
-    /// Where the granular position gives the first thing of the page.
-    /// ```text
-    /// [gran---target---]
-    ///    ^     
-    /// return value
-    /// ```
-    /// Return the granular position of the current page
-    /// The Ogg reader will continue form the found page
-    ///
-    /// UNTESTED
-    pub fn _find_granular_position_first(
+    /// The position and granule position of the first page of the stream
+    /// that starts in the range and has a granule position
+    fn first_page_from(
         &mut self,
-        target: u64,
-        start_current: bool,
-    ) -> Result<u64> {
-        if !start_current {
-            self.find_start_stream(self.bitstream)?;
-        }
-        let mut last_granular = self.granule_position;
-        let mut last_pos = self.header_position;
-        self.skip_page()?;
+        start: u64,
+        end: u64,
+        bitstream: u32,
+    ) -> Result<Option<(u64, u64)>> {
+        self.file_reader.seek(Start(start))?;
         loop {
-            if self.read_page_header_try()?.is_some() {
-                match self.header_type {
-                    // We did not find the right page
-                    OggHeaderType::End => {
-                        return Err(std::io::Error::new(
-                            ErrorKind::NotFound,
-                            "Could not find the value in Stream",
-                        ));
-                    }
+            let page = self.find_capture_pattern().and_then(|_| self.read_page());
+            match page {
+                Ok(()) => (),
+                Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(err) => return Err(err),
+            }
+            let page = &self.page;
+            if page.position >= end {
+                return Ok(None);
+            }
+            if page.bitstream == bitstream && page.granule_position != u64::MAX {
+                return Ok(Some((page.position, page.granule_position)));
+            }
+        }
+    }
+}
 
-                    OggHeaderType::Continuation | OggHeaderType::None => {
-                        // Is our target in the last page
-                        if (last_granular..=self.granule_position).contains(&target) {
-                            // Reset to the last page where the target is
-                            self.file_reader.seek(Start(last_pos))?;
-                            self.read_page_header_expect()?;
-                            return Ok(last_granular);
-                        }
-                        if target < self.granule_position {
-                            // We flew past it ???
-                            return Ok(self.granule_position);
-                        }
-                        // else we search further
-                        self.skip_page()?;
-                        last_granular = self.granule_position;
-                        last_pos = self.header_position;
-                    }
-                    OggHeaderType::Start => {
-                        return Err(std::io::Error::new(
-                            ErrorKind::InvalidData,
-                            "Found no End of Stream",
-                        ));
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoders::source::StreamSource;
+    use std::io::Cursor;
+
+    /// Durations of the packets in the test streams, used in turn
+    const DURATIONS: [u64; 6] = [120, 240, 480, 960, 1920, 2880];
+
+    /// A packet of the test codec, it starts with its first sample and its length in samples
+    fn packet(start: u64, samples: u64, size: usize) -> Vec<u8> {
+        let mut data = vec![0; size.max(16)];
+        LittleEndian::write_u64(&mut data[0..8], start);
+        LittleEndian::write_u64(&mut data[8..16], samples);
+        for (i, byte) in data[16..].iter_mut().enumerate() {
+            *byte = (i as u64 + start) as u8;
+        }
+        data
+    }
+
+    fn read_test_packet(data: &[u8]) -> (u64, u64) {
+        (
+            LittleEndian::read_u64(&data[0..8]),
+            LittleEndian::read_u64(&data[8..16]),
+        )
+    }
+
+    struct PageWriter<'a> {
+        file: &'a mut Vec<u8>,
+        bitstream: u32,
+        sequence: u32,
+        segments: Vec<u8>,
+        data: Vec<u8>,
+        granule_position: u64,
+        continued: bool,
+        /// Granule positions of the pages where a packet ends
+        granules: Vec<u64>,
+    }
+
+    impl PageWriter<'_> {
+        fn write_page(&mut self, end: bool) {
+            let mut flags = 0;
+            if self.continued {
+                flags |= 1;
+            }
+            if self.sequence == 0 {
+                flags |= 2;
+            }
+            if end {
+                flags |= 4;
+            }
+            let mut header = vec![0; HEADER_LENGTH];
+            header[0..4].copy_from_slice(b"OggS");
+            header[5] = flags;
+            LittleEndian::write_u64(&mut header[6..14], self.granule_position);
+            LittleEndian::write_u32(&mut header[14..18], self.bitstream);
+            LittleEndian::write_u32(&mut header[18..22], self.sequence);
+            header[26] = self.segments.len() as u8;
+            let crc = crc32(crc32(crc32(0, &header), &self.segments), &self.data);
+            LittleEndian::write_u32(&mut header[22..26], crc);
+            self.file.extend_from_slice(&header);
+            self.file.append(&mut self.segments);
+            self.file.append(&mut self.data);
+            if self.granule_position != u64::MAX {
+                self.granules.push(self.granule_position);
+            }
+            self.sequence += 1;
+            self.granule_position = u64::MAX;
+            self.continued = false;
+        }
+    }
+
+    /// Write a logical stream with two header packets and packets of the test codec,
+    /// a page is finished once it has `page_size` bytes of data
+    ///
+    /// Returns the granule positions of the pages where a packet ends
+    fn write_stream(
+        file: &mut Vec<u8>,
+        bitstream: u32,
+        packets: usize,
+        page_size: usize,
+    ) -> Vec<u64> {
+        let mut writer = PageWriter {
+            file,
+            bitstream,
+            sequence: 0,
+            segments: vec![],
+            data: vec![],
+            granule_position: u64::MAX,
+            continued: false,
+            granules: vec![],
+        };
+        let mut start = 0;
+        for i in 0..packets + 2 {
+            let (data, granule) = match i {
+                0 => (b"head".to_vec(), 0),
+                1 => (b"tags".to_vec(), 0),
+                _ => {
+                    let samples = DURATIONS[i % DURATIONS.len()];
+                    // Sizes that span pages, and sizes that are a multiple of 255
+                    let size = match i % 7 {
+                        0 => 255 * (i % 5 + 1),
+                        1 => page_size + i % 300,
+                        _ => (i * 37) % 700,
+                    };
+                    start += samples;
+                    (packet(start - samples, samples, size), start)
+                }
+            };
+            let mut lacing = vec![255; data.len() / 255];
+            lacing.push((data.len() % 255) as u8);
+            let mut position = 0;
+            for (j, segment) in lacing.into_iter().enumerate() {
+                if writer.segments.len() == 255 || writer.data.len() >= page_size {
+                    writer.write_page(false);
+                    writer.continued = j > 0;
                 }
+                let end = position + segment as usize;
+                writer.segments.push(segment);
+                writer.data.extend_from_slice(&data[position..end]);
+                position = end;
+            }
+            writer.granule_position = granule;
+            // The headers are on pages of their own
+            if i < 2 {
+                writer.write_page(false);
             }
         }
+        writer.write_page(true);
+        writer.granules
+    }
+
+    fn open(file: Vec<u8>) -> OggReader<Cursor<Vec<u8>>> {
+        OggReader::try_new(BufReader::new(Cursor::new(file))).unwrap()
     }
 
-    /// Will skip all packets in the current page
-    fn skip_page(&mut self) -> Result<()> {
-        // Add all segment lengths and skip that number of bytes
-        self.file_reader.seek(SeekFrom::Current({
-            let mut total = 0;
-            for i in self.page_segments.iter() {
-                total += *i as i64;
+    /// Seek to every target and check that the next packet starts at the last page boundary
+    /// before the target, and that the packets continue up to the target sample
+    fn check_seeks(
+        reader: &mut OggReader<Cursor<Vec<u8>>>,
+        link: &OggLink,
+        granules: &[u64],
+        step: u64,
+    ) {
+        let length = *granules.last().unwrap();
+        for target in (0..length).step_by(step as usize).chain([1, length - 1]) {
+            let expected = granules.iter().rev().find(|x| **x <= target).unwrap();
+            let granule = reader.seek_granule(link, target).unwrap();
+            assert_eq!(granule, *expected, "seek to {target}");
+            let mut start = granule;
+            while start <= target {
+                let packet = reader.read_packet().unwrap();
+                assert!(!packet.first);
+                let (packet_start, samples) = read_test_packet(packet.data);
+                assert_eq!(packet_start, start, "seek to {target}");
+                start += samples;
             }
-            total
-        }))?;
-        Ok(())
+        }
     }
 
-    /// Finds the start of the target bitstream
-    /// Will search form the beginning of the file
-    fn find_start_stream(&mut self, target_bitstream: u32) -> Result<()> {
-        self.file_reader.seek(Start(0))?;
+    #[test]
+    fn seek_lands_on_target() {
+        let mut file = vec![];
+        let granules = write_stream(&mut file, 1, 300, 4000);
+        let length = file.len() as u64;
+        let mut reader = open(file);
+        let links = reader.links().unwrap();
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].last_granule, *granules.last().unwrap());
+        assert_eq!(links[0].end, length);
+        check_seeks(&mut reader, &links[0], &granules, 997);
+    }
+
+    #[test]
+    fn seek_bisects_long_stream() {
+        let mut file = vec![];
+        let granules = write_stream(&mut file, 7, 5000, 4000);
+        assert!(file.len() as u64 > 16 * SEEK_WINDOW);
+        let mut reader = open(file);
+        let links = reader.links().unwrap();
+        check_seeks(&mut reader, &links[0], &granules, 26021);
+    }
+
+    #[test]
+    fn seek_in_chained_file() {
+        let mut file = vec![];
+        let first = write_stream(&mut file, 1, 500, 2000);
+        let second_position = file.len() as u64;
+        let second = write_stream(&mut file, 2, 700, 3000);
+        let mut reader = open(file);
+        let links = reader.links().unwrap();
+        assert_eq!(links.len(), 2);
+        assert_eq!(links[0].end, second_position);
+        assert_eq!(links[1].position, second_position);
+        assert_eq!(links[1].last_granule, *second.last().unwrap());
+        assert_eq!(links[1].bitstream, 2);
+        check_seeks(&mut reader, &links[1], &second, 1009);
+        check_seeks(&mut reader, &links[0], &first, 1013);
+    }
+
+    /// Read all packets of a test stream that has one corrupt page
+    fn check_corrupt<R: Read + Seek>(reader: &mut OggReader<R>) {
+        let mut start = 0;
+        let mut missing = 0;
         loop {
-            if self.read_page_header_try()?.is_some() {
-                // Header type check could be removed, as it should just be a formality
-                if target_bitstream == self.bitstream && self.header_type == OggHeaderType::Start {
-                    return Ok(());
+            match reader.read_packet() {
+                Ok(packet) => {
+                    if packet.data.len() >= 16 {
+                        let (packet_start, samples) = read_test_packet(packet.data);
+                        assert!(packet_start >= start);
+                        start = packet_start + samples;
+                    }
+                    if packet.last {
+                        break;
+                    }
                 }
-                self.skip_page()?;
+                Err(err) if err.kind() == ErrorKind::InvalidData => missing += 1,
+                Err(err) => panic!("{err}"),
             }
         }
+        assert_eq!(reader.dropped_pages(), 1);
+        assert!(missing <= 1);
+    }
+
+    fn corrupt_file() -> Vec<u8> {
+        let mut file = vec![];
+        write_stream(&mut file, 1, 300, 4000);
+        let middle = file.len() / 2;
+        file[middle] ^= 0x10;
+        file
+    }
+
+    #[test]
+    fn corrupt_page_is_skipped() {
+        check_corrupt(&mut open(corrupt_file()));
+    }
+
+    #[test]
+    fn corrupt_page_is_skipped_in_stream() {
+        let stream = StreamSource::new(Cursor::new(corrupt_file()));
+        check_corrupt(&mut OggReader::try_new(BufReader::new(stream)).unwrap());
     }
 }
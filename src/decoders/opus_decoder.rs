@@ -1,22 +1,25 @@
-use log::warn;
-use opusic_c::{Channels, Decoder, SampleRate};
+use log::{info, warn};
 use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, ErrorKind};
 
 use anyhow::{anyhow, Result};
 use byteorder::{ByteOrder, LittleEndian};
-use cpal::Sample;
 
-use crate::decoders::ogg_demuxer::OggReader;
+use crate::decoders::ogg_demuxer::{OggLink, OggReader};
+use crate::decoders::opus_multistream::OpusStreamDecoder;
+use crate::decoders::source::{ByteSource, Input};
 use crate::BuF;
 
-use super::MAXERROR;
+use super::{AudioDecoder, Chapter, MAXERROR};
 
-#[derive(Debug)]
+/// Samples decoded and thrown away before the target of a seek,
+/// so the decoder state converges (80 ms, RFC 7845 section 4.6)
+const PRE_ROLL: u64 = 3840;
+
+#[derive(Debug, Clone)]
 /// The header of the Opus Stream
 pub struct OpusHeader {
     _version: u8,
@@ -26,8 +29,14 @@ pub struct OpusHeader {
     ///
     /// Unless you know FOR sure what this is, which you probably don't.
     _input_sample_rate: u32,
-    output_gain: i16,
-    _channel_mapping_family: u8,
+    pub(super) output_gain: i16,
+    pub(super) channel_mapping_family: u8,
+    /// Amount of Opus streams in every packet
+    pub(super) streams: u8,
+    /// Amount of streams that have two channels, these come first
+    pub(super) coupled_streams: u8,
+    /// The stream channel of every output channel, 255 is silence
+    pub(super) mapping: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -55,7 +64,7 @@ impl Display for OpusPhraseError {
 }
 
 impl OpusHeader {
-    fn new(header: &[u8]) -> Result<OpusHeader, OpusPhraseError> {
+    pub(super) fn new(header: &[u8]) -> Result<OpusHeader, OpusPhraseError> {
         if !header.starts_with(b"OpusHead") {
             // Magic Signature
             return Err(OpusPhraseError {
@@ -63,6 +72,12 @@ impl OpusHeader {
                 message: "No Magic Signature \"OpusHead\" found",
             });
         }
+        if header.len() < 19 {
+            return Err(OpusPhraseError {
+                opus_header_error_kind: OpusPhraseErrorKind::NotValid,
+                message: "Header is too short",
+            });
+        }
         let version = header[8];
         if version > 15 {
             return Err(OpusPhraseError {
@@ -75,192 +90,456 @@ impl OpusHeader {
         let input_sample_rate = LittleEndian::read_u32(&header[12..=15]);
         let output_gain = LittleEndian::read_i16(&header[16..=17]);
         let channel_mapping_family = header[18];
-        if channel_mapping_family != 0 {
-            return Err(OpusPhraseError {
-                opus_header_error_kind: OpusPhraseErrorKind::Unsupported,
-                message: "Channel mapping is not supported",
-            });
-        }
+        let (streams, coupled_streams, mapping) = match channel_mapping_family {
+            // Mono or stereo in a single stream
+            0 => {
+                if !(1..=2).contains(&channels) {
+                    return Err(OpusPhraseError {
+                        opus_header_error_kind: OpusPhraseErrorKind::NotValid,
+                        message: "Mapping family 0 needs 1 or 2 channels",
+                    });
+                }
+                (1, channels - 1, (0..channels).collect())
+            }
+            // Surround in the Vorbis order, or channels without a defined meaning
+            1 | 255 => {
+                if channels == 0 || (channel_mapping_family == 1 && channels > 8) {
+                    return Err(OpusPhraseError {
+                        opus_header_error_kind: OpusPhraseErrorKind::NotValid,
+                        message: "Unsupported amount of channels",
+                    });
+                }
+                let Some(mapping) = header.get(21..21 + channels as usize) else {
+                    return Err(OpusPhraseError {
+                        opus_header_error_kind: OpusPhraseErrorKind::NotValid,
+                        message: "Channel mapping table is too short",
+                    });
+                };
+                let (streams, coupled_streams) = (header[19], header[20]);
+                let total = streams as usize + coupled_streams as usize;
+                if streams == 0
+                    || coupled_streams > streams
+                    || mapping.iter().any(|x| *x != 255 && *x as usize >= total)
+                {
+                    return Err(OpusPhraseError {
+                        opus_header_error_kind: OpusPhraseErrorKind::NotValid,
+                        message: "Channel mapping table is not valid",
+                    });
+                }
+                (streams, coupled_streams, mapping.to_vec())
+            }
+            _ => {
+                return Err(OpusPhraseError {
+                    opus_header_error_kind: OpusPhraseErrorKind::Unsupported,
+                    message: "Channel mapping family is not supported",
+                })
+            }
+        };
         Ok(OpusHeader {
             _version: version,
             channels,
             pre_skip,
             _input_sample_rate: input_sample_rate,
             output_gain,
-            _channel_mapping_family: channel_mapping_family,
+            channel_mapping_family,
+            streams,
+            coupled_streams,
+            mapping,
         })
     }
 }
 
+/// A link of a chained Opus file, each link has its own headers
+struct OpusLink {
+    ogg_link: OggLink,
+    opus_header: OpusHeader,
+    /// Samples of the links before this one
+    start: u64,
+    /// Length in samples
+    length: u64,
+}
+
 pub struct OpusReader {
-    ogg_reader: OggReader,
-    decoder: Decoder,
+    ogg_reader: OggReader<Box<dyn ByteSource>>,
+    /// Without seeking there is no `goto`, and the length is not known
+    seekable: bool,
+    decoder: OpusStreamDecoder,
+    /// The header of the current link
     pub opus_header: OpusHeader,
+    links: Vec<OpusLink>,
+    /// Index of the current link
+    link: usize,
+    /// The next link has another amount of channels,
+    /// it starts once the samples of the current link are given out
+    next_link: Option<usize>,
     buffer: VecDeque<BuF>,
-    package_size: usize,
-    pos: u32,
-    /// Length in samples
+    /// Granule position at the end of the decoded samples of the current link
+    granule: u64,
+    /// Length in samples, for a stream the samples that were given out
     pub length: u64,
     pub finished: bool,
+    /// Samples per channel that were not given out yet
     left: u64,
     samples: Vec<BuF>,
+    /// Samples of the decoded output that still have to be thrown away,
+    /// for the pre-skip or after a seek
+    skip: usize,
+    /// Corrupt Ogg pages that were already reported,
+    /// skipped pages give a short glitch
+    dropped_pages: u64,
+    /// Comments of the current link
+    tags: Vec<(String, String)>,
 }
 
 impl OpusReader {
-    pub fn new(file: BufReader<File>) -> Result<OpusReader> {
+    pub fn new(input: Input) -> Result<OpusReader> {
+        let seekable = input.is_seekable();
         // Ogg initialization
-        let mut ogg_reader = OggReader::try_new(file)?;
+        let mut ogg_reader = OggReader::try_new(BufReader::new(input.into_source()))?;
 
-        // Get the first package and turn it into a header
-        let opus_header = OpusHeader::new(ogg_reader.read_packet()?.0)?;
-        // Check if there is a comment stream and skip it
-        let comment_packet = ogg_reader.read_packet()?.0;
-        if !comment_packet.starts_with(b"OpusTags") {
-            // Magic Signature
+        // Get the header of every link, and the length.
+        // The links of a stream are found while reading it, its length is not known
+        let ogg_links = match seekable {
+            true => ogg_reader.links()?,
+            false => vec![ogg_reader.current_link()],
+        };
+        let mut links = vec![];
+        let mut length = 0;
+        for ogg_link in ogg_links {
+            let link = opus_link(ogg_link, length)?;
+            length += link.length;
+            links.push(link);
+        }
+        let Some(first) = links.first() else {
             Err(OpusPhraseError {
                 opus_header_error_kind: OpusPhraseErrorKind::NotValid,
-                message: "No Magic Signature \"OpusTags\" found",
-            })?;
-        }
-
-        // Get length
-        let length = ogg_reader.last_granular_position()? - opus_header.pre_skip as u64;
-
-        // Get channels
-        let channels = match opus_header.channels {
-            1 => Channels::Mono,
-            2 => Channels::Stereo,
-            _ => Err(OpusPhraseError {
-                opus_header_error_kind: OpusPhraseErrorKind::Unsupported,
-                message: "Unsupported amount of channels",
-            })?,
-        };
-
-        // Setup decoder
-        let mut decoder = match Decoder::new(channels, SampleRate::Hz48000) {
-            Ok(it) => it,
-            Err(err) => return Err(anyhow!(err.message())),
-        };
-        match decoder.set_gain(opus_header.output_gain as i32) {
-            Ok(it) => it,
-            Err(err) => return Err(anyhow!(err.message())),
+                message: "No Opus stream found",
+            })?
         };
+        let opus_header = first.opus_header.clone();
+        let decoder = OpusStreamDecoder::new(&opus_header)?;
 
-        let mut pos = 0;
-
-        // Create buffer and fill with first decoder output
-        let mut buffer = Vec::new();
-        let packet = ogg_reader.read_packet()?.0;
-        let package_size = decoder.get_nb_samples(packet).to_err()?;
-        let mut samples = vec![0f32; package_size * opus_header.channels as usize];
-
-        decoder
-            .decode_float_to_slice(packet, &mut samples, false)
-            .to_err()?;
-        buffer.extend(samples.iter());
-        pos += 1;
-        // remove the pre-skip from the buffer
-        while buffer.len() < opus_header.pre_skip as usize {
-            let packet = &ogg_reader.read_packet()?.0;
-            decoder
-                .decode_float_to_slice(packet, &mut samples, false)
-                .to_err()?;
-            buffer.extend(samples.iter());
-        }
-        buffer.drain(0..opus_header.pre_skip as usize);
-
-        // return the Opus reader
-        Ok(OpusReader {
+        let mut reader = OpusReader {
             ogg_reader,
+            seekable,
             decoder,
             opus_header,
-            buffer: buffer.into(),
-            package_size,
+            links,
+            link: 0,
+            next_link: None,
+            buffer: VecDeque::new(),
+            granule: 0,
             length,
-            pos,
             finished: false,
             left: length,
-            samples,
-        })
+            samples: vec![],
+            skip: 0,
+            dropped_pages: 0,
+            tags: vec![],
+        };
+        // Skip the header that was already read
+        reader.ogg_reader.read_packet()?;
+        reader.start_link(0)?;
+        Ok(reader)
     }
 
-    fn add_buffer(&mut self) -> Result<()> {
-        let packet = &self.ogg_reader.read_packet()?;
-        self.pos += 1;
+    /// Set up the decoder for a link, after its OpusHead packet was read
+    fn start_link(&mut self, link: usize) -> Result<()> {
+        // Check if there is a comment stream and read the comments
+        let packet = self.ogg_reader.read_packet()?;
+        if !packet.data.starts_with(b"OpusTags") {
+            // Magic Signature
+            Err(OpusPhraseError {
+                opus_header_error_kind: OpusPhraseErrorKind::NotValid,
+                message: "No Magic Signature \"OpusTags\" found",
+            })?;
+        }
+        self.tags = parse_tags(packet.data);
+        self.set_link(link)
+    }
 
-        self.decoder
-            .decode_float_to_slice(packet.0, &mut self.samples, false)
-            .to_err()?;
+    /// Use a new decoder with the header of the link
+    fn set_link(&mut self, link: usize) -> Result<()> {
+        let opus_header = self.links[link].opus_header.clone();
+        self.decoder = OpusStreamDecoder::new(&opus_header)?;
+        self.skip = opus_header.pre_skip as usize * opus_header.channels as usize;
+        self.granule = 0;
+        self.opus_header = opus_header;
+        self.link = link;
+        Ok(())
+    }
 
-        if packet.1
-        // Are we in the last page?
-        {
-            // last package length
-            let last = self.length % self.package_size as u64;
-            self.finished = true;
-            self.left = last;
-            // Remove samples that are not part of the song
-            self.buffer
-                .extend(self.samples[self.package_size - last as usize..self.package_size].iter());
-        } else {
-            self.left = self.left.saturating_sub(self.package_size as u64);
-            self.buffer.extend(self.samples.iter());
+    /// The current link is the last one, a stream can always have more links
+    fn last_link(&self) -> bool {
+        self.seekable && self.link + 1 == self.links.len()
+    }
+
+    /// The link with the bitstream that is being read, the links after the current one come first
+    fn current_link(&self) -> Result<usize> {
+        let bitstream = self.ogg_reader.bitstream();
+        let (before, after) = self.links.split_at(self.link + 1);
+        after
+            .iter()
+            .position(|x| x.ogg_link.bitstream == bitstream)
+            .map(|x| x + before.len())
+            .or_else(|| {
+                before
+                    .iter()
+                    .position(|x| x.ogg_link.bitstream == bitstream)
+            })
+            .ok_or(anyhow!("Ogg stream {bitstream} is not part of the chain"))
+    }
+
+    fn add_buffer(&mut self) -> Result<()> {
+        // Pages skipped while reading the last packets
+        let dropped = self.ogg_reader.dropped_pages();
+        if dropped != self.dropped_pages {
+            warn!("Skipped {} corrupt Ogg pages", dropped - self.dropped_pages);
+            self.dropped_pages = dropped;
+        }
+        let packet = match self.ogg_reader.read_packet() {
+            Ok(packet) => packet,
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+                // Nothing left to read
+                self.finished = true;
+                return Ok(());
+            }
+            Err(err) => return Err(err.into()),
         };
+        if packet.first {
+            // The next link of the chain starts
+            let head = packet.data.to_vec();
+            let link = match self.current_link() {
+                Ok(link) => link,
+                // The links of a stream are found while reading it
+                Err(_) if !self.seekable => {
+                    let ogg_link = OggLink {
+                        bitstream: self.ogg_reader.bitstream(),
+                        position: 0,
+                        end: 0,
+                        last_granule: 0,
+                        head,
+                    };
+                    let link = opus_link(ogg_link, self.length)?;
+                    self.links.push(link);
+                    self.links.len() - 1
+                }
+                Err(err) => return Err(err),
+            };
+            if self.links[link].opus_header.channels != self.opus_header.channels {
+                self.next_link = Some(link);
+                return Ok(());
+            }
+            return self.start_link(link);
+        }
+        let last = packet.last;
+        let page_granule = packet.granule;
+        // Frame sizes can change from packet to packet
+        let channels = self.opus_header.channels as usize;
+        let samples = self.decoder.get_nb_samples(packet.data)?;
+        if self.samples.len() < samples * channels {
+            self.samples.resize(samples * channels, 0.0);
+        }
+        let decoded = self
+            .decoder
+            .decode_float_to_slice(packet.data, &mut self.samples[..samples * channels])?;
+
+        let start_granule = self.granule;
+        self.granule += decoded as u64;
+        let mut end = decoded * channels;
+        if let Some(granule) = page_granule {
+            if last {
+                // Remove samples after the end of the stream
+                end = granule.saturating_sub(start_granule).min(decoded as u64) as usize * channels;
+                if self.last_link() {
+                    self.finished = true;
+                }
+            }
+            // Follow the stream when packets were lost
+            self.granule = granule;
+        } else if last && self.last_link() {
+            self.finished = true;
+        }
+        let start = self.skip.min(end);
+        self.skip -= start;
+        self.buffer.extend(self.samples[start..end].iter());
         Ok(())
     }
 
+    /// Amount of corrupt Ogg pages that were skipped
+    pub fn dropped_pages(&self) -> u64 {
+        self.ogg_reader.dropped_pages()
+    }
+}
+
+impl AudioDecoder for OpusReader {
     /// Go to the target sample
-    pub fn goto(&mut self, target: u64) -> Result<()> {
-        let gran = if target > self.ogg_reader.granular_position() {
-            self.ogg_reader.find_granular_position_last(target, true)?
-        } else {
-            self.ogg_reader.find_granular_position_last(target, false)?
-        };
-        self.left = self.length - gran;
-        let off = target - gran;
-        // Skip packets
-        let to_skip_packets = off / self.package_size as u64;
-        for _ in 0..to_skip_packets {
-            self.ogg_reader.read_packet()?;
+    ///
+    /// Decoding starts with a new decoder at least the pre-roll before the target,
+    /// the samples before the target are thrown away
+    fn goto(&mut self, target: u64) -> Result<()> {
+        if !self.seekable {
+            return Err(anyhow!("Can't seek in an Opus stream"));
         }
-        // Refill buffer and skip samples
-        let to_skip_samples = (off % self.package_size as u64) as usize;
+        let target = target.min(self.length);
+        let link = self
+            .links
+            .iter()
+            .rposition(|x| x.start <= target)
+            .unwrap_or(0);
+        let ogg_link = self.links[link].ogg_link.clone();
+        self.next_link = None;
+        self.set_link(link)?;
+
+        let target_granule = target - self.links[link].start + self.opus_header.pre_skip as u64;
+        let gran = self
+            .ogg_reader
+            .seek_granule(&ogg_link, target_granule.saturating_sub(PRE_ROLL))?;
+        self.left = self.length - target;
+        self.finished = self.left == 0;
+        self.granule = gran;
+        self.skip = (target_granule - gran) as usize * self.opus_header.channels as usize;
         self.buffer.clear();
-        while self.buffer.len() < to_skip_samples {
-            self.add_buffer()?;
+        // Decode the pre-roll right away, the buffer then starts at the target
+        let mut errors = 0;
+        while self.skip > 0 && !self.finished && errors < MAXERROR {
+            if let Err(err) = self.add_buffer() {
+                warn!("decode error: {}", err);
+                errors += 1;
+            }
         }
-        self.buffer.drain(0..to_skip_samples);
         Ok(())
     }
 
-    /// Fill external data with the internal buffer
+    /// The links of a chained file, empty when there is only one link
+    fn chapters(&self) -> Vec<Chapter> {
+        if !self.seekable || self.links.len() < 2 {
+            return vec![];
+        }
+        self.links
+            .iter()
+            .map(|x| Chapter {
+                start: x.start,
+                length: x.length,
+            })
+            .collect()
+    }
+
+    /// Fill data from the internal buffer, decoding packets until it has enough samples
     ///
-    /// Will fill up the internal buffer first, so it has enough samples
-    /// to fill the data
-    pub fn fill(&mut self, data: &mut [BuF]) -> Result<u64> {
+    /// Returns the amount of samples written, which is only less than the length of data
+    /// at the end of the stream or before a link with another amount of channels
+    fn fill_available(&mut self, data: &mut [BuF]) -> Result<usize> {
         let mut errors = 0;
-        while data.len() > self.buffer.len() && !self.finished && errors < MAXERROR {
+        while data.len() > self.buffer.len()
+            && !self.finished
+            && self.next_link.is_none()
+            && errors < MAXERROR
+        {
             if let Err(err) = self.add_buffer() {
                 warn!("decode error: {}", err);
                 errors += 1;
             };
         }
-        for i in data.iter_mut() {
-            *i = self.buffer.pop_front().unwrap_or(Sample::EQUILIBRIUM)
+        let amount = data.len().min(self.buffer.len());
+        for (i, sample) in data.iter_mut().zip(self.buffer.drain(..amount)) {
+            *i = sample
         }
-        Ok(self.left)
+        let channels = self.opus_header.channels as usize;
+        self.left = self.left.saturating_sub((amount / channels.max(1)) as u64);
+        if !self.seekable {
+            // The length of a stream is what was given out so far
+            self.length += (amount / channels.max(1)) as u64;
+        }
+        if self.finished && self.buffer.is_empty() {
+            self.left = 0;
+        }
+        if self.buffer.is_empty() {
+            if let Some(link) = self.next_link.take() {
+                self.start_link(link)?;
+                info!(
+                    "Opus link {link} has {} channels",
+                    self.opus_header.channels
+                );
+            }
+        }
+        Ok(amount)
+    }
+
+    /// Samples left in the stream
+    fn left(&self) -> u64 {
+        self.left
+    }
+
+    fn length(&self) -> u64 {
+        self.length
+    }
+
+    fn length_is_estimate(&self) -> bool {
+        !self.seekable && (!self.finished || !self.buffer.is_empty())
+    }
+
+    fn channels(&self) -> usize {
+        self.opus_header.channels as usize
+    }
+
+    fn sample_rate(&self) -> usize {
+        48000
+    }
+
+    fn finished(&self) -> bool {
+        self.finished
+    }
+
+    fn name(&self) -> &str {
+        "Opus"
+    }
+
+    /// The comments of the current link
+    fn tags(&self) -> Vec<(String, String)> {
+        self.tags.clone()
     }
 }
 
-trait ToErr<T>
-where
-    Self: std::marker::Sized,
-{
-    fn to_err(self) -> anyhow::Result<T>;
+/// Read the header of a link, the links can have different amounts of channels
+fn opus_link(ogg_link: OggLink, start: u64) -> Result<OpusLink> {
+    let opus_header = OpusHeader::new(&ogg_link.head)?;
+    let length = ogg_link
+        .last_granule
+        .saturating_sub(opus_header.pre_skip as u64);
+    Ok(OpusLink {
+        ogg_link,
+        opus_header,
+        start,
+        length,
+    })
 }
 
-impl<T> ToErr<T> for std::result::Result<T, opusic_c::ErrorCode> {
-    fn to_err(self) -> anyhow::Result<T> {
-        self.map_err(|err| anyhow!(err.message()))
+/// Read the comments of an OpusTags packet, a comment that is cut off ends the list
+fn parse_tags(data: &[u8]) -> Vec<(String, String)> {
+    let mut tags = vec![];
+    // Skip the magic signature and the vendor string
+    let Some(vendor) = data.get(8..12).map(LittleEndian::read_u32) else {
+        return tags;
+    };
+    let mut position = 12 + vendor as usize;
+    let Some(count) = data.get(position..position + 4).map(LittleEndian::read_u32) else {
+        return tags;
+    };
+    position += 4;
+    for _ in 0..count {
+        let Some(length) = data.get(position..position + 4).map(LittleEndian::read_u32) else {
+            break;
+        };
+        position += 4;
+        let Some(comment) = data.get(position..position + length as usize) else {
+            break;
+        };
+        position += length as usize;
+        let comment = String::from_utf8_lossy(comment);
+        if let Some((key, value)) = comment.split_once('=') {
+            tags.push((key.to_string(), value.to_string()));
+        }
     }
+    tags
 }
@@ -0,0 +1,367 @@
+use anyhow::{anyhow, Result};
+use opusic_c::{Channels, Decoder, SampleRate};
+
+use crate::BuF;
+
+use super::opus_decoder::OpusHeader;
+
+/// Most samples per channel in one Opus packet, 120 ms at 48 kHz
+const MAX_PACKET_SAMPLES: usize = 5760;
+
+/// Channel in the Vorbis order for every channel in the WAV order, used by mapping family 1
+const VORBIS_TO_WAV: [&[usize]; 9] = [
+    &[],
+    &[0],
+    &[0, 1],
+    // L C R
+    &[0, 2, 1],
+    // FL FR RL RR
+    &[0, 1, 2, 3],
+    // FL C FR RL RR
+    &[0, 2, 1, 3, 4],
+    // FL C FR RL RR LFE
+    &[0, 2, 1, 5, 3, 4],
+    // FL C FR SL SR RC LFE
+    &[0, 2, 1, 6, 5, 3, 4],
+    // FL C FR SL SR RL RR LFE
+    &[0, 2, 1, 7, 5, 6, 3, 4],
+];
+
+/// Decodes the streams of an Opus packet, every stream is decoded on its own
+/// and its channels are put in place according to the channel mapping
+///
+/// Channels of mapping family 1 are put in the WAV order
+pub struct OpusStreamDecoder {
+    /// The coupled (stereo) streams come first
+    streams: Vec<Decoder>,
+    coupled_streams: usize,
+    /// Stream and channel in that stream for every output channel, `None` is silence
+    sources: Vec<Option<(usize, usize)>>,
+    stream_samples: Vec<BuF>,
+    packet: Vec<u8>,
+}
+
+impl OpusStreamDecoder {
+    pub fn new(opus_header: &OpusHeader) -> Result<OpusStreamDecoder> {
+        let streams = opus_header.streams as usize;
+        let coupled_streams = opus_header.coupled_streams as usize;
+        let mut decoders = Vec::with_capacity(streams);
+        for stream in 0..streams {
+            let channels = match stream < coupled_streams {
+                true => Channels::Stereo,
+                false => Channels::Mono,
+            };
+            let mut decoder = Decoder::new(channels, SampleRate::Hz48000)
+                .map_err(|err| anyhow!(err.message()))?;
+            decoder
+                .set_gain(opus_header.output_gain as i32)
+                .map_err(|err| anyhow!(err.message()))?;
+            decoders.push(decoder);
+        }
+
+        let mut sources: Vec<Option<(usize, usize)>> = opus_header
+            .mapping
+            .iter()
+            .map(|x| match *x as usize {
+                255 => None,
+                x if x < 2 * coupled_streams => Some((x / 2, x % 2)),
+                x => Some((x - coupled_streams, 0)),
+            })
+            .collect();
+        if opus_header.channel_mapping_family == 1 {
+            sources = VORBIS_TO_WAV[sources.len()]
+                .iter()
+                .map(|x| sources[*x])
+                .collect();
+        }
+
+        Ok(OpusStreamDecoder {
+            streams: decoders,
+            coupled_streams,
+            sources,
+            stream_samples: vec![0.0; MAX_PACKET_SAMPLES * 2],
+            packet: Vec::new(),
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.sources.len()
+    }
+
+    /// Samples per channel in the packet
+    pub fn get_nb_samples(&self, packet: &[u8]) -> Result<usize> {
+        // Only the frame count of the first stream is read, which is the same for all streams
+        self.streams[0]
+            .get_nb_samples(packet)
+            .map_err(|err| anyhow!(err.message()))
+    }
+
+    /// Decode a packet with all streams into interleaved output,
+    /// returns the amount of samples per channel
+    pub fn decode_float_to_slice(&mut self, packet: &[u8], output: &mut [BuF]) -> Result<usize> {
+        let channels = self.channels();
+        let samples = output.len() / channels.max(1);
+        let mut data = packet;
+        let mut decoded = samples;
+        for stream in 0..self.streams.len() {
+            // All streams but the last use self-delimiting framing
+            let stream_packet = if stream + 1 < self.streams.len() {
+                let length = undelimit(data, &mut self.packet)
+                    .ok_or(anyhow!("Opus multistream packet is not valid"))?;
+                data = &data[length..];
+                &self.packet[..]
+            } else {
+                data
+            };
+            let stream_channels = if stream < self.coupled_streams { 2 } else { 1 };
+            let stream_samples =
+                &mut self.stream_samples[..samples.min(MAX_PACKET_SAMPLES) * stream_channels];
+            decoded = self.streams[stream]
+                .decode_float_to_slice(stream_packet, stream_samples, false)
+                .map_err(|err| anyhow!(err.message()))?;
+            for (channel, source) in self.sources.iter().enumerate() {
+                match source {
+                    Some((source_stream, source_channel)) if *source_stream == stream => {
+                        for (frame, sample) in output
+                            .chunks_exact_mut(channels)
+                            .zip(stream_samples.chunks_exact(stream_channels))
+                            .take(decoded)
+                        {
+                            frame[channel] = sample[*source_channel];
+                        }
+                    }
+                    None if stream == 0 => {
+                        for frame in output.chunks_exact_mut(channels).take(decoded) {
+                            frame[channel] = 0.0;
+                        }
+                    }
+                    _ => (),
+                }
+            }
+        }
+        Ok(decoded)
+    }
+}
+
+/// Read a frame length of the Opus framing, returns the length and the bytes it uses
+fn frame_length(data: &[u8]) -> Option<(usize, usize)> {
+    let first = *data.first()? as usize;
+    if first < 252 {
+        Some((first, 1))
+    } else {
+        Some((first + 4 * *data.get(1)? as usize, 2))
+    }
+}
+
+/// Turn a packet with self-delimiting framing (RFC 6716 appendix B) into a normal packet,
+/// returns the length of the self-delimited packet
+fn undelimit(data: &[u8], packet: &mut Vec<u8>) -> Option<usize> {
+    packet.clear();
+    let toc = *data.first()?;
+    packet.push(toc);
+    let mut position = 1;
+    let data_length = match toc & 3 {
+        // One frame
+        0 => {
+            let (length, used) = frame_length(&data[position..])?;
+            position += used;
+            length
+        }
+        // Two frames of the same length
+        1 => {
+            let (length, used) = frame_length(&data[position..])?;
+            position += used;
+            2 * length
+        }
+        // Two frames, the length of the second one is added
+        2 => {
+            let (first, used) = frame_length(&data[position..])?;
+            packet.extend_from_slice(&data[position..position + used]);
+            position += used;
+            let (second, used) = frame_length(data.get(position..)?)?;
+            position += used;
+            first + second
+        }
+        // Any amount of frames, with padding
+        _ => {
+            let count = *data.get(position)?;
+            packet.push(count);
+            position += 1;
+            let frames = (count & 0x3f) as usize;
+            let mut padding = 0;
+            if count & 0x40 != 0 {
+                loop {
+                    let byte = *data.get(position)?;
+                    packet.push(byte);
+                    position += 1;
+                    padding += if byte == 255 { 254 } else { byte as usize };
+                    if byte != 255 {
+                        break;
+                    }
+                }
+            }
+            if count & 0x80 != 0 {
+                // Variable bitrate, the length of the last frame is added
+                let mut total = 0;
+                for _ in 1..frames {
+                    let (length, used) = frame_length(data.get(position..)?)?;
+                    packet.extend_from_slice(&data[position..position + used]);
+                    position += used;
+                    total += length;
+                }
+                let (length, used) = frame_length(data.get(position..)?)?;
+                position += used;
+                total + length + padding
+            } else {
+                // Constant bitrate, the length of all frames is added
+                let (length, used) = frame_length(data.get(position..)?)?;
+                position += used;
+                frames * length + padding
+            }
+        }
+    };
+    let end = position + data_length;
+    packet.extend_from_slice(data.get(position..end)?);
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TOC of a 20 ms CELT fullband frame
+    const TOC: u8 = 0xf8;
+
+    fn header(family: u8, streams: u8, coupled_streams: u8, mapping: &[u8]) -> OpusHeader {
+        let mut header = b"OpusHead\x01".to_vec();
+        header.push(mapping.len() as u8);
+        header.extend_from_slice(&[0; 8]);
+        header.extend_from_slice(&[family, streams, coupled_streams]);
+        header.extend_from_slice(mapping);
+        OpusHeader::new(&header).unwrap()
+    }
+
+    /// The normal packet and the length of the self-delimited one, followed by the next stream
+    fn undelimited(data: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let mut data = data.to_vec();
+        data.extend_from_slice(&[TOC, 9, 9]);
+        let mut packet = vec![];
+        let length = undelimit(&data, &mut packet)?;
+        Some((packet, length))
+    }
+
+    #[test]
+    fn one_frame() {
+        assert_eq!(
+            undelimited(&[TOC, 3, 1, 2, 3]),
+            Some((vec![TOC, 1, 2, 3], 5))
+        );
+        // A length of two bytes, 252 + 4 * 12
+        let mut data = vec![TOC, 252, 12];
+        data.resize(3 + 300, 7);
+        let (packet, length) = undelimited(&data).unwrap();
+        assert_eq!(length, 303);
+        assert_eq!(packet.len(), 301);
+        assert!(packet[1..].iter().all(|x| *x == 7));
+    }
+
+    #[test]
+    fn two_frames_of_the_same_length() {
+        assert_eq!(
+            undelimited(&[TOC | 1, 2, 1, 2, 3, 4]),
+            Some((vec![TOC | 1, 1, 2, 3, 4], 6))
+        );
+    }
+
+    #[test]
+    fn two_frames_of_different_lengths() {
+        // The length of the first frame stays, the length of the second one is removed
+        assert_eq!(
+            undelimited(&[TOC | 2, 1, 3, 1, 2, 3, 4]),
+            Some((vec![TOC | 2, 1, 1, 2, 3, 4], 7))
+        );
+    }
+
+    #[test]
+    fn constant_bitrate_frames() {
+        assert_eq!(
+            undelimited(&[TOC | 3, 0x02, 2, 1, 2, 3, 4]),
+            Some((vec![TOC | 3, 0x02, 1, 2, 3, 4], 7))
+        );
+        // With 3 bytes of padding
+        assert_eq!(
+            undelimited(&[TOC | 3, 0x42, 3, 2, 1, 2, 3, 4, 0, 0, 0]),
+            Some((vec![TOC | 3, 0x42, 3, 1, 2, 3, 4, 0, 0, 0], 11))
+        );
+        // 255 adds 254 bytes of padding and is followed by another padding byte
+        let mut data = vec![TOC | 3, 0x41, 255, 1, 2, 1, 2];
+        data.resize(data.len() + 255, 0);
+        let (packet, length) = undelimited(&data).unwrap();
+        assert_eq!(length, data.len());
+        assert_eq!(packet[..6], [TOC | 3, 0x41, 255, 1, 1, 2]);
+        assert_eq!(packet.len(), data.len() - 1);
+    }
+
+    #[test]
+    fn variable_bitrate_frames() {
+        // Only the length of the last frame is removed
+        assert_eq!(
+            undelimited(&[TOC | 3, 0x83, 1, 2, 3, 1, 2, 3, 4, 5, 6]),
+            Some((vec![TOC | 3, 0x83, 1, 2, 1, 2, 3, 4, 5, 6], 11))
+        );
+        assert_eq!(
+            undelimited(&[TOC | 3, 0xc2, 2, 1, 1, 1, 2, 0, 0]),
+            Some((vec![TOC | 3, 0xc2, 2, 1, 1, 2, 0, 0], 9))
+        );
+    }
+
+    #[test]
+    fn cut_off_packets_are_not_valid() {
+        let mut packet = vec![];
+        for data in [
+            &[][..],
+            &[TOC, 5, 1],
+            &[TOC | 2, 1],
+            &[TOC | 3],
+            &[TOC | 3, 0x83, 1],
+        ] {
+            assert_eq!(undelimit(data, &mut packet), None, "{data:?}");
+        }
+    }
+
+    #[test]
+    fn vorbis_order_becomes_wav_order() {
+        // L C R RL RR LFE, the front and rear pairs are coupled
+        let decoder = OpusStreamDecoder::new(&header(1, 4, 2, &[0, 4, 1, 2, 3, 5])).unwrap();
+        assert_eq!(
+            decoder.sources,
+            [
+                Some((0, 0)),
+                Some((0, 1)),
+                Some((2, 0)),
+                Some((3, 0)),
+                Some((1, 0)),
+                Some((1, 1)),
+            ]
+        );
+        // Without a defined meaning the order stays
+        let decoder = OpusStreamDecoder::new(&header(255, 2, 1, &[2, 255, 0])).unwrap();
+        assert_eq!(decoder.sources, [Some((1, 0)), None, Some((0, 0))]);
+    }
+
+    #[test]
+    fn streams_are_put_in_place() {
+        // L C R, the centre is silent
+        let mut decoder = OpusStreamDecoder::new(&header(1, 2, 1, &[0, 255, 1])).unwrap();
+        assert_eq!(decoder.channels(), 3);
+        // A self-delimited stereo frame without data, then a mono one
+        let packet = [TOC | 0x04, 0, TOC];
+        let mut output = vec![BuF::NAN; MAX_PACKET_SAMPLES * 3];
+        let decoded = decoder.decode_float_to_slice(&packet, &mut output).unwrap();
+        assert_eq!(decoded, 960);
+        let frames = output[..decoded * 3].chunks_exact(3);
+        assert!(frames.clone().all(|x| x.iter().all(|x| x.is_finite())));
+        assert!(frames.clone().all(|x| x[2] == 0.0));
+        assert!(output[decoded * 3..].iter().all(|x| x.is_nan()));
+    }
+}
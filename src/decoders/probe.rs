@@ -0,0 +1,298 @@
+use std::fs::File;
+use std::io::{Read, Result, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder};
+
+/// Bytes read from the start of a file to recognise the format
+const PROBE_LENGTH: usize = 64;
+/// Length of an ID3v2 header, and of its footer
+const ID3_HEADER_LENGTH: u64 = 10;
+
+/// Audio formats that are recognised by their first bytes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioFormat {
+    /// Opus in Ogg
+    Opus,
+    /// Vorbis, FLAC or another codec in Ogg
+    Ogg,
+    Flac,
+    Wav,
+    Aiff,
+    Caf,
+    /// MP4 and M4A
+    Mp4,
+    /// Matroska and WebM
+    Matroska,
+    /// MPEG audio layer 1, 2 and 3
+    Mp3,
+    /// AAC in ADTS frames
+    Aac,
+}
+
+impl AudioFormat {
+    /// The file extension, used as a hint for Symphonia
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Opus => "opus",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Aiff => "aiff",
+            AudioFormat::Caf => "caf",
+            AudioFormat::Mp4 => "m4a",
+            AudioFormat::Matroska => "mkv",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::Aac => "aac",
+        }
+    }
+
+    /// Recognise the format from the first bytes of a file,
+    /// does not look past ID3 tags (see [`probe_file`])
+    pub fn from_magic(data: &[u8]) -> Option<AudioFormat> {
+        if data.starts_with(b"OggS") {
+            // The first packet starts after the segment table of the first page
+            let segments = *data.get(26)? as usize;
+            let packet = data.get(27 + segments..)?;
+            return Some(match packet.starts_with(b"OpusHead") {
+                true => AudioFormat::Opus,
+                false => AudioFormat::Ogg,
+            });
+        }
+        if data.starts_with(b"fLaC") {
+            return Some(AudioFormat::Flac);
+        }
+        if (data.starts_with(b"RIFF") || data.starts_with(b"RF64"))
+            && matches!(data.get(8..12), Some(b"WAVE"))
+        {
+            return Some(AudioFormat::Wav);
+        }
+        if data.starts_with(b"FORM") && matches!(data.get(8..12), Some(b"AIFF" | b"AIFC")) {
+            return Some(AudioFormat::Aiff);
+        }
+        if data.starts_with(b"caff") {
+            return Some(AudioFormat::Caf);
+        }
+        if matches!(data.get(4..8), Some(b"ftyp")) {
+            return Some(AudioFormat::Mp4);
+        }
+        if data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+            return Some(AudioFormat::Matroska);
+        }
+        // Frame sync, 11 or 12 bits set
+        match data {
+            [0xff, second, ..] if second & 0xf6 == 0xf0 => Some(AudioFormat::Aac),
+            // Layer 00 is reserved
+            [0xff, second, ..] if second & 0xe0 == 0xe0 && second & 0x06 != 0 => {
+                Some(AudioFormat::Mp3)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Recognise the audio format of a file by its content, the name of the file is not used
+///
+/// ID3v2 tags in front of the audio are skipped, returns `None` when the format is not known
+pub fn probe_file(path: &Path) -> Result<Option<AudioFormat>> {
+    probe(&mut File::open(path)?)
+}
+
+/// Recognise the audio format from the current position of the source,
+/// the source is back at that position afterwards
+pub fn probe<R: Read + Seek>(source: &mut R) -> Result<Option<AudioFormat>> {
+    let start = source.stream_position()?;
+    let mut data = [0; PROBE_LENGTH];
+    let mut length = read_start(source, &mut data)?;
+    // There can be more than one tag
+    while let Some(size) = id3_size(&data[..length]) {
+        source.seek(SeekFrom::Current(size as i64 - length as i64))?;
+        length = read_start(source, &mut data)?;
+    }
+    source.seek(SeekFrom::Start(start))?;
+    Ok(AudioFormat::from_magic(&data[..length]))
+}
+
+/// Recognise the audio format of a stream, returns the bytes that were read from it
+pub fn probe_stream<R: Read>(stream: &mut R) -> Result<(Option<AudioFormat>, Vec<u8>)> {
+    let mut read = vec![];
+    let mut data = [0; PROBE_LENGTH];
+    let mut length = read_start(stream, &mut data)?;
+    read.extend_from_slice(&data[..length]);
+    while let Some(size) = id3_size(&data[..length]) {
+        let kept = match usize::try_from(size).ok().filter(|x| *x < length) {
+            // The tag ends in what was read, the rest of it comes after the tag
+            Some(size) => {
+                data.copy_within(size..length, 0);
+                length - size
+            }
+            // The tag has to be read to get past it
+            None => {
+                let rest = size - length as u64;
+                stream.by_ref().take(rest).read_to_end(&mut read)?;
+                0
+            }
+        };
+        let more = read_start(stream, &mut data[kept..])?;
+        read.extend_from_slice(&data[kept..kept + more]);
+        length = kept + more;
+    }
+    Ok((AudioFormat::from_magic(&data[..length]), read))
+}
+
+/// Length of the ID3v2 tag the data starts with, with its header and footer
+fn id3_size(data: &[u8]) -> Option<u64> {
+    if !data.starts_with(b"ID3") || data.len() < ID3_HEADER_LENGTH as usize {
+        return None;
+    }
+    let footer = match data[5] & 0x10 != 0 {
+        true => ID3_HEADER_LENGTH,
+        false => 0,
+    };
+    Some(ID3_HEADER_LENGTH + syncsafe(&data[6..10]) + footer)
+}
+
+/// Read as much of the buffer as the source has, returns the amount read
+fn read_start<R: Read>(source: &mut R, data: &mut [u8]) -> Result<usize> {
+    let mut length = 0;
+    while length < data.len() {
+        match source.read(&mut data[length..])? {
+            0 => break,
+            read => length += read,
+        }
+    }
+    Ok(length)
+}
+
+/// A size of an ID3v2 tag, 7 bits of every byte are used
+fn syncsafe(data: &[u8]) -> u64 {
+    let value = BigEndian::read_u32(data);
+    (((value & 0x7f00_0000) >> 3)
+        | ((value & 0x007f_0000) >> 2)
+        | ((value & 0x0000_7f00) >> 1)
+        | (value & 0x0000_007f)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// The start of an Ogg page with one segment, holding the packet
+    fn ogg_page(packet: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.resize(26, 0);
+        page.extend_from_slice(&[1, packet.len() as u8]);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    /// An ID3v2 tag with the body, with or without a footer
+    fn id3_tag(body: usize, footer: bool) -> Vec<u8> {
+        let size = body as u32;
+        let mut tag = b"ID3".to_vec();
+        tag.extend_from_slice(&[4, 0, if footer { 0x10 } else { 0 }]);
+        tag.extend_from_slice(&[
+            (size >> 21) as u8 & 0x7f,
+            (size >> 14) as u8 & 0x7f,
+            (size >> 7) as u8 & 0x7f,
+            size as u8 & 0x7f,
+        ]);
+        tag.resize(tag.len() + body, 0);
+        if footer {
+            tag.extend_from_slice(b"3DI");
+            tag.resize(tag.len() + 7, 0);
+        }
+        tag
+    }
+
+    fn wav_header(magic: &[u8; 4]) -> Vec<u8> {
+        let mut header = magic.to_vec();
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(b"WAVEfmt ");
+        header
+    }
+
+    #[test]
+    fn formats_by_magic() {
+        let cases: Vec<(Vec<u8>, Option<AudioFormat>)> = vec![
+            (ogg_page(b"OpusHead\x01\x02"), Some(AudioFormat::Opus)),
+            (ogg_page(b"\x01vorbis\x00\x00"), Some(AudioFormat::Ogg)),
+            (ogg_page(b"\x7fFLAC\x01\x00"), Some(AudioFormat::Ogg)),
+            // The segment table is cut off
+            (b"OggS".to_vec(), None),
+            (b"fLaC\x00\x00\x00\x22".to_vec(), Some(AudioFormat::Flac)),
+            (wav_header(b"RIFF"), Some(AudioFormat::Wav)),
+            (wav_header(b"RF64"), Some(AudioFormat::Wav)),
+            (b"RIFF\x00\x00\x00\x00AVI ".to_vec(), None),
+            (
+                b"FORM\x00\x00\x00\x00AIFC".to_vec(),
+                Some(AudioFormat::Aiff),
+            ),
+            (b"caff\x00\x01".to_vec(), Some(AudioFormat::Caf)),
+            (b"\x00\x00\x00\x20ftypM4A ".to_vec(), Some(AudioFormat::Mp4)),
+            (vec![0x1a, 0x45, 0xdf, 0xa3], Some(AudioFormat::Matroska)),
+            // ADTS, MPEG-4 and MPEG-2 without CRC
+            (vec![0xff, 0xf1, 0x50, 0x80], Some(AudioFormat::Aac)),
+            (vec![0xff, 0xf9, 0x50, 0x80], Some(AudioFormat::Aac)),
+            // MPEG-1 layer 3 and MPEG-2 layer 2
+            (vec![0xff, 0xfb, 0x90, 0x64], Some(AudioFormat::Mp3)),
+            (vec![0xff, 0xf4, 0x90, 0x64], Some(AudioFormat::Mp3)),
+            // MPEG-2.5 layer 3
+            (vec![0xff, 0xe3, 0x90, 0x64], Some(AudioFormat::Mp3)),
+            // Reserved layer
+            (vec![0xff, 0xe0, 0x90, 0x64], None),
+            (vec![0xff, 0x00], None),
+            (vec![], None),
+        ];
+        for (data, format) in cases {
+            assert_eq!(AudioFormat::from_magic(&data), format, "{data:x?}");
+        }
+    }
+
+    #[test]
+    fn id3_tag_sizes() {
+        assert_eq!(id3_size(&id3_tag(300, false)), Some(310));
+        assert_eq!(id3_size(&id3_tag(300, true)), Some(320));
+        assert_eq!(id3_size(&id3_tag(300, true)[..9]), None);
+        assert_eq!(id3_size(b"fLaC\x00\x00\x00\x22\x00\x00"), None);
+    }
+
+    #[test]
+    fn probe_skips_id3_tags() {
+        for footer in [false, true] {
+            let mut data = id3_tag(200, footer);
+            data.extend(id3_tag(5, !footer));
+            data.extend_from_slice(b"fLaC\x00\x00\x00\x22");
+            let mut source = Cursor::new(data.clone());
+            assert_eq!(probe(&mut source).unwrap(), Some(AudioFormat::Flac));
+            assert_eq!(source.position(), 0);
+
+            let (format, read) = probe_stream(&mut Cursor::new(data.clone())).unwrap();
+            assert_eq!(format, Some(AudioFormat::Flac));
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn probe_starts_at_the_position() {
+        let mut data = b"junk".to_vec();
+        data.extend(wav_header(b"RIFF"));
+        let mut source = Cursor::new(data);
+        source.set_position(4);
+        assert_eq!(probe(&mut source).unwrap(), Some(AudioFormat::Wav));
+        assert_eq!(source.position(), 4);
+    }
+
+    #[test]
+    fn file_name_is_not_used() {
+        let directory = std::env::temp_dir();
+        for name in ["TRACK.FLAC", "track"] {
+            let path = directory.join(format!("rmusic-probe-{}-{name}", std::process::id()));
+            std::fs::write(&path, wav_header(b"RIFF")).unwrap();
+            let format = probe_file(&path);
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(format.unwrap(), Some(AudioFormat::Wav));
+        }
+    }
+}
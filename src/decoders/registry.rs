@@ -0,0 +1,181 @@
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use anyhow::{anyhow, Result};
+use log::warn;
+
+use super::opus_decoder::OpusReader;
+use super::probe::{probe, probe_file, probe_stream, AudioFormat};
+use super::source::Input;
+use super::symphonia_wrap::SymphoniaWrapper;
+use super::{AudioDecoder, Decoder};
+
+/// Priority of the Opus decoder of this crate
+pub const OPUS_PRIORITY: i32 = 100;
+/// Priority of Symphonia, which is tried for every file
+pub const SYMPHONIA_PRIORITY: i32 = 0;
+
+/// Opens decoders for the formats it knows
+pub trait DecoderProvider: Send + Sync {
+    /// The provider decodes files of this format.
+    /// The format is what the content looks like, `None` when it was not recognised,
+    /// the extension is in lowercase
+    fn accepts(&self, format: Option<AudioFormat>, extension: Option<&str>) -> bool;
+
+    /// Open a decoder for the input, which is at its start
+    fn open(
+        &self,
+        input: Input,
+        format: Option<AudioFormat>,
+        extension: Option<&str>,
+    ) -> Result<Box<dyn AudioDecoder>>;
+}
+
+/// The decoder providers that are tried for every file, the highest priority first
+pub struct DecoderRegistry {
+    providers: Vec<(i32, Arc<dyn DecoderProvider>)>,
+}
+
+impl Default for DecoderRegistry {
+    /// The decoders of this crate
+    fn default() -> Self {
+        let mut registry = DecoderRegistry { providers: vec![] };
+        registry.register(OPUS_PRIORITY, Arc::new(OpusProvider));
+        registry.register(SYMPHONIA_PRIORITY, Arc::new(SymphoniaProvider));
+        registry
+    }
+}
+
+impl DecoderRegistry {
+    /// Add a provider, it is tried after providers with the same priority that were added before
+    pub fn register(&mut self, priority: i32, provider: Arc<dyn DecoderProvider>) {
+        let index = self.providers.partition_point(|(x, _)| *x >= priority);
+        self.providers.insert(index, (priority, provider));
+    }
+
+    /// Open the file with the first provider that knows the format,
+    /// a provider that fails is skipped
+    pub fn open(&self, path: &Path) -> Result<Decoder> {
+        let format = probe_file(path)?;
+        let extension = extension(path);
+        for provider in self.accepting(format, extension.as_deref()) {
+            let input = Input::seekable(File::open(path)?);
+            match provider.open(input, format, extension.as_deref()) {
+                Ok(decoder) => return Ok(Decoder::new(decoder)),
+                Err(err) => warn!("Decoder could not open {}: {:?}", path.display(), err),
+            }
+        }
+        Err(anyhow!("No decoder for {}", path.display()))
+    }
+
+    /// Open the input with the first provider that knows the format,
+    /// the extension is a hint for formats that can't be recognised
+    ///
+    /// Only one provider is tried, because the input can only be read once
+    pub fn open_input(&self, input: Input, extension: Option<&str>) -> Result<Decoder> {
+        let extension = extension.map(|x| x.to_lowercase());
+        let (format, input) = match input {
+            Input::Seekable(mut source) => (probe(&mut source)?, Input::Seekable(source)),
+            Input::Stream(mut stream) => {
+                let (format, read) = probe_stream(&mut stream)?;
+                // Give the bytes that were read back to the decoder
+                (format, Input::stream(Cursor::new(read).chain(stream)))
+            }
+        };
+        let provider = self
+            .accepting(format, extension.as_deref())
+            .next()
+            .ok_or(anyhow!("No decoder for the format {format:?}"))?;
+        Ok(Decoder::new(provider.open(
+            input,
+            format,
+            extension.as_deref(),
+        )?))
+    }
+
+    /// The providers that accept the format, the highest priority first
+    fn accepting<'a>(
+        &'a self,
+        format: Option<AudioFormat>,
+        extension: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Arc<dyn DecoderProvider>> {
+        self.providers
+            .iter()
+            .map(|(_, provider)| provider)
+            .filter(move |x| x.accepts(format, extension))
+    }
+}
+
+/// The extension of the file in lowercase
+fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|x| x.to_string_lossy().to_lowercase())
+}
+
+fn registry() -> &'static RwLock<DecoderRegistry> {
+    static REGISTRY: OnceLock<RwLock<DecoderRegistry>> = OnceLock::new();
+    REGISTRY.get_or_init(|| RwLock::new(DecoderRegistry::default()))
+}
+
+/// Add a decoder for all files that are opened from now on.
+/// Higher priorities are tried first, see [`OPUS_PRIORITY`] and [`SYMPHONIA_PRIORITY`]
+pub fn register_decoder(priority: i32, provider: impl DecoderProvider + 'static) {
+    registry()
+        .write()
+        .unwrap_or_else(|err| err.into_inner())
+        .register(priority, Arc::new(provider));
+}
+
+/// Open the file with the registered decoders
+pub fn open_decoder(path: &Path) -> Result<Decoder> {
+    registry()
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .open(path)
+}
+
+/// Open any input with the registered decoders, like a buffer in memory or an archive entry.
+/// The extension is a hint for formats that can't be recognised by their content
+pub fn open_input(input: Input, extension: Option<&str>) -> Result<Decoder> {
+    registry()
+        .read()
+        .unwrap_or_else(|err| err.into_inner())
+        .open_input(input, extension)
+}
+
+struct OpusProvider;
+
+impl DecoderProvider for OpusProvider {
+    fn accepts(&self, format: Option<AudioFormat>, _extension: Option<&str>) -> bool {
+        format == Some(AudioFormat::Opus)
+    }
+
+    fn open(
+        &self,
+        input: Input,
+        _format: Option<AudioFormat>,
+        _extension: Option<&str>,
+    ) -> Result<Box<dyn AudioDecoder>> {
+        Ok(Box::new(OpusReader::new(input)?))
+    }
+}
+
+/// Symphonia is tried for every file, it recognises formats on its own
+struct SymphoniaProvider;
+
+impl DecoderProvider for SymphoniaProvider {
+    fn accepts(&self, _format: Option<AudioFormat>, _extension: Option<&str>) -> bool {
+        true
+    }
+
+    fn open(
+        &self,
+        input: Input,
+        format: Option<AudioFormat>,
+        extension: Option<&str>,
+    ) -> Result<Box<dyn AudioDecoder>> {
+        let hint = format.map(|x| x.extension()).or(extension).unwrap_or("");
+        Ok(Box::new(SymphoniaWrapper::new(input, hint)?))
+    }
+}
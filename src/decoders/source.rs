@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+
+use symphonia::core::io::{MediaSource, ReadOnlySource};
+
+/// Bytes of a stream that are kept, so a reader can go back a little.
+/// More than the largest Ogg page and the buffer of a `BufReader`
+const LOOKBACK: usize = 128 * 1024;
+
+/// Bytes a decoder can read and seek in, like a file, a buffer in memory or an archive entry
+pub trait ByteSource: Read + Seek + Send + Sync {}
+
+impl<T: Read + Seek + Send + Sync> ByteSource for T {}
+
+/// What a decoder reads from
+pub enum Input {
+    /// The decoder can seek, go to any position and find the length
+    Seekable(Box<dyn ByteSource>),
+    /// Read once from start to end, `goto` fails and the length is not searched for
+    Stream(Box<dyn Read + Send + Sync>),
+}
+
+impl Input {
+    pub fn seekable(source: impl ByteSource + 'static) -> Input {
+        Input::Seekable(Box::new(source))
+    }
+
+    pub fn stream(stream: impl Read + Send + Sync + 'static) -> Input {
+        Input::Stream(Box::new(stream))
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        matches!(self, Input::Seekable(_))
+    }
+
+    /// The input as a seekable source, a stream can only go back a little
+    pub(super) fn into_source(self) -> Box<dyn ByteSource> {
+        match self {
+            Input::Seekable(source) => source,
+            Input::Stream(stream) => Box::new(StreamSource::new(stream)),
+        }
+    }
+
+    /// The input as a source for Symphonia
+    pub(super) fn into_media_source(self) -> Result<Box<dyn MediaSource>> {
+        Ok(match self {
+            Input::Seekable(source) => Box::new(SeekableMedia::new(source)?),
+            Input::Stream(stream) => Box::new(ReadOnlySource::new(stream)),
+        })
+    }
+}
+
+/// A stream that can seek forward, and back within the last bytes that were read
+pub struct StreamSource<R> {
+    inner: R,
+    /// The last bytes read from the inner stream
+    history: VecDeque<u8>,
+    /// Bytes read from the inner stream
+    end: u64,
+    position: u64,
+}
+
+impl<R: Read> StreamSource<R> {
+    pub fn new(inner: R) -> StreamSource<R> {
+        StreamSource {
+            inner,
+            history: VecDeque::with_capacity(LOOKBACK),
+            end: 0,
+            position: 0,
+        }
+    }
+
+    /// Read from the inner stream and remember the bytes
+    fn read_inner(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.history.extend(&buf[..read]);
+        let excess = self.history.len().saturating_sub(LOOKBACK);
+        self.history.drain(..excess);
+        self.end += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> Read for StreamSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.position < self.end {
+            // Read again what was read before
+            let start = self.history.len() - (self.end - self.position) as usize;
+            let amount = buf.len().min(self.history.len() - start);
+            for (byte, old) in buf
+                .iter_mut()
+                .zip(self.history.range(start..start + amount))
+            {
+                *byte = *old;
+            }
+            self.position += amount as u64;
+            return Ok(amount);
+        }
+        let read = self.read_inner(buf)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> Seek for StreamSource<R> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(target) => target,
+            SeekFrom::Current(offset) => self
+                .position
+                .checked_add_signed(offset)
+                .ok_or(Error::from(ErrorKind::InvalidInput))?,
+            SeekFrom::End(_) => {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "The end of a stream is not known",
+                ))
+            }
+        };
+        if target < self.end - self.history.len() as u64 {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Can't go back that far in a stream",
+            ));
+        }
+        // Skip ahead by reading
+        let mut skip = [0; 4096];
+        while self.end < target {
+            let amount = skip.len().min((target - self.end) as usize);
+            if self.read_inner(&mut skip[..amount])? == 0 {
+                return Err(Error::from(ErrorKind::UnexpectedEof));
+            }
+        }
+        self.position = target;
+        Ok(target)
+    }
+}
+
+/// A seekable source for Symphonia, the length is found once
+struct SeekableMedia {
+    source: Box<dyn ByteSource>,
+    length: Option<u64>,
+}
+
+impl SeekableMedia {
+    fn new(mut source: Box<dyn ByteSource>) -> Result<SeekableMedia> {
+        let position = source.stream_position()?;
+        let length = source.seek(SeekFrom::End(0)).ok();
+        source.seek(SeekFrom::Start(position))?;
+        Ok(SeekableMedia { source, length })
+    }
+}
+
+impl Read for SeekableMedia {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.source.read(buf)
+    }
+}
+
+impl Seek for SeekableMedia {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.source.seek(pos)
+    }
+}
+
+impl MediaSource for SeekableMedia {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.length
+    }
+}
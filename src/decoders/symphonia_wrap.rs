@@ -1,43 +1,56 @@
 use std::collections::VecDeque;
-use std::fs::File;
 use std::io::{Error, ErrorKind};
 
 use anyhow::{anyhow, Result};
-use cpal::Sample;
 use log::warn;
 use symphonia::core::audio::{SampleBuffer, SignalSpec};
-use symphonia::core::formats::{SeekMode, SeekTo};
+use symphonia::core::formats::{Packet, SeekMode, SeekTo, Track};
 use symphonia::core::{
     audio::Channels,
     codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL},
     formats::{FormatOptions, FormatReader},
-    io::MediaSourceStream,
-    meta::MetadataOptions,
+    io::{MediaSource, MediaSourceStream},
+    meta::{MetadataOptions, MetadataRevision},
     probe::Hint,
     units::TimeBase,
 };
 
 use crate::BuF;
 
-use super::MAXERROR;
+use super::source::Input;
+use super::{AudioDecoder, AudioTrack, MAXERROR};
 
 pub struct SymphoniaWrapper {
     format: Box<dyn FormatReader>,
+    /// Without seeking there is no `goto`
+    seekable: bool,
+    /// Bytes of the whole input, for the length estimate
+    byte_len: Option<u64>,
+    /// Index of the audio track that is decoded
+    audio_track: usize,
     decoder: Box<dyn Decoder>,
     track_id: u32,
     _time_base: TimeBase,
     length: u64,
+    /// Only used when the container has no length
+    estimate: Option<LengthEstimate>,
+    /// Frame after the last decoded packet
+    position: u64,
     channels: Channels,
     sample_rate: usize,
     buffer_interleaved: SampleBuffer<BuF>,
     buffer: VecDeque<BuF>,
     left: u64,
     finished: bool,
+    /// Tags of the file and of the container
+    tags: Vec<(String, String)>,
 }
 
 impl SymphoniaWrapper {
-    pub fn new(file: File, extension: &str) -> Result<SymphoniaWrapper> {
-        let media_stream = MediaSourceStream::new(Box::new(file), Default::default());
+    pub fn new(input: Input, extension: &str) -> Result<SymphoniaWrapper> {
+        let seekable = input.is_seekable();
+        let media_stream = MediaSourceStream::new(input.into_media_source()?, Default::default());
+        let byte_len = media_stream.byte_len();
         let mut hint = Hint::new();
         hint.with_extension(extension);
 
@@ -46,56 +59,50 @@ impl SymphoniaWrapper {
             enable_gapless: true,
             ..Default::default()
         };
-        let probed =
+        let mut probed =
             symphonia::default::get_probe().format(&hint, media_stream, &fmt_opts, &meta_opts)?;
+        // Tags in front of the container, like ID3
+        let mut tags = match probed.metadata.get() {
+            Some(metadata) => metadata.current().map(revision_tags).unwrap_or_default(),
+            None => vec![],
+        };
 
         let mut format = probed.format;
-        let track = format
-            .tracks()
-            .iter()
-            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        let track = decodable_tracks(format.as_ref())
+            .next()
             .ok_or(Error::new(ErrorKind::Unsupported, "Unsupported codec"))?;
-
-        // track info
-        let _time_base = track
-            .codec_params
-            .time_base
-            .ok_or(Error::new(ErrorKind::Unsupported, "No time base"))?;
-        let length = track
-            .codec_params
-            .n_frames
-            .ok_or(Error::new(ErrorKind::Unsupported, "No length"))?;
-        let channels = track
-            .codec_params
-            .channels
-            .ok_or(Error::new(ErrorKind::Unsupported, "No channels"))?;
-        let sample_rate = track
-            .codec_params
-            .sample_rate
-            .ok_or(Error::new(ErrorKind::Unsupported, "No sample_rate"))?
-            as usize;
-        let track_id = track.id;
-
-        let dec_opts = DecoderOptions::default();
-        let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts)?;
+        let TrackDecoder {
+            mut decoder,
+            track_id,
+            time_base: _time_base,
+            mut length,
+            mut estimate,
+            channels,
+            sample_rate,
+        } = TrackDecoder::new(track, byte_len)?;
 
         let mut buffer = VecDeque::new();
         let mut left;
+        let position;
 
         // decode first valid packet
         let buffer_interleaved = loop {
             let packet = format.next_packet()?;
-            // Error?
+            // Packets of the other tracks are skipped
             if packet.track_id() != track_id {
-                unimplemented!()
+                continue;
             }
             // Consume metadata
             while !format.metadata().is_latest() {
                 format.metadata().pop();
             }
-            left = length - packet.ts;
+            if let Some(estimate) = estimate.as_mut() {
+                length = estimate.update(&packet);
+            }
+            left = length.saturating_sub(packet.ts);
             match decoder.decode(&packet) {
                 Ok(decoded) => {
+                    position = packet.ts + decoded.frames() as u64;
                     let mut buffer_interleaved: SampleBuffer<BuF> = SampleBuffer::new(
                         decoded.capacity() as u64,
                         SignalSpec::new(sample_rate as u32, channels),
@@ -108,18 +115,28 @@ impl SymphoniaWrapper {
             }
         };
 
+        if let Some(revision) = format.metadata().current() {
+            tags.extend(revision_tags(revision));
+        }
+
         Ok(SymphoniaWrapper {
             format,
+            seekable,
+            byte_len,
+            audio_track: 0,
             decoder,
             track_id,
             _time_base,
             length,
+            estimate,
+            position,
             channels,
             sample_rate,
             buffer_interleaved,
             buffer,
             left,
             finished: false,
+            tags,
         })
     }
 
@@ -130,6 +147,10 @@ impl SymphoniaWrapper {
             Err(symphonia::core::errors::Error::IoError(error))
                 if error.kind() == std::io::ErrorKind::UnexpectedEof =>
             {
+                // All packets were seen, the end is the exact length
+                if let Some(estimate) = self.estimate.take() {
+                    self.length = estimate.end;
+                }
                 self.finished = true;
                 return Err(error.into());
             }
@@ -141,26 +162,59 @@ impl SymphoniaWrapper {
             }
         };
 
-        // Error?
+        // Packets of the other tracks are skipped
         if packet.track_id() != self.track_id {
-            unimplemented!()
+            return Ok(());
         }
         // Consume metadata
         while !self.format.metadata().is_latest() {
             self.format.metadata().pop();
         }
 
+        if let Some(estimate) = self.estimate.as_mut() {
+            self.length = estimate.update(&packet);
+        }
         let decoded = self.decoder.decode(&packet)?;
+        self.position = packet.ts + decoded.frames() as u64;
+
+        // Another audio track can have larger packets
+        if self.buffer_interleaved.capacity() < decoded.capacity() * decoded.spec().channels.count()
+        {
+            self.buffer_interleaved = SampleBuffer::new(decoded.capacity() as u64, *decoded.spec());
+        }
 
         self.buffer_interleaved.copy_interleaved_ref(decoded);
 
         self.buffer.extend(self.buffer_interleaved.samples());
 
-        self.left = self.length - packet.ts;
+        self.left = self.length.saturating_sub(packet.ts);
+        Ok(())
+    }
+
+    /// Go to the target by decoding and dropping everything before it,
+    /// for containers that can't seek
+    fn decode_to(&mut self, target: u64) -> Result<()> {
+        let channels = self.channels.count() as u64;
+        if target < self.position - self.buffer.len() as u64 / channels {
+            return Err(anyhow!("Can't go back without seeking"));
+        }
+        while self.position <= target && !self.finished {
+            self.buffer.clear();
+            self.add_buffer()?;
+        }
+        let front = self.position - self.buffer.len() as u64 / channels;
+        let skip = ((target.saturating_sub(front)) * channels) as usize;
+        self.buffer.drain(..skip.min(self.buffer.len()));
         Ok(())
     }
+}
 
-    pub fn fill(&mut self, data: &mut [BuF]) -> Result<u64> {
+impl AudioDecoder for SymphoniaWrapper {
+    /// Fill data from the internal buffer, decoding packets until it has enough samples
+    ///
+    /// Returns the amount of samples written,
+    /// which is only less than the length of data at the end of the stream
+    fn fill_available(&mut self, data: &mut [BuF]) -> Result<usize> {
         let mut errors = 0;
         while data.len() > self.buffer.len() && !self.finished && errors < MAXERROR {
             if let Err(err) = self.add_buffer() {
@@ -168,42 +222,214 @@ impl SymphoniaWrapper {
                 errors += 1;
             };
         }
-        for i in data.iter_mut() {
-            *i = self.buffer.pop_front().unwrap_or(Sample::EQUILIBRIUM)
+        let amount = data.len().min(self.buffer.len());
+        for (i, sample) in data.iter_mut().zip(self.buffer.drain(..amount)) {
+            *i = sample
         }
-        Ok(self.left)
+        Ok(amount)
     }
 
-    pub fn channels(&self) -> usize {
+    fn left(&self) -> u64 {
+        self.left
+    }
+
+    fn channels(&self) -> usize {
         self.channels.count()
     }
 
-    pub fn length(&self) -> u64 {
+    fn length(&self) -> u64 {
         self.length
     }
 
-    pub fn finished(&self) -> bool {
+    fn length_is_estimate(&self) -> bool {
+        self.estimate.is_some()
+    }
+
+    fn finished(&self) -> bool {
         self.finished
     }
 
-    pub fn sample_rate(&self) -> usize {
+    fn sample_rate(&self) -> usize {
         self.sample_rate
     }
 
-    pub fn goto(&mut self, target: u64) -> Result<()> {
-        let seeked_to = self.format.seek(
+    fn goto(&mut self, target: u64) -> Result<()> {
+        if !self.seekable {
+            return Err(anyhow!("Can't seek in a stream"));
+        }
+        let seeked_to = match self.format.seek(
             SeekMode::Accurate,
             SeekTo::TimeStamp {
                 ts: target,
                 track_id: self.track_id,
             },
-        )?;
+        ) {
+            Ok(seeked_to) => seeked_to,
+            Err(err) => {
+                warn!("Seeking failed, decoding up to the target: {}", err);
+                return self.decode_to(target);
+            }
+        };
+        self.decoder.reset();
         self.buffer.clear();
-        let diff = (seeked_to.required_ts - seeked_to.actual_ts) as usize;
+        let diff = (seeked_to.required_ts - seeked_to.actual_ts) as usize * self.channels();
         while diff >= self.buffer.len() {
             self.add_buffer()?;
         }
         self.buffer.drain(0..diff);
         Ok(())
     }
+
+    fn name(&self) -> &str {
+        "Symphonia"
+    }
+
+    fn tags(&self) -> Vec<(String, String)> {
+        self.tags.clone()
+    }
+
+    fn audio_tracks(&self) -> Vec<AudioTrack> {
+        decodable_tracks(self.format.as_ref())
+            .enumerate()
+            .map(|(index, track)| AudioTrack {
+                index,
+                channels: track.codec_params.channels.map(|x| x.count()),
+                sample_rate: track.codec_params.sample_rate.map(|x| x as usize),
+                language: track.language.clone(),
+            })
+            .collect()
+    }
+
+    /// Decode another audio track, a stream continues where it is
+    fn select_audio_track(&mut self, index: usize) -> Result<()> {
+        if index == self.audio_track {
+            return Ok(());
+        }
+        let track = decodable_tracks(self.format.as_ref())
+            .nth(index)
+            .ok_or(anyhow!("There is no audio track {index}"))?;
+        let track = TrackDecoder::new(track, self.byte_len)?;
+        self.decoder = track.decoder;
+        self.track_id = track.track_id;
+        self._time_base = track.time_base;
+        self.length = track.length;
+        self.estimate = track.estimate;
+        self.channels = track.channels;
+        self.sample_rate = track.sample_rate;
+        self.audio_track = index;
+        self.buffer.clear();
+        self.left = self.length;
+        if self.seekable {
+            self.format.seek(
+                SeekMode::Accurate,
+                SeekTo::TimeStamp {
+                    ts: 0,
+                    track_id: self.track_id,
+                },
+            )?;
+            self.position = 0;
+            self.finished = false;
+        }
+        Ok(())
+    }
+}
+
+/// The decoder and the parameters of one audio track
+struct TrackDecoder {
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: TimeBase,
+    length: u64,
+    estimate: Option<LengthEstimate>,
+    channels: Channels,
+    sample_rate: usize,
+}
+
+impl TrackDecoder {
+    fn new(track: &Track, byte_len: Option<u64>) -> Result<TrackDecoder> {
+        let time_base = track
+            .codec_params
+            .time_base
+            .ok_or(Error::new(ErrorKind::Unsupported, "No time base"))?;
+        // Streams and some containers (like ADTS) don't know their length
+        let estimate = match track.codec_params.n_frames {
+            Some(_) => None,
+            None => Some(LengthEstimate::new(byte_len)),
+        };
+        let length = track.codec_params.n_frames.unwrap_or(0);
+        let channels = track
+            .codec_params
+            .channels
+            .ok_or(Error::new(ErrorKind::Unsupported, "No channels"))?;
+        let sample_rate = track
+            .codec_params
+            .sample_rate
+            .ok_or(Error::new(ErrorKind::Unsupported, "No sample_rate"))?
+            as usize;
+
+        let dec_opts = DecoderOptions::default();
+        let decoder = symphonia::default::get_codecs().make(&track.codec_params, &dec_opts)?;
+        Ok(TrackDecoder {
+            decoder,
+            track_id: track.id,
+            time_base,
+            length,
+            estimate,
+            channels,
+            sample_rate,
+        })
+    }
+}
+
+/// The tracks that have a codec, other tracks like subtitles are left out
+fn decodable_tracks(format: &dyn FormatReader) -> impl Iterator<Item = &Track> {
+    format
+        .tracks()
+        .iter()
+        .filter(|x| x.codec_params.codec != CODEC_TYPE_NULL)
+}
+
+fn revision_tags(revision: &MetadataRevision) -> Vec<(String, String)> {
+    revision
+        .tags()
+        .iter()
+        .map(|tag| (tag.key.clone(), tag.value.to_string()))
+        .collect()
+}
+
+/// Length from the bytes per frame of the packets that were read,
+/// for containers that don't have the length
+struct LengthEstimate {
+    /// Bytes of the whole input, not known for streams
+    byte_len: Option<u64>,
+    /// Bytes and frames of the packets that were read
+    bytes: u64,
+    frames: u64,
+    /// Frame after the last packet
+    end: u64,
+}
+
+impl LengthEstimate {
+    fn new(byte_len: Option<u64>) -> LengthEstimate {
+        LengthEstimate {
+            byte_len,
+            bytes: 0,
+            frames: 0,
+            end: 0,
+        }
+    }
+
+    /// Add the packet, returns the new estimate
+    fn update(&mut self, packet: &Packet) -> u64 {
+        self.bytes += packet.data.len() as u64;
+        self.frames += packet.dur;
+        self.end = self.end.max(packet.ts + packet.dur);
+        match self.byte_len {
+            Some(byte_len) if self.bytes > 0 => {
+                let estimate = byte_len as u128 * self.frames as u128 / self.bytes as u128;
+                (estimate as u64).max(self.end)
+            }
+            _ => self.end,
+        }
+    }
 }
@@ -1,6 +1,7 @@
 pub mod audio_conversion;
 pub mod database;
 pub mod decoders;
+pub mod loudness;
 pub mod models;
 pub mod playback;
 pub mod playback_loop;
@@ -0,0 +1,378 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+use crate::BuF;
+
+/// Loudness offset that cancels the gain of the K-weighting at 1 kHz
+const LOUDNESS_OFFSET: f64 = -0.691;
+/// Blocks quieter than this in LUFS are never measured
+pub const ABSOLUTE_GATE: f64 = -70.0;
+/// Gate for the integrated loudness, relative to the absolute gated loudness
+const RELATIVE_GATE: f64 = -10.0;
+/// Gate for the loudness range, relative to the absolute gated loudness
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+/// Lower and upper percentile of the loudness range
+const RANGE_PERCENTILES: (f64, f64) = (0.10, 0.95);
+/// Time between the start of two blocks in ms
+const STEP_MS: usize = 100;
+/// Length of a momentary block (400 ms) in steps
+const MOMENTARY_STEPS: usize = 4;
+/// Length of a short-term block (3 s) in steps
+const SHORT_TERM_STEPS: usize = 30;
+/// Weight of the surround channels
+const SURROUND_WEIGHT: f64 = 1.41;
+/// Oversampling used to find the true peak
+const TRUE_PEAK_OVERSAMPLING: usize = 4;
+/// Taps of every phase of the true peak interpolation filter
+const TRUE_PEAK_TAPS: usize = 12;
+
+/// Loudness of audio according to EBU R128
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Loudness {
+    /// Integrated loudness in LUFS, `None` if everything is below the absolute gate
+    pub integrated: Option<f32>,
+    /// Loudness range in LU
+    pub range: f32,
+    /// True peak in dBTP, `None` for digital silence
+    pub true_peak: Option<f32>,
+}
+
+/// The gating blocks of one or more measured tracks
+///
+/// Measurements of multiple tracks can be combined to get the loudness of an album
+#[derive(Clone, Debug, Default)]
+pub struct Measurement {
+    /// Mean square of every momentary block
+    momentary: Vec<f64>,
+    /// Mean square of every short-term block
+    short_term: Vec<f64>,
+    /// Linear true peak
+    true_peak: f64,
+}
+
+impl Measurement {
+    /// Add the blocks of another measurement, as if the audio was played after this one
+    pub fn extend(&mut self, other: &Measurement) {
+        self.momentary.extend_from_slice(&other.momentary);
+        self.short_term.extend_from_slice(&other.short_term);
+        self.true_peak = self.true_peak.max(other.true_peak);
+    }
+
+    /// Gated loudness in LUFS, `None` if everything is below the absolute gate
+    pub fn integrated(&self) -> Option<f64> {
+        let gated = gate(&self.momentary, RELATIVE_GATE);
+        if gated.is_empty() {
+            return None;
+        }
+        Some(loudness(gated.iter().sum::<f64>() / gated.len() as f64))
+    }
+
+    /// The difference between the soft and loud parts in LU
+    pub fn range(&self) -> f64 {
+        let mut gated: Vec<f64> = gate(&self.short_term, RANGE_RELATIVE_GATE)
+            .into_iter()
+            .map(loudness)
+            .collect();
+        if gated.is_empty() {
+            return 0.0;
+        }
+        gated.sort_by(f64::total_cmp);
+        let percentile = |p: f64| gated[((gated.len() - 1) as f64 * p).round() as usize];
+        percentile(RANGE_PERCENTILES.1) - percentile(RANGE_PERCENTILES.0)
+    }
+
+    /// True peak in dBTP
+    pub fn true_peak(&self) -> f64 {
+        20.0 * self.true_peak.log10()
+    }
+
+    /// All values, silence has no loudness so it never gets a gain
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: self.integrated().map(|x| x as f32),
+            range: self.range() as f32,
+            true_peak: (self.true_peak > 0.0).then(|| self.true_peak() as f32),
+        }
+    }
+}
+
+/// Loudness of a mean square
+fn loudness(mean_square: f64) -> f64 {
+    LOUDNESS_OFFSET + 10.0 * mean_square.log10()
+}
+
+/// Mean square of a loudness
+fn mean_square(loudness: f64) -> f64 {
+    10f64.powf((loudness - LOUDNESS_OFFSET) / 10.0)
+}
+
+/// Apply the absolute gate and then the relative gate (in LU) to the blocks
+fn gate(blocks: &[f64], relative_gate: f64) -> Vec<f64> {
+    let absolute = mean_square(ABSOLUTE_GATE);
+    let gated: Vec<f64> = blocks.iter().copied().filter(|x| *x > absolute).collect();
+    if gated.is_empty() {
+        return gated;
+    }
+    let relative =
+        gated.iter().sum::<f64>() / gated.len() as f64 * 10f64.powf(relative_gate / 10.0);
+    gated.into_iter().filter(|x| *x > relative).collect()
+}
+
+/// Second order IIR filter, transposed direct form II
+#[derive(Clone, Copy, Debug, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+
+    /// High shelf of the K-weighting, models the head
+    fn k_shelf(sample_rate: f64) -> Biquad {
+        let (f0, gain, q) = (1681.97445095553, 3.99984385397335, 0.70717523695542);
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.499666774154542);
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            state: [0.0; 2],
+        }
+    }
+
+    /// High pass of the K-weighting (RLB)
+    fn k_high_pass(sample_rate: f64) -> Biquad {
+        let (f0, q) = (38.1354708760244, 0.500327037323877);
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+            state: [0.0; 2],
+        }
+    }
+}
+
+/// Filter state of a single channel
+#[derive(Clone, Debug)]
+struct ChannelState {
+    weight: f64,
+    shelf: Biquad,
+    high_pass: Biquad,
+    /// Last samples, newest first, for the true peak interpolation
+    history: [f64; TRUE_PEAK_TAPS],
+}
+
+/// Weight of the channel in the WAV/SMPTE order, the LFE channel is not measured
+fn channel_weight(channels: usize, channel: usize) -> f64 {
+    match (channels, channel) {
+        // L R BL BR
+        (4, 2 | 3) => SURROUND_WEIGHT,
+        // L R C BL BR
+        (5, 3 | 4) => SURROUND_WEIGHT,
+        // L R C LFE (surround)
+        (6.., 3) => 0.0,
+        (6.., 4..) => SURROUND_WEIGHT,
+        _ => 1.0,
+    }
+}
+
+/// Windowed sinc interpolation filter, one row of taps per phase
+fn true_peak_filter() -> [[f64; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING] {
+    let length = TRUE_PEAK_TAPS * TRUE_PEAK_OVERSAMPLING;
+    let centre = (length - 1) as f64 / 2.0;
+    let mut filter = [[0.0; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING];
+    for (phase, taps) in filter.iter_mut().enumerate() {
+        for (i, tap) in taps.iter_mut().enumerate() {
+            let n = phase + i * TRUE_PEAK_OVERSAMPLING;
+            let x = (n as f64 - centre) / TRUE_PEAK_OVERSAMPLING as f64;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let window = 0.5 - 0.5 * (2.0 * PI * (n + 1) as f64 / (length + 1) as f64).cos();
+            *tap = sinc * window;
+        }
+        // Every phase should let a constant signal through unchanged
+        let sum: f64 = taps.iter().sum();
+        taps.iter_mut().for_each(|x| *x /= sum);
+    }
+    filter
+}
+
+/// Measures the loudness of interleaved audio
+#[derive(Clone, Debug)]
+pub struct LoudnessMeter {
+    channels: Vec<ChannelState>,
+    true_peak_filter: [[f64; TRUE_PEAK_TAPS]; TRUE_PEAK_OVERSAMPLING],
+    /// Frames in a step
+    step_length: usize,
+    /// Frames in the current step
+    step_frames: usize,
+    /// Weighted sum of squares of the current step
+    step_energy: f64,
+    /// Energy of the last steps, newest last
+    steps: VecDeque<f64>,
+    measurement: Measurement,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: usize, channels: usize) -> LoudnessMeter {
+        let rate = sample_rate.max(1) as f64;
+        LoudnessMeter {
+            channels: (0..channels)
+                .map(|channel| ChannelState {
+                    weight: channel_weight(channels, channel),
+                    shelf: Biquad::k_shelf(rate),
+                    high_pass: Biquad::k_high_pass(rate),
+                    history: [0.0; TRUE_PEAK_TAPS],
+                })
+                .collect(),
+            true_peak_filter: true_peak_filter(),
+            step_length: (sample_rate * STEP_MS / 1000).max(1),
+            step_frames: 0,
+            step_energy: 0.0,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS),
+            measurement: Measurement::default(),
+        }
+    }
+
+    /// Measure interleaved samples, an incomplete frame at the end is ignored
+    pub fn process(&mut self, data: &[BuF]) {
+        if self.channels.is_empty() {
+            return;
+        }
+        for frame in data.chunks_exact(self.channels.len()) {
+            for (channel, sample) in self.channels.iter_mut().zip(frame) {
+                let sample = *sample as f64;
+                channel.history.copy_within(..TRUE_PEAK_TAPS - 1, 1);
+                channel.history[0] = sample;
+                for taps in self.true_peak_filter.iter() {
+                    let peak: f64 = taps
+                        .iter()
+                        .zip(channel.history.iter())
+                        .map(|(a, b)| a * b)
+                        .sum();
+                    self.measurement.true_peak = self.measurement.true_peak.max(peak.abs());
+                }
+                self.measurement.true_peak = self.measurement.true_peak.max(sample.abs());
+
+                let weighted = channel.high_pass.process(channel.shelf.process(sample));
+                self.step_energy += channel.weight * weighted * weighted;
+            }
+            self.step_frames += 1;
+            if self.step_frames == self.step_length {
+                self.finish_step();
+            }
+        }
+    }
+
+    fn finish_step(&mut self) {
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_energy);
+        self.step_energy = 0.0;
+        self.step_frames = 0;
+
+        let block = |steps: &VecDeque<f64>, amount: usize, length: usize| {
+            steps.iter().rev().take(amount).sum::<f64>() / (amount * length) as f64
+        };
+        if self.steps.len() >= MOMENTARY_STEPS {
+            let momentary = block(&self.steps, MOMENTARY_STEPS, self.step_length);
+            self.measurement.momentary.push(momentary);
+        }
+        if self.steps.len() == SHORT_TERM_STEPS {
+            let short_term = block(&self.steps, SHORT_TERM_STEPS, self.step_length);
+            self.measurement.short_term.push(short_term);
+        }
+    }
+
+    /// Everything measured so far
+    pub fn measurement(&self) -> &Measurement {
+        &self.measurement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: usize = 48000;
+
+    /// Interleaved stereo 1 kHz sine with the peak in dBFS
+    fn sine(peak: f64, seconds: usize) -> Vec<BuF> {
+        let amplitude = 10f64.powf(peak / 20.0);
+        (0..SAMPLE_RATE * seconds)
+            .flat_map(|i| {
+                let x = amplitude * (2.0 * PI * 1000.0 * i as f64 / SAMPLE_RATE as f64).sin();
+                [x as BuF; 2]
+            })
+            .collect()
+    }
+
+    fn measure(data: &[BuF]) -> Loudness {
+        let mut meter = LoudnessMeter::new(SAMPLE_RATE, 2);
+        meter.process(data);
+        meter.measurement().loudness()
+    }
+
+    #[test]
+    fn sine_has_its_loudness() {
+        let loudness = measure(&sine(-23.0, 5));
+        assert!((loudness.integrated.unwrap() + 23.0).abs() < 0.1);
+        assert!((loudness.true_peak.unwrap() + 23.0).abs() < 0.1);
+        assert!(loudness.range.abs() < 0.1);
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let loudness = measure(&vec![0.0; SAMPLE_RATE * 2 * 5]);
+        assert_eq!(loudness.integrated, None);
+        assert_eq!(loudness.true_peak, None);
+    }
+
+    #[test]
+    fn absolute_gate_keeps_the_peak() {
+        let loudness = measure(&sine(-80.0, 5));
+        assert_eq!(loudness.integrated, None);
+        assert!((loudness.true_peak.unwrap() + 80.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn relative_gate_skips_quiet_parts() {
+        let mut data = sine(-20.0, 5);
+        data.extend(sine(-50.0, 5));
+        let loudness = measure(&data);
+        // Without the gate it would be about -23, the blocks at the change still count
+        assert!((loudness.integrated.unwrap() + 20.0).abs() < 0.2);
+    }
+
+    #[test]
+    fn album_combines_tracks() {
+        let mut album = Measurement::default();
+        for peak in [-20.0, -26.0] {
+            let mut meter = LoudnessMeter::new(SAMPLE_RATE, 2);
+            meter.process(&sine(peak, 5));
+            album.extend(meter.measurement());
+        }
+        let loudness = album.loudness();
+        // The mean of the power of both tracks
+        let expected = 10.0 * ((0.01 + 10f64.powf(-2.6)) / 2.0).log10();
+        assert!((loudness.integrated.unwrap() as f64 - expected).abs() < 0.1);
+        assert!((loudness.true_peak.unwrap() + 20.0).abs() < 0.1);
+    }
+}
@@ -57,6 +57,56 @@ pub struct Release {
     pub date: NaiveDate,
     pub publisher_id: Option<i32>,
     pub artist_id: i32,
+    pub gapless: bool,
+    pub replay_gain: Option<f32>,
+    pub replay_peak: Option<f32>,
+    pub loudness: Option<f32>,
+    pub loudness_range: Option<f32>,
+    pub true_peak: Option<f32>,
+}
+
+#[derive(Queryable, Selectable, Debug, Identifiable, Clone, PartialEq)]
+#[diesel(table_name = saved_queue)]
+pub struct SavedQueue {
+    pub id: i32,
+    pub options_id: i32,
+    pub repeat_current: bool,
+    pub max_history: i32,
+    pub next_id: i64,
+    pub current_track: Option<String>,
+    pub position: i64,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = saved_queue_items)]
+pub struct SavedQueueItem {
+    #[diesel(skip_insertion)]
+    pub id: i32,
+    pub list: i32,
+    pub parent_id: Option<i32>,
+    pub number: i32,
+    pub item_type: i32,
+    pub queue_id: i64,
+    pub track_id: Option<i32>,
+    pub release_id: Option<i32>,
+    pub playlist_id: Option<i32>,
+    pub options_id: Option<i32>,
+}
+
+#[derive(Queryable, Selectable, Insertable, Debug, Clone, PartialEq)]
+#[diesel(table_name = saved_queue_options)]
+pub struct SavedQueueOptions {
+    #[diesel(skip_insertion)]
+    pub id: i32,
+    pub shuffle_type: i32,
+    pub weights: String,
+    pub default_weights: String,
+    pub stop_condition: i32,
+    pub stop_value: i64,
+    pub selected: Option<i32>,
+    pub progress_tracks: i32,
+    pub progress_time: i64,
+    pub progress_picked: i32,
 }
 
 #[derive(Queryable, Selectable, Debug, Identifiable, Associations, Clone, PartialEq)]
@@ -70,14 +120,20 @@ pub struct Track {
     pub duration: i32,
     pub artist_id: i32,
     pub release_id: i32,
+    pub replay_gain: Option<f32>,
+    pub replay_peak: Option<f32>,
+    pub loudness: Option<f32>,
+    pub loudness_range: Option<f32>,
+    pub true_peak: Option<f32>,
 }
 
 #[derive(
     Queryable, Insertable, Selectable, Debug, Identifiable, Associations, Clone, PartialEq,
 )]
 #[diesel(belongs_to(Track))]
-#[diesel(primary_key(path))]
+#[diesel(primary_key(path, audio_track))]
 pub struct TrackLocation {
     pub path: String,
+    pub audio_track: i32,
     pub track_id: i32,
 }
@@ -1,59 +1,249 @@
 use std::collections::VecDeque;
 use std::fmt::Debug;
-use std::fs::File;
-use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use anyhow::{anyhow, Result};
 use cpal::Sample;
-use log::{error, warn};
-use rubato::{FftFixedInOut, Resampler};
+use log::{error, info, warn};
+use rubato::VecResampler;
 
-use crate::audio_conversion::{interleaved_to_planar, planar_to_interleaved};
-use crate::decoders::{opus_decoder::OpusReader, symphonia_wrap::SymphoniaWrapper, Decoder};
-use crate::queue::queue_items::QueueItem;
+use crate::audio_conversion::{interleaved_to_planar, planar_to_interleaved, ChannelMixer};
+use crate::decoders::{registry::open_decoder, Decoder};
+use crate::queue::queue_items::{QueueItem, QueueTrack};
+use crate::queue::Queue;
 use crate::BuF;
+use crossfade::{Crossfade, FadeCurve};
 use playback_context::{ArcPlaybackContext, PlaybackContext};
+use replay_gain::{apply_gain, Limiter, ReplayGainMode, TrackGain};
+use resampling::ResamplerConfig;
+use sleep_timer::SleepTimer;
 
+pub mod crossfade;
 pub mod playback_context;
+pub mod replay_gain;
+pub mod resampling;
+pub(crate) mod ring_buffer;
+pub mod sleep_timer;
+
+/// Seconds before the end of a track to start opening the next track
+const PREFETCH_SEC: u64 = 5;
+/// Amount of tracks that can't be opened before we give up on the queue
+const MAX_SKIP: u8 = 20;
+/// Seconds into a track after which going to the previous track restarts the track instead
+const PREVIOUS_RESTART_SEC: u64 = 3;
+/// Frames decoded at once when the sample rates are the same and nothing is resampled
+const BYPASS_FRAMES: usize = 1024;
 
 pub struct PlaybackDaemon {
     pub playing: bool,
     playback_context: ArcPlaybackContext,
     decoder: Decoder,
+    track_info: Option<TrackInfo>,
+    prefetch: Prefetch,
+    /// Tracks in a row that could not be opened
+    failed_opens: u8,
+    fade_in: Option<FadeIn>,
     resampler: PlaybackResampler,
     buffer_output: VecDeque<BuF>,
+    /// Samples at the front of `buffer_output` that are not exactly as they were decoded,
+    /// changed by the gain, resampling, mixing or a fade
+    changed_output: usize,
+    /// The last filled chunk has samples that are not exactly as they were decoded
+    changed: bool,
+    /// Keeps ReplayGain from clipping
+    limiter: Limiter,
+    sleep_timer: Option<SleepTimer>,
+    /// Sample rate the tracks are resampled to, the current track can already be at the next rate
     sample_rate_output: usize,
+    /// Sample rate the output was opened with, used when the device can't play a track at its own
+    sample_rate_device: usize,
+    /// Changes of the output sample rate that are due once the output buffer is taken up to them
+    rate_changes: VecDeque<RateChange>,
+    channels_output: usize,
+}
+
+/// The output sample rate changes after the audio at the old rate is taken from the output buffer
+#[derive(Clone, Copy, Debug)]
+struct RateChange {
+    /// Samples of the output buffer before the change
+    samples: usize,
+    sample_rate: usize,
+}
+
+/// A track from the queue, with what is needed to decide on a crossfade
+#[derive(Clone, Debug)]
+struct TrackInfo {
+    path: PathBuf,
+    /// Index of the audio track in the file
+    audio_track: usize,
+    release_id: Option<i32>,
+    gapless: bool,
+    gain: TrackGain,
+}
+
+impl TrackInfo {
+    fn from_path(path: PathBuf) -> TrackInfo {
+        TrackInfo {
+            path,
+            audio_track: 0,
+            release_id: None,
+            gapless: false,
+            gain: TrackGain::default(),
+        }
+    }
+
+    /// Tracks of a gapless release, or consecutive tracks of the same release are never faded
+    fn can_crossfade(&self, next: &TrackInfo) -> bool {
+        if self.gapless || next.gapless {
+            return false;
+        }
+        match (self.release_id, next.release_id) {
+            (Some(current), Some(next)) => current != next,
+            _ => true,
+        }
+    }
+}
+
+impl From<QueueTrack> for TrackInfo {
+    fn from(value: QueueTrack) -> Self {
+        TrackInfo {
+            path: value.location().to_path_buf(),
+            audio_track: value.audio_track(),
+            release_id: Some(value.track().release_id),
+            gapless: value.gapless(),
+            gain: value.gain(),
+        }
+    }
+}
+
+/// The next track, opened on another thread so the realtime thread doesn't have to wait on it
+enum Prefetch {
+    /// The queue has not been asked for the next track yet
+    NotStarted,
+    /// The decoder of the next track is being opened
+    Opening(Box<TrackInfo>, JoinHandle<Option<Decoder>>),
+    /// The queue has no next track
+    EndOfQueue,
+}
+
+/// What the prefetch has for the next track, it is never waited on
+#[allow(clippy::large_enum_variant)]
+enum NextTrack {
+    Ready(TrackInfo, Decoder),
+    /// The decoder is still being opened, ask again for the next chunk
+    Opening,
+    EndOfQueue,
+}
+
+impl Prefetch {
+    fn open(track: TrackInfo) -> Prefetch {
+        let path = track.path.clone();
+        let audio_track = track.audio_track;
+        Prefetch::Opening(
+            Box::new(track),
+            std::thread::spawn(move || match_audio_track(&path, audio_track)),
+        )
+    }
+}
+
+/// The decoder of the next track is opened and can be taken without waiting.
+/// Tests always take it, so they don't depend on the timing of the thread
+fn is_opened(handle: &JoinHandle<Option<Decoder>>) -> bool {
+    cfg!(test) || handle.is_finished()
+}
+
+/// The incoming track during a crossfade,
+/// has its own resampler because the format can differ from the outgoing track
+struct FadeIn {
+    track: TrackInfo,
+    decoder: Decoder,
+    resampler: PlaybackResampler,
+    buffer: VecDeque<BuF>,
+    curve: FadeCurve,
+    /// Frames of the output that are faded
+    position: u64,
+    /// Length of the fade in frames of the output
+    length: u64,
+}
+
+impl FadeIn {
+    /// Decode until the buffer has at least `amount` samples, or the track ends
+    fn fill_buffer(&mut self, amount: usize, mode: ReplayGainMode) -> Result<()> {
+        while self.buffer.len() < amount {
+            let filled = self
+                .decoder
+                .fill_available(&mut self.resampler.decoder_output)?;
+            if filled == 0 && self.decoder.finished() {
+                break;
+            }
+            apply_gain(
+                &mut self.resampler.decoder_output[..filled],
+                self.track.gain.linear(mode),
+            );
+            for i in self.resampler.decoder_output[filled..].iter_mut() {
+                *i = Sample::EQUILIBRIUM
+            }
+            self.resampler.resample()?;
+            self.buffer.extend(self.resampler.mixed.iter());
+        }
+        Ok(())
+    }
 }
 
 /// Helper struct for PlaybackDaemon
 /// Only contains buffers that are dependent on the decoder sample rate
 /// and the resampler itself
 struct PlaybackResampler {
-    fixed_in_out_resampler: FftFixedInOut<BuF>,
+    /// `None` when the sample rates are the same, then the samples are not touched
+    resampler: Option<Box<dyn VecResampler<BuF>>>,
+    /// What the resampler was made with, it is made again for a track when this changes
+    config: ResamplerConfig,
     decoder_output: Vec<BuF>,
     /// Input Resampler
     input: Vec<Vec<BuF>>,
     /// Output Resampler
     output: Vec<Vec<BuF>>,
     interleaved: Vec<BuF>,
+    /// Mixes the decoder channels to the channels of the output stream
+    channel_mixer: ChannelMixer,
+    /// Interleaved with the channels of the output stream
+    mixed: Vec<BuF>,
 }
 
 impl PlaybackDaemon {
-    pub fn new(sample_rate_output: usize) -> PlaybackDaemon {
+    /// `channels_output` is the amount of channels of the output stream,
+    /// every track will be mixed to this amount of channels
+    pub fn new(sample_rate_output: usize, channels_output: usize) -> PlaybackDaemon {
+        let playback_context = PlaybackContext::new();
+        playback_context.set_output_sample_rate(sample_rate_output);
         PlaybackDaemon {
             playing: false,
-            decoder: Decoder::None,
-            playback_context: PlaybackContext::new(),
-            resampler: PlaybackResampler::new(1, 1, 2).expect("should be fine"),
+            decoder: Decoder::none(),
+            track_info: None,
+            prefetch: Prefetch::NotStarted,
+            failed_opens: 0,
+            fade_in: None,
+            playback_context,
+            resampler: PlaybackResampler::new(1, 1, 2, channels_output, ResamplerConfig::default())
+                .expect("should be fine"),
             buffer_output: VecDeque::new(),
+            changed_output: 0,
+            changed: false,
+            limiter: Limiter::new(sample_rate_output),
+            sleep_timer: None,
             sample_rate_output,
+            sample_rate_device: sample_rate_output,
+            rate_changes: VecDeque::new(),
+            channels_output,
         }
     }
 
     pub fn try_new(
         file: &str,
         sample_rate_output: usize,
+        channels_output: usize,
         volume_level: BuF,
     ) -> Option<PlaybackDaemon> {
         let current = PathBuf::from(file);
@@ -62,81 +252,500 @@ impl PlaybackDaemon {
             decoder.sample_rate(),
             sample_rate_output,
             decoder.channels(),
+            channels_output,
+            ResamplerConfig::default(),
         )?;
         let playback_context = PlaybackContext::new_from(
             decoder.length(),
-            current,
+            current.clone(),
             decoder.sample_rate(),
             volume_level,
         );
+        playback_context.set_output_sample_rate(sample_rate_output);
 
         Some(PlaybackDaemon {
             playing: true,
             decoder,
+            track_info: Some(TrackInfo::from_path(current)),
+            prefetch: Prefetch::NotStarted,
+            failed_opens: 0,
+            fade_in: None,
             playback_context,
             resampler,
             buffer_output: VecDeque::new(),
+            changed_output: 0,
+            changed: false,
+            limiter: Limiter::new(sample_rate_output),
+            sleep_timer: None,
             sample_rate_output,
+            sample_rate_device: sample_rate_output,
+            rate_changes: VecDeque::new(),
+            channels_output,
         })
     }
 
-    /// Fill the data with music
+    /// Fill the data with music, the volume is applied by the output
     pub fn fill(&mut self, data: &mut [BuF]) -> Result<()> {
         while data.len() > self.buffer_output.len() {
             self.add_buffer()?;
         }
-        let volume_level = self.playback_context.volume_level();
-        for i in data.iter_mut() {
-            *i = volume_level
-                * self.buffer_output.pop_front().unwrap_or_else(|| {
+        self.changed = self.changed_output > 0;
+        self.changed_output = self.changed_output.saturating_sub(data.len());
+        let mut stopped = false;
+        for frame in data.chunks_mut(self.channels_output) {
+            let sleep_gain = match self.sleep_timer.as_mut().map(SleepTimer::next_gain) {
+                Some(Some(gain)) => gain,
+                Some(None) => {
+                    info!("Sleep timer ended, stopping playback");
+                    self.sleep_timer = None;
+                    self.playing = false;
+                    stopped = true;
+                    0.0
+                }
+                None => 1.0,
+            };
+            if stopped {
+                for i in frame.iter_mut() {
+                    *i = Sample::EQUILIBRIUM
+                }
+                continue;
+            }
+            for i in frame.iter_mut() {
+                *i = self.buffer_output.pop_front().unwrap_or_else(|| {
                     error!("AHAH, No BuFFerS");
                     Sample::EQUILIBRIUM
                 })
+            }
+            self.advance_rate_changes(frame.len());
+            // ReplayGain is already applied, limit before the volume
+            self.limiter.process(frame);
+            for i in frame.iter_mut() {
+                *i *= sleep_gain
+            }
+            self.changed |= self.limiter.is_limiting() || sleep_gain != 1.0;
         }
         Ok(())
     }
 
+    /// The last filled chunk is exactly as it was decoded, so it can be output without dither
+    pub fn filled_unchanged(&self) -> bool {
+        !self.changed
+    }
+
+    /// Move the mixed chunk to the output buffer,
+    /// `changed` is set when a gain or fade changed the decoded samples
+    fn output_mixed(&mut self, changed: bool) {
+        self.buffer_output.extend(self.resampler.mixed.iter());
+        if changed || !self.resampler.is_transparent() {
+            self.changed_output = self.buffer_output.len();
+        }
+    }
+
+    /// Samples that can be filled before the output sample rate changes,
+    /// the output has to be opened again at the new rate after them
+    pub fn samples_until_rate_change(&self) -> Option<usize> {
+        self.rate_changes.front().map(|x| x.samples)
+    }
+
+    /// The samples were taken from the output buffer, change the sample rate when that is due
+    fn advance_rate_changes(&mut self, taken: usize) {
+        for change in self.rate_changes.iter_mut() {
+            change.samples = change.samples.saturating_sub(taken);
+        }
+        while let Some(change) = self.rate_changes.front().copied() {
+            if change.samples > 0 {
+                break;
+            }
+            self.rate_changes.pop_front();
+            self.apply_output_rate(change.sample_rate);
+        }
+    }
+
+    /// The audio at the new sample rate is next in the output
+    fn apply_output_rate(&mut self, sample_rate: usize) {
+        self.limiter = Limiter::new(sample_rate);
+        self.playback_context.set_output_sample_rate(sample_rate);
+    }
+
+    /// Resample to another rate from now on, the audio that is buffered is played at the old rate first
+    fn change_output_rate(&mut self, sample_rate: usize) {
+        self.sample_rate_output = sample_rate;
+        if self.buffer_output.is_empty() {
+            self.rate_changes.clear();
+            self.apply_output_rate(sample_rate);
+        } else {
+            self.rate_changes.push_back(RateChange {
+                samples: self.buffer_output.len(),
+                sample_rate,
+            });
+        }
+    }
+
+    /// Drop the buffered audio, a sample rate change that was waiting for it happens at once
+    fn clear_output(&mut self) {
+        self.buffer_output.clear();
+        self.changed_output = 0;
+        if !self.rate_changes.is_empty() {
+            self.rate_changes.clear();
+            self.apply_output_rate(self.sample_rate_output);
+        }
+    }
+
+    /// The decoder has another format than the current one, or the resampler config was changed
+    fn needs_new_resampler(&self, decoder: &Decoder) -> bool {
+        decoder.sample_rate() != self.decoder.sample_rate()
+            || decoder.channels() != self.decoder.channels()
+            || self.output_rate_for(decoder) != self.sample_rate_output
+            || self.playback_context.resampler_config() != self.resampler.config
+    }
+
+    /// The sample rate a decoder is played at. In bit-perfect mode that is its own rate,
+    /// if the device supports it, so nothing is resampled
+    fn output_rate_for(&self, decoder: &Decoder) -> usize {
+        let sample_rate = decoder.sample_rate();
+        if self.playback_context.bit_perfect()
+            && self.playback_context.supports_sample_rate(sample_rate)
+        {
+            sample_rate
+        } else {
+            self.sample_rate_device
+        }
+    }
+
+    /// The decoder of the current track gives another amount of channels than it started with
+    fn channels_changed(&self) -> bool {
+        !self.decoder.finished()
+            && self.decoder.channels() != self.resampler.channel_mixer.channels_input()
+    }
+
     /// Add to internal buffer
+    ///
+    /// When the current track ends the next track is joined in the same chunk,
+    /// so there is no gap between tracks that have the same format
     fn add_buffer(&mut self) -> Result<()> {
-        let left = self.decoder.fill(&mut self.resampler.decoder_output)?;
-        self.playback_context.update_left(left);
-
-        self.resampler.resample(self.decoder.channels())?;
-
-        self.buffer_output.extend(self.resampler.interleaved.iter());
-        if self.decoder.finished() {
-            // TODO:
-            let mut queue = self.playback_context.lock_queue();
-            let track = queue.next_track();
-            drop(queue);
-            if let Some(track) = track {
-                self.set_track(track)?;
-            } else {
-                self.playing = false;
+        if self.fade_in.is_some() {
+            return self.add_buffer_crossfade();
+        }
+        if self.channels_changed() {
+            // Like a chained Ogg file with links that have different amounts of channels
+            info!("The track changed to {} channels", self.decoder.channels());
+            self.resampler.change_sample_rate(
+                self.decoder.sample_rate(),
+                self.sample_rate_output,
+                self.decoder.channels(),
+                self.playback_context.resampler_config(),
+            )?;
+        }
+        let mode = self.playback_context.replay_gain_mode();
+        let length = self.resampler.decoder_output.len();
+        let mut filled = self
+            .decoder
+            .fill_available(&mut self.resampler.decoder_output)?;
+        let gain = self.current_gain(mode);
+        apply_gain(&mut self.resampler.decoder_output[..filled], gain);
+        let mut changed = gain != 1.0;
+        if filled < length && self.channels_changed() {
+            // Finish the chunk with the old amount of channels, the resampler changes next time
+            for i in self.resampler.decoder_output[filled..].iter_mut() {
+                *i = Sample::EQUILIBRIUM
             }
+            self.update_progress();
+            self.resampler.resample()?;
+            self.output_mixed(changed);
+            return Ok(());
+        }
+        while filled < length && self.decoder.finished() {
+            let (track, decoder) = match self.take_next() {
+                NextTrack::Ready(track, decoder) => (track, decoder),
+                // Silence until the next track is opened
+                NextTrack::Opening => break,
+                NextTrack::EndOfQueue => {
+                    self.playing = false;
+                    break;
+                }
+            };
+            if self.needs_new_resampler(&decoder) {
+                // The resampler has to change, so finish the chunk of the old track first
+                for i in self.resampler.decoder_output[filled..].iter_mut() {
+                    *i = Sample::EQUILIBRIUM
+                }
+                self.resampler.resample()?;
+                self.output_mixed(changed);
+                return self.start_decoder(track, decoder);
+            }
+            self.start_decoder(track, decoder)?;
+            let start = filled;
+            filled += self
+                .decoder
+                .fill_available(&mut self.resampler.decoder_output[filled..])?;
+            let gain = self.current_gain(mode);
+            apply_gain(&mut self.resampler.decoder_output[start..filled], gain);
+            changed |= gain != 1.0;
+        }
+        for i in self.resampler.decoder_output[filled..].iter_mut() {
+            *i = Sample::EQUILIBRIUM
+        }
+        let left = self.decoder.left();
+        self.update_progress();
+        let crossfade = self.playback_context.crossfade();
+        let sample_rate = self.decoder.sample_rate();
+        let fade_samples = crossfade.samples(sample_rate);
+        if left < PREFETCH_SEC * sample_rate as u64 + fade_samples {
+            self.prefetch_next();
+        }
+
+        self.resampler.resample()?;
+
+        self.output_mixed(changed);
+        // The tracks are not mixed in bit-perfect mode, they can have different sample rates
+        if crossfade.is_enabled()
+            && !self.playback_context.bit_perfect()
+            && left > 0
+            && left <= fade_samples
+        {
+            self.start_crossfade(crossfade.curve, left)?;
         }
         Ok(())
     }
 
-    /// Set up a track to be decoded
-    fn set_track(&mut self, track: PathBuf) -> Result<()> {
-        self.decoder = match_decoder(&track).ok_or(anyhow!("Could not match decoder"))?;
-        self.playback_context.update_left(self.decoder.length());
-        self.resampler.change_sample_rate(
-            self.decoder.sample_rate(),
+    /// Add to the internal buffer while fading from the current track to the next
+    fn add_buffer_crossfade(&mut self) -> Result<()> {
+        let mode = self.playback_context.replay_gain_mode();
+        let gain = self.current_gain(mode);
+        if self.fade_in.is_none() {
+            return Ok(());
+        }
+        let length = self.resampler.decoder_output.len();
+        let filled = self
+            .decoder
+            .fill_available(&mut self.resampler.decoder_output)?;
+        apply_gain(&mut self.resampler.decoder_output[..filled], gain);
+        for i in self.resampler.decoder_output[filled..].iter_mut() {
+            *i = Sample::EQUILIBRIUM
+        }
+        self.update_progress();
+        self.resampler.resample()?;
+
+        let Some(fade) = self.fade_in.as_mut() else {
+            return Ok(());
+        };
+        let amount = self.resampler.mixed.len();
+        fade.fill_buffer(amount, mode)?;
+        for (i, outgoing) in self.resampler.mixed.iter().enumerate() {
+            let frame = fade.position + (i / self.channels_output) as u64;
+            let progress = frame as BuF / fade.length as BuF;
+            let incoming = fade.buffer.pop_front().unwrap_or(Sample::EQUILIBRIUM);
+            self.buffer_output.push_back(
+                outgoing * fade.curve.fade_out(progress) + incoming * fade.curve.fade_in(progress),
+            );
+        }
+        fade.position += (amount / self.channels_output) as u64;
+        self.changed_output = self.buffer_output.len();
+
+        if (filled < length && self.decoder.finished()) || fade.position >= fade.length {
+            self.finish_crossfade()?;
+        }
+        Ok(())
+    }
+
+    /// The linear ReplayGain of the current track
+    fn current_gain(&self, mode: ReplayGainMode) -> BuF {
+        self.track_info
+            .as_ref()
+            .map_or(1.0, |track| track.gain.linear(mode))
+    }
+
+    /// Start fading into the next track, if it is ready and may be faded into
+    fn start_crossfade(&mut self, curve: FadeCurve, left: u64) -> Result<()> {
+        let ready = match (&self.prefetch, &self.track_info) {
+            (Prefetch::Opening(next, handle), Some(current)) => {
+                is_opened(handle) && current.can_crossfade(next)
+            }
+            (Prefetch::Opening(_, handle), None) => is_opened(handle),
+            _ => false,
+        };
+        if !ready {
+            return Ok(());
+        }
+        let NextTrack::Ready(track, decoder) = self.take_next() else {
+            return Ok(());
+        };
+        let resampler = PlaybackResampler::new(
+            decoder.sample_rate(),
             self.sample_rate_output,
-            self.decoder.channels(),
-        )?;
+            decoder.channels(),
+            self.channels_output,
+            self.playback_context.resampler_config(),
+        )
+        .ok_or(anyhow!("Could not create resampler for crossfade"))?;
+        let length = left * self.sample_rate_output as u64 / self.decoder.sample_rate() as u64;
+        self.fade_in = Some(FadeIn {
+            track,
+            decoder,
+            resampler,
+            buffer: VecDeque::new(),
+            curve,
+            position: 0,
+            length: length.max(1),
+        });
+        Ok(())
+    }
 
-        self.playback_context
-            .set_track(track, self.decoder.length(), self.decoder.sample_rate());
+    /// The incoming track of the crossfade becomes the current track
+    fn finish_crossfade(&mut self) -> Result<()> {
+        let Some(fade) = self.fade_in.take() else {
+            return Ok(());
+        };
+        self.buffer_output.extend(fade.buffer.iter());
+        self.changed_output = self.buffer_output.len();
+        self.decoder = fade.decoder;
+        self.resampler = fade.resampler;
+        self.set_current(fade.track);
+        Ok(())
+    }
+
+    /// Stop the crossfade and play the outgoing track on its own,
+    /// the incoming track will be opened again as the next track
+    fn cancel_crossfade(&mut self) {
+        if let Some(fade) = self.fade_in.take() {
+            self.prefetch = Prefetch::open(fade.track);
+        }
+    }
+
+    /// Ask the queue for the next track and start opening it on another thread
+    ///
+    /// Does nothing if this was already done for the current track
+    fn prefetch_next(&mut self) {
+        if !matches!(self.prefetch, Prefetch::NotStarted) {
+            return;
+        }
+        let repeat_current = self.playback_context.lock_queue().repeat_current;
+        match &self.track_info {
+            Some(current) if repeat_current => {
+                // Not taken from the queue, so it is never given back
+                self.prefetch = Prefetch::open(current.clone());
+            }
+            _ => self.prefetch_from_queue(),
+        }
+    }
+
+    /// Ask the queue for the next track and start opening it
+    fn prefetch_from_queue(&mut self) {
+        let mut queue = self.playback_context.lock_queue();
+        let track = queue.next_track();
+        drop(queue);
+        self.prefetch = match track {
+            Some(track) => Prefetch::open(track.into()),
+            None => Prefetch::EndOfQueue,
+        };
+    }
+
+    /// Give the next track (or the end of the queue) back to the queue, so the queue is asked again.
+    /// A track that was not taken from the queue is kept
+    fn discard_prefetch(&mut self) {
+        self.cancel_crossfade();
+        if self.playback_context.lock_queue().return_next_track() {
+            self.prefetch = Prefetch::NotStarted;
+        }
+    }
+
+    /// Get the prefetched track, never waits for a decoder that is not opened yet.
+    /// Tracks that can't be opened are skipped
+    fn take_next(&mut self) -> NextTrack {
+        while self.failed_opens < MAX_SKIP {
+            self.prefetch_next();
+            match std::mem::replace(&mut self.prefetch, Prefetch::NotStarted) {
+                Prefetch::Opening(track, handle) if !is_opened(&handle) => {
+                    self.prefetch = Prefetch::Opening(track, handle);
+                    return NextTrack::Opening;
+                }
+                Prefetch::Opening(track, handle) => match handle.join() {
+                    Ok(Some(decoder)) => {
+                        self.failed_opens = 0;
+                        return NextTrack::Ready(*track, decoder);
+                    }
+                    Ok(None) => error!("Could not match decoder for: {}", track.path.display()),
+                    Err(_) => error!("Opening decoder panicked for: {}", track.path.display()),
+                },
+                Prefetch::EndOfQueue => {
+                    self.prefetch = Prefetch::EndOfQueue;
+                    return NextTrack::EndOfQueue;
+                }
+                Prefetch::NotStarted => (),
+            }
+            // Skipped, the track stays in the history
+            self.playback_context.lock_queue().keep_next_track();
+            self.failed_opens += 1;
+        }
+        warn!("Could not open {MAX_SKIP} tracks in a row, stopping");
+        self.failed_opens = 0;
+        NextTrack::EndOfQueue
+    }
+
+    /// Stop the current track and play the track once it is opened on another thread
+    fn start_track(&mut self, track: TrackInfo) {
+        // The track is already the current track of the queue
+        self.playback_context.lock_queue().keep_next_track();
+        self.prefetch = Prefetch::open(track);
+        self.decoder = Decoder::none();
+    }
+
+    /// Set up a track to be decoded, the decoder is opened on this thread
+    fn set_track(&mut self, track: TrackInfo) -> Result<()> {
+        let decoder = match_audio_track(&track.path, track.audio_track)
+            .ok_or(anyhow!("Could not match decoder"))?;
+        self.start_decoder(track, decoder)
+    }
+
+    /// Use an opened decoder for the next samples
+    ///
+    /// The resampler is only rebuild when the format or the resampler config changes,
+    /// so the tracks join without a gap
+    fn start_decoder(&mut self, track: TrackInfo, decoder: Decoder) -> Result<()> {
+        let sample_rate_output = self.output_rate_for(&decoder);
+        let new_resampler = self.needs_new_resampler(&decoder);
+        self.decoder = decoder;
+        if sample_rate_output != self.sample_rate_output {
+            self.change_output_rate(sample_rate_output);
+        }
+        if new_resampler {
+            self.resampler.change_sample_rate(
+                self.decoder.sample_rate(),
+                self.sample_rate_output,
+                self.decoder.channels(),
+                self.playback_context.resampler_config(),
+            )?;
+        }
+        self.set_current(track);
         Ok(())
     }
 
+    /// Update everything that depends on the current track, after the decoder is changed
+    fn set_current(&mut self, track: TrackInfo) {
+        self.prefetch = Prefetch::NotStarted;
+        self.playback_context.set_track(
+            track.path.clone(),
+            self.decoder.length(),
+            self.decoder.sample_rate(),
+        );
+        self.update_progress();
+        self.track_info = Some(track);
+    }
+
+    /// Tell the context how much of the track is left,
+    /// and the length, which changes while decoding when it is an estimate
+    fn update_progress(&self) {
+        self.playback_context
+            .update_length(self.decoder.length(), self.decoder.length_is_estimate());
+        self.playback_context.update_left(self.decoder.left());
+    }
+
     pub fn goto(&mut self, target: u64) -> Result<()> {
+        self.cancel_crossfade();
+        // Decoded samples from before the target are never heard
+        self.clear_output();
         self.decoder.goto(target)?;
-        self.playback_context
-            .update_left(self.decoder.length() - target);
+        self.update_progress();
         Ok(())
     }
 
@@ -145,8 +754,11 @@ impl PlaybackDaemon {
         let track = queue.play_queue_item(item, flatten);
         // Dispose of mutex guard
         drop(queue);
+        // The prefetched track was from the old queue
+        self.prefetch = Prefetch::NotStarted;
+        self.fade_in = None;
         if let Some(track) = track {
-            self.set_track(track)?;
+            self.start_track(track.into());
             self.playing = true;
         } else {
             warn!("Tried to play empty queue item");
@@ -154,6 +766,96 @@ impl PlaybackDaemon {
         Ok(())
     }
 
+    /// Continue with a saved queue, the current track is opened at the saved position
+    ///
+    /// Does not start playing
+    pub fn resume(&mut self, queue: Queue, position: u64) -> Result<()> {
+        let current = queue.current_track().clone();
+        let track = queue
+            .last_played()
+            .filter(|x| Some(x.location()) == current.as_deref())
+            .cloned();
+        *self.playback_context.lock_queue() = queue;
+        self.prefetch = Prefetch::NotStarted;
+        self.fade_in = None;
+        let track = match (track, current) {
+            (Some(track), _) => track.into(),
+            (None, Some(path)) => TrackInfo::from_path(path),
+            (None, None) => return Ok(()),
+        };
+        self.set_track(track)?;
+        self.goto(position.min(self.decoder.length()))
+    }
+
+    /// Add an item to the end of the queue, if the queue had ended it is asked for the next track again
+    pub fn que(&mut self, item: QueueItem) {
+        if matches!(self.prefetch, Prefetch::EndOfQueue) {
+            // The queue continues with the item instead of starting over
+            self.discard_prefetch();
+        }
+        self.playback_context
+            .lock_queue()
+            .append_queue_item(item, false);
+    }
+
+    /// Change the queue, the prefetched track is given back
+    /// since the edit can change what should be played next
+    pub fn edit_queue<F>(&mut self, edit: F)
+    where
+        F: FnOnce(&mut Queue),
+    {
+        self.discard_prefetch();
+        edit(&mut self.playback_context.lock_queue());
+    }
+
+    /// Remove everything that would be played after the current track
+    pub fn clear_upcoming(&mut self) {
+        self.discard_prefetch();
+        self.playback_context.lock_queue().clear_upcoming();
+    }
+
+    /// Skip to the next track of the queue, skips over `repeat_current`
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<()> {
+        self.cancel_crossfade();
+        if self.playback_context.lock_queue().repeat_current {
+            self.discard_prefetch();
+            self.prefetch_from_queue();
+        }
+        self.clear_output();
+        match self.take_next() {
+            NextTrack::Ready(track, decoder) => self.start_decoder(track, decoder),
+            // The current track stops, the next track starts once it is opened
+            NextTrack::Opening => {
+                self.decoder = Decoder::none();
+                Ok(())
+            }
+            NextTrack::EndOfQueue => {
+                self.playing = false;
+                Ok(())
+            }
+        }
+    }
+
+    /// Go to the previous track in the history,
+    /// restarts the current track if it has played for a while or there is no previous track
+    pub fn previous(&mut self) -> Result<()> {
+        let played = self.decoder.length().saturating_sub(self.decoder.left());
+        if played > PREVIOUS_RESTART_SEC * self.decoder.sample_rate() as u64 {
+            return self.goto(0);
+        }
+        self.discard_prefetch();
+        let track = self.playback_context.lock_queue().previous_track();
+        match track {
+            Some(track) => {
+                self.clear_output();
+                self.start_track(track.into());
+                Ok(())
+            }
+            None => self.goto(0),
+        }
+    }
+
     pub fn current_length(&self) -> u64 {
         self.decoder.length()
     }
@@ -166,6 +868,43 @@ impl PlaybackDaemon {
         self.playback_context.change_volume_level(volume_change);
     }
 
+    pub fn set_crossfade(&self, crossfade: Crossfade) {
+        self.playback_context.set_crossfade(crossfade);
+    }
+
+    pub fn set_replay_gain_mode(&self, mode: ReplayGainMode) {
+        self.playback_context.set_replay_gain_mode(mode);
+    }
+
+    /// Shape the dither of integer output formats, so the noise is heard less
+    pub fn set_noise_shaping(&self, noise_shaping: bool) {
+        self.playback_context.set_noise_shaping(noise_shaping);
+    }
+
+    /// Play every track at its own sample rate if the device supports it, from the next track on.
+    /// The output stream is opened again at that rate, and tracks are not crossfaded
+    pub fn set_bit_perfect(&self, bit_perfect: bool) {
+        self.playback_context.set_bit_perfect(bit_perfect);
+    }
+
+    /// Choose how tracks are resampled, from the next track on
+    pub fn set_resampler(&self, config: ResamplerConfig) {
+        self.playback_context.set_resampler_config(config);
+    }
+
+    /// Stop the playback after the duration, `None` turns the sleep timer off
+    pub fn set_sleep_timer(&mut self, duration: Option<Duration>) {
+        self.sleep_timer = duration.map(|x| SleepTimer::new(x, self.sample_rate_output));
+    }
+
+    pub fn channels_output(&self) -> usize {
+        self.channels_output
+    }
+
+    pub fn sample_rate_output(&self) -> usize {
+        self.sample_rate_output
+    }
+
     pub fn sample_rate_input(&self) -> usize {
         self.decoder.sample_rate()
     }
@@ -174,6 +913,15 @@ impl PlaybackDaemon {
         self.playback_context.left()
     }
 
+    /// Publish how much decoded audio is not heard yet, so the progress follows the output.
+    /// `queued` is the amount of samples waiting in the output
+    pub fn update_buffered(&self, queued: usize) {
+        let frames = (queued + self.buffer_output.len()) / self.channels_output;
+        let frames = frames as u64 * self.decoder.sample_rate() as u64
+            / self.sample_rate_output.max(1) as u64;
+        self.playback_context.update_buffered(frames);
+    }
+
     pub fn get_playback_context(&self) -> ArcPlaybackContext {
         self.playback_context.clone()
     }
@@ -184,28 +932,29 @@ impl PlaybackResampler {
         sample_rate_input: usize,
         sample_rate_output: usize,
         channels: usize,
+        channels_output: usize,
+        config: ResamplerConfig,
     ) -> Option<PlaybackResampler> {
-        let fixed_in_out_resampler = FftFixedInOut::new(
-            sample_rate_input,
-            sample_rate_output,
-            sample_rate_input / 500,
-            channels,
-        )
-        .ok()?;
+        let resampler = config
+            .build(sample_rate_input, sample_rate_output, channels)
+            .ok()?;
+        let (frames_input, frames_output) = resampler_frames(&resampler);
         // Buffers
-        let input = fixed_in_out_resampler.input_buffer_allocate(true);
-        let output = fixed_in_out_resampler.output_buffer_allocate(true);
-        let decoder_output: Vec<BuF> =
-            vec![Sample::EQUILIBRIUM; fixed_in_out_resampler.input_frames_max() * channels];
-        let interleaved: Vec<BuF> =
-            vec![Sample::EQUILIBRIUM; fixed_in_out_resampler.output_frames_max() * channels];
+        let input = vec![vec![Sample::EQUILIBRIUM; frames_input]; channels];
+        let output = vec![vec![Sample::EQUILIBRIUM; frames_output]; channels];
+        let decoder_output: Vec<BuF> = vec![Sample::EQUILIBRIUM; frames_input * channels];
+        let interleaved: Vec<BuF> = vec![Sample::EQUILIBRIUM; frames_output * channels];
+        let mixed: Vec<BuF> = vec![Sample::EQUILIBRIUM; frames_output * channels_output];
 
         Some(PlaybackResampler {
-            fixed_in_out_resampler,
+            resampler,
+            config,
             decoder_output,
             input,
             output,
             interleaved,
+            channel_mixer: ChannelMixer::new(channels, channels_output),
+            mixed,
         })
     }
 
@@ -214,63 +963,88 @@ impl PlaybackResampler {
         sample_rate_input: usize,
         sample_rate_output: usize,
         channels: usize,
+        config: ResamplerConfig,
     ) -> Result<()> {
-        let channel_change = self.fixed_in_out_resampler.nbr_channels().cmp(&channels);
-        self.fixed_in_out_resampler = FftFixedInOut::new(
-            sample_rate_input,
-            sample_rate_output,
-            sample_rate_input / 500,
-            channels,
-        )?;
+        let channels_changed = self.channel_mixer.channels_input() != channels;
+        self.resampler = config.build(sample_rate_input, sample_rate_output, channels)?;
+        self.config = config;
+        let (frames_input, frames_output) = resampler_frames(&self.resampler);
 
-        match channel_change {
-            std::cmp::Ordering::Equal => {
-                rubato::resize_buffer(
-                    &mut self.input,
-                    self.fixed_in_out_resampler.input_frames_max(),
-                );
-                rubato::resize_buffer(
-                    &mut self.output,
-                    self.fixed_in_out_resampler.output_frames_max(),
-                );
-            }
-            std::cmp::Ordering::Less => unimplemented!("Can't change channels for now :)"),
-            std::cmp::Ordering::Greater => unimplemented!("Can't change channels for now :)"),
+        if channels_changed {
+            // The amount of planar buffers changes, so just make new ones
+            self.input = vec![vec![Sample::EQUILIBRIUM; frames_input]; channels];
+            self.output = vec![vec![Sample::EQUILIBRIUM; frames_output]; channels];
+            self.channel_mixer = ChannelMixer::new(channels, self.channel_mixer.channels_output());
+        } else {
+            rubato::resize_buffer(&mut self.input, frames_input);
+            rubato::resize_buffer(&mut self.output, frames_output);
         }
 
         // Buffers
-        self.decoder_output.resize(
-            self.fixed_in_out_resampler.input_frames_max() * channels,
-            Sample::EQUILIBRIUM,
-        );
-        self.interleaved.resize(
-            self.fixed_in_out_resampler.output_frames_max() * channels,
+        self.decoder_output
+            .resize(frames_input * channels, Sample::EQUILIBRIUM);
+        self.interleaved
+            .resize(frames_output * channels, Sample::EQUILIBRIUM);
+        self.mixed.resize(
+            frames_output * self.channel_mixer.channels_output(),
             Sample::EQUILIBRIUM,
         );
         Ok(())
     }
 
-    fn resample(&mut self, channels: usize) -> Result<()> {
+    /// The samples are only copied, there is nothing to resample or mix
+    fn is_transparent(&self) -> bool {
+        self.resampler.is_none() && self.channel_mixer.is_identity()
+    }
+
+    fn resample(&mut self) -> Result<()> {
+        let channels = self.channel_mixer.channels_input();
+        let Some(resampler) = self.resampler.as_mut() else {
+            // The same sample rate, the samples are only mixed
+            self.channel_mixer
+                .mix(&self.decoder_output, &mut self.mixed);
+            return Ok(());
+        };
         interleaved_to_planar(&self.decoder_output, &mut self.input, channels);
 
-        self.fixed_in_out_resampler
-            .process_into_buffer(&self.input, &mut self.output, None)?;
+        let (_, frames) = resampler.process_into_buffer(&self.input, &mut self.output, None)?;
 
+        // The sinc resamplers don't give the same amount of frames every time,
+        // the buffers stay within what they were allocated with
+        self.interleaved
+            .resize(frames * channels, Sample::EQUILIBRIUM);
+        self.mixed.resize(
+            frames * self.channel_mixer.channels_output(),
+            Sample::EQUILIBRIUM,
+        );
         planar_to_interleaved(&self.output, &mut self.interleaved, channels);
+        self.channel_mixer.mix(&self.interleaved, &mut self.mixed);
 
         Ok(())
     }
 }
 
+/// Frames going in and coming out of one resampling step
+fn resampler_frames(resampler: &Option<Box<dyn VecResampler<BuF>>>) -> (usize, usize) {
+    match resampler {
+        Some(resampler) => (resampler.input_frames_max(), resampler.output_frames_max()),
+        None => (BYPASS_FRAMES, BYPASS_FRAMES),
+    }
+}
+
+/// Open the decoder for the file with the registered decoders,
+/// the format is recognised by the content of the file
 pub fn match_decoder(file: &Path) -> Option<Decoder> {
-    match file.extension()?.to_str()? {
-        "opus" => Some(Decoder::Opus(
-            OpusReader::new(BufReader::new(File::open(file).print_err_ok()?)).print_err_ok()?,
-        )),
-        extension => Some(Decoder::Symphonia(
-            SymphoniaWrapper::new(File::open(file).print_err_ok()?, extension).print_err_ok()?,
-        )),
+    open_decoder(file).print_err_ok()
+}
+
+/// Open the decoder for an audio track of the file, the first audio track is 0
+pub fn match_audio_track(file: &Path, audio_track: usize) -> Option<Decoder> {
+    let mut decoder = match_decoder(file)?;
+    if audio_track != 0 {
+        decoder.select_audio_track(audio_track).print_err_ok()?;
     }
+    Some(decoder)
 }
 
 impl<T, E: Debug> PrintErrOk<T, E> for std::result::Result<T, E> {
@@ -290,3 +1064,274 @@ trait PrintErrOk<T, E> {
     /// but will print the error on an Err using the `error!()` macro
     fn print_err_ok(self) -> Option<T>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio_conversion::OutputConverter;
+    use crate::models::Track;
+    use crate::queue::queue_items::QueuePlaylist;
+    use chrono::NaiveDate;
+    use std::fs;
+
+    const SAMPLE_RATE: usize = 44100;
+    const CHANNELS: usize = 2;
+
+    /// A 16 bit PCM WAV file of the interleaved samples
+    fn write_wav(path: &Path, samples: &[i16]) {
+        let data_length = (samples.len() * 2) as u32;
+        let mut file = vec![];
+        file.extend_from_slice(b"RIFF");
+        file.extend_from_slice(&(36 + data_length).to_le_bytes());
+        file.extend_from_slice(b"WAVEfmt ");
+        file.extend_from_slice(&16u32.to_le_bytes());
+        file.extend_from_slice(&1u16.to_le_bytes());
+        file.extend_from_slice(&(CHANNELS as u16).to_le_bytes());
+        file.extend_from_slice(&(SAMPLE_RATE as u32).to_le_bytes());
+        file.extend_from_slice(&((SAMPLE_RATE * CHANNELS * 2) as u32).to_le_bytes());
+        file.extend_from_slice(&((CHANNELS * 2) as u16).to_le_bytes());
+        file.extend_from_slice(&16u16.to_le_bytes());
+        file.extend_from_slice(b"data");
+        file.extend_from_slice(&data_length.to_le_bytes());
+        for sample in samples {
+            file.extend_from_slice(&sample.to_le_bytes());
+        }
+        fs::write(path, file).unwrap();
+    }
+
+    #[test]
+    fn bit_perfect_output_is_transparent() {
+        // Every value of the format, in a scrambled order
+        let samples: Vec<i16> = (0..SAMPLE_RATE as u32 * CHANNELS as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 16) as i16)
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("rmusic-bit-perfect-{}.wav", std::process::id()));
+        write_wav(&path, &samples);
+
+        let mut decoder = match_decoder(&path).unwrap();
+        let mut decoded = vec![];
+        let mut buffer = vec![0.0; 4096];
+        while !decoder.finished() {
+            let filled = decoder.fill_available(&mut buffer).unwrap();
+            decoded.extend_from_slice(&buffer[..filled]);
+        }
+        assert_eq!(decoded.len(), samples.len());
+
+        // The device is opened at another rate, it can also play the rate of the track
+        let mut daemon = PlaybackDaemon::new(48000, CHANNELS);
+        daemon
+            .playback_context
+            .set_supported_sample_rates(vec![44100..=48000]);
+        daemon.set_bit_perfect(true);
+        daemon
+            .play(QueueItem::Track(queue_track(0, &path)), false)
+            .unwrap();
+
+        // Fill like the decode loop, a chunk never has audio at two sample rates
+        let mut chunk = vec![0.0; 882 * CHANNELS];
+        let mut output = vec![];
+        let mut rate_changed = false;
+        // Enough chunks for the track and the silence before the rate changes
+        for _ in 0..samples.len() / chunk.len() + 10 {
+            if output.len() >= samples.len() {
+                break;
+            }
+            let pending = daemon.samples_until_rate_change();
+            rate_changed |= pending.is_some();
+            let amount = pending.map_or(chunk.len(), |x| x.min(chunk.len()));
+            let before = daemon.playback_context.output_sample_rate();
+            daemon.fill(&mut chunk[..amount]).unwrap();
+            // Without a pending change the rate can change before the samples are taken
+            let sample_rate = match pending {
+                Some(_) => before,
+                None => daemon.playback_context.output_sample_rate(),
+            };
+            match sample_rate {
+                SAMPLE_RATE => {
+                    assert!(daemon.filled_unchanged());
+                    output.extend_from_slice(&chunk[..amount]);
+                }
+                _ => {
+                    assert!(chunk[..amount].iter().all(|x| *x == 0.0));
+                }
+            }
+        }
+        fs::remove_file(&path).unwrap();
+        assert!(rate_changed);
+        assert_eq!(daemon.output_rate_for(&daemon.decoder), SAMPLE_RATE);
+        assert_eq!(daemon.playback_context.output_sample_rate(), SAMPLE_RATE);
+        assert!(daemon.resampler.resampler.is_none());
+        assert_eq!(output.len(), samples.len());
+        assert!(decoded
+            .iter()
+            .zip(output.iter())
+            .all(|(x, y)| x.to_bits() == y.to_bits()));
+
+        let mut converter = OutputConverter::new(CHANNELS);
+        converter.set_dither(false);
+        let mut converted = vec![0i16; output.len()];
+        converter.convert(&output, &mut converted);
+        assert_eq!(converted, samples);
+    }
+
+    fn queue_track(id: i32, path: &Path) -> QueueTrack {
+        QueueTrack::new(
+            Track {
+                id,
+                name: format!("Track {id}"),
+                date: NaiveDate::default(),
+                number: id,
+                duration: 1,
+                artist_id: 0,
+                release_id: 0,
+                replay_gain: None,
+                replay_peak: None,
+                loudness: None,
+                loudness_range: None,
+                true_peak: None,
+            },
+            path.to_path_buf(),
+        )
+    }
+
+    #[test]
+    fn resume_queue_saved_while_prefetching() {
+        let paths: Vec<PathBuf> = (0..2)
+            .map(|i| {
+                std::env::temp_dir().join(format!("rmusic-resume-{i}-{}.wav", std::process::id()))
+            })
+            .collect();
+        for path in &paths {
+            write_wav(path, &vec![0; SAMPLE_RATE * CHANNELS]);
+        }
+        let tracks: Vec<QueueTrack> = (0..2).map(|i| queue_track(i, &paths[i as usize])).collect();
+        let items = tracks.iter().cloned().map(QueueItem::Track).collect();
+
+        let mut daemon = PlaybackDaemon::new(SAMPLE_RATE, CHANNELS);
+        daemon
+            .play(QueuePlaylist::from_items(items).into(), false)
+            .unwrap();
+        // The tracks are shorter than the prefetch time,
+        // so the second track is taken from the queue once the first one starts
+        let mut chunk = vec![0.0; 882 * CHANNELS];
+        for _ in 0..10 {
+            if matches!(&daemon.prefetch, Prefetch::Opening(track, _) if track.path == paths[1]) {
+                break;
+            }
+            daemon.fill(&mut chunk).unwrap();
+        }
+        assert!(matches!(&daemon.prefetch, Prefetch::Opening(track, _) if track.path == paths[1]));
+        assert_eq!(
+            daemon.playback_context.lock_queue().last_played(),
+            Some(&tracks[1])
+        );
+
+        let saved = daemon.playback_context.queue_snapshot();
+        let position = daemon.playback_context.played();
+        let mut resumed = PlaybackDaemon::new(SAMPLE_RATE, CHANNELS);
+        resumed.resume(saved, position).unwrap();
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(resumed.track_info.map(|x| x.path), Some(paths[0].clone()));
+        assert_eq!(resumed.decoder.left(), daemon.decoder.left());
+        assert_eq!(
+            resumed.playback_context.lock_queue().next_track(),
+            Some(tracks[1].clone())
+        );
+    }
+
+    /// A daemon playing the first of the tracks, the second one is prefetched
+    fn prefetching_daemon(name: &str) -> (PlaybackDaemon, Vec<PathBuf>) {
+        let paths: Vec<PathBuf> = (0..4)
+            .map(|i| {
+                std::env::temp_dir().join(format!("rmusic-{name}-{i}-{}.wav", std::process::id()))
+            })
+            .collect();
+        for path in &paths {
+            write_wav(path, &vec![0; SAMPLE_RATE * CHANNELS]);
+        }
+        let items = (0..3)
+            .map(|i| QueueItem::Track(queue_track(i, &paths[i as usize])))
+            .collect();
+        let mut daemon = PlaybackDaemon::new(SAMPLE_RATE, CHANNELS);
+        daemon
+            .play(QueuePlaylist::from_items(items).into(), true)
+            .unwrap();
+        let mut chunk = vec![0.0; 882 * CHANNELS];
+        for _ in 0..10 {
+            if matches!(&daemon.prefetch, Prefetch::Opening(track, _) if track.path == paths[1]) {
+                break;
+            }
+            daemon.fill(&mut chunk).unwrap();
+        }
+        assert!(matches!(&daemon.prefetch, Prefetch::Opening(track, _) if track.path == paths[1]));
+        (daemon, paths)
+    }
+
+    /// The paths of the next tracks the daemon plays
+    fn next_paths(daemon: &mut PlaybackDaemon, amount: usize) -> Vec<PathBuf> {
+        (0..amount)
+            .map_while(|_| match daemon.take_next() {
+                NextTrack::Ready(track, _) => Some(track.path),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn play_next_while_prefetching() {
+        let (mut daemon, paths) = prefetching_daemon("play-next");
+        let next = queue_track(3, &paths[3]);
+        daemon.edit_queue(|queue| queue.play_next(QueueItem::Track(next)));
+        let played = next_paths(&mut daemon, 3);
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(played, [3, 1, 2].map(|i| paths[i].clone()));
+    }
+
+    #[test]
+    fn remove_prefetched_track() {
+        let (mut daemon, paths) = prefetching_daemon("remove");
+        daemon.edit_queue(|queue| {
+            queue.remove_queue_item(1);
+        });
+        let played = next_paths(&mut daemon, 2);
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(played, [2].map(|i| paths[i].clone()));
+        assert_eq!(
+            daemon.playback_context.lock_queue().last_played(),
+            Some(&queue_track(2, &paths[2]))
+        );
+    }
+
+    #[test]
+    fn move_prefetched_track() {
+        let (mut daemon, paths) = prefetching_daemon("move");
+        daemon.edit_queue(|queue| {
+            queue.move_queue_item(1, 2);
+        });
+        let played = next_paths(&mut daemon, 3);
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(played, [2, 1].map(|i| paths[i].clone()));
+    }
+
+    #[test]
+    fn que_after_end_of_queue() {
+        let (mut daemon, paths) = prefetching_daemon("que");
+        assert_eq!(next_paths(&mut daemon, 3), [1, 2].map(|i| paths[i].clone()));
+        assert!(matches!(daemon.prefetch, Prefetch::EndOfQueue));
+        daemon.que(QueueItem::Track(queue_track(3, &paths[3])));
+        let played = next_paths(&mut daemon, 2);
+        for path in &paths {
+            fs::remove_file(path).unwrap();
+        }
+        assert_eq!(played, [3].map(|i| paths[i].clone()));
+    }
+}
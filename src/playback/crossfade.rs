@@ -0,0 +1,66 @@
+use std::f32::consts::FRAC_PI_2;
+use std::time::Duration;
+
+use crate::BuF;
+
+/// Dynamic range of the logarithmic curve in dB
+const LOG_RANGE_DB: BuF = 60.0;
+
+/// The shape of the volume change during a crossfade
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum FadeCurve {
+    /// The gains of both tracks always add up to one
+    #[default]
+    Linear,
+    /// The power of both tracks always adds up to one,
+    /// avoids the dip in loudness halfway a linear fade
+    EqualPower,
+    /// Linear in dB, sounds like a steady change in loudness
+    Logarithmic,
+}
+
+impl FadeCurve {
+    /// Gain of the incoming track, progress goes from 0.0 to 1.0
+    pub fn fade_in(&self, progress: BuF) -> BuF {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            FadeCurve::Linear => progress,
+            FadeCurve::EqualPower => (progress * FRAC_PI_2).sin(),
+            FadeCurve::Logarithmic => {
+                if progress == 0.0 {
+                    0.0
+                } else {
+                    BuF::powf(10.0, (progress - 1.0) * LOG_RANGE_DB / 20.0)
+                }
+            }
+        }
+    }
+
+    /// Gain of the outgoing track, progress goes from 0.0 to 1.0
+    pub fn fade_out(&self, progress: BuF) -> BuF {
+        self.fade_in(1.0 - progress)
+    }
+}
+
+/// How consecutive tracks are faded into each other
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Crossfade {
+    /// A duration of zero turns crossfading off
+    pub duration: Duration,
+    pub curve: FadeCurve,
+}
+
+impl Crossfade {
+    pub fn new(duration: Duration, curve: FadeCurve) -> Crossfade {
+        Crossfade { duration, curve }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.duration.is_zero()
+    }
+
+    /// The duration in samples (per channel) for the sample rate
+    pub fn samples(&self, sample_rate: usize) -> u64 {
+        (self.duration.as_millis() as u64 * sample_rate as u64) / 1000
+    }
+}
@@ -1,7 +1,8 @@
 use std::{
+    ops::RangeInclusive,
     path::PathBuf,
     sync::{
-        atomic::{AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex,
     },
 };
@@ -11,16 +12,42 @@ use log::error;
 
 use crate::queue::Queue;
 
+use super::crossfade::Crossfade;
+use super::replay_gain::ReplayGainMode;
+use super::resampling::ResamplerConfig;
 use super::BuF;
 
 pub type ArcPlaybackContext = Arc<PlaybackContext>;
 
 pub struct PlaybackContext {
     pub queue: Mutex<Queue>,
+    /// The output plays, it is silent and keeps its buffer while paused
+    playing: AtomicBool,
     left: AtomicU64,
+    /// Frames of the current track that were decoded but are not heard yet,
+    /// at the sample rate of the track
+    buffered: AtomicU64,
     length: AtomicU64,
+    /// The length is not known yet, it is refined while decoding
+    length_estimate: AtomicBool,
     sample_rate: AtomicUsize,
     volume_level: AtomicF32,
+    crossfade: Mutex<Crossfade>,
+    replay_gain_mode: Mutex<ReplayGainMode>,
+    resampler_config: Mutex<ResamplerConfig>,
+    /// Shape the dither of integer outputs
+    noise_shaping: AtomicBool,
+    /// Position in the output buffer after the last sample that is not exactly as it was decoded,
+    /// the samples after it are converted without dither
+    changed_until: AtomicUsize,
+    /// Play every track at its own sample rate when the device supports it
+    bit_perfect: AtomicBool,
+    /// Sample rates the output device can be opened at
+    supported_sample_rates: Mutex<Vec<RangeInclusive<usize>>>,
+    /// The sample rate the decoded audio has, the stream is reopened when it differs
+    output_sample_rate: AtomicUsize,
+    /// The sample rate of the open output stream
+    stream_sample_rate: AtomicUsize,
 }
 
 impl PlaybackContext {
@@ -32,10 +59,22 @@ impl PlaybackContext {
         let volume_level = AtomicF32::new(100.0);
         Arc::new(PlaybackContext {
             queue,
+            playing: AtomicBool::new(false),
             left,
+            buffered: AtomicU64::new(0),
             length,
+            length_estimate: AtomicBool::new(false),
             sample_rate,
             volume_level,
+            crossfade: Mutex::new(Crossfade::default()),
+            replay_gain_mode: Mutex::new(ReplayGainMode::default()),
+            resampler_config: Mutex::new(ResamplerConfig::default()),
+            noise_shaping: AtomicBool::new(false),
+            changed_until: AtomicUsize::new(0),
+            bit_perfect: AtomicBool::new(false),
+            supported_sample_rates: Mutex::new(vec![]),
+            output_sample_rate: AtomicUsize::new(0),
+            stream_sample_rate: AtomicUsize::new(0),
         })
     }
 
@@ -54,17 +93,42 @@ impl PlaybackContext {
         let volume_level = AtomicF32::new(volume_level);
         Arc::new(PlaybackContext {
             queue,
+            playing: AtomicBool::new(false),
             left,
+            buffered: AtomicU64::new(0),
             length,
+            length_estimate: AtomicBool::new(false),
             sample_rate,
             volume_level,
+            crossfade: Mutex::new(Crossfade::default()),
+            replay_gain_mode: Mutex::new(ReplayGainMode::default()),
+            resampler_config: Mutex::new(ResamplerConfig::default()),
+            noise_shaping: AtomicBool::new(false),
+            changed_until: AtomicUsize::new(0),
+            bit_perfect: AtomicBool::new(false),
+            supported_sample_rates: Mutex::new(vec![]),
+            output_sample_rate: AtomicUsize::new(0),
+            stream_sample_rate: AtomicUsize::new(0),
         })
     }
 
+    pub(crate) fn set_playing(&self, playing: bool) {
+        self.playing.store(playing, Ordering::Relaxed)
+    }
+
     pub(crate) fn update_left(&self, left: u64) {
         self.left.store(left, Ordering::Relaxed)
     }
 
+    pub(crate) fn update_buffered(&self, buffered: u64) {
+        self.buffered.store(buffered, Ordering::Relaxed)
+    }
+
+    pub(crate) fn update_length(&self, length: u64, estimate: bool) {
+        self.length.store(length, Ordering::Relaxed);
+        self.length_estimate.store(estimate, Ordering::Relaxed);
+    }
+
     pub fn update_volume_level(&self, volume_level: BuF) {
         self.volume_level
             .store(volume_level.max(0.0), Ordering::Relaxed);
@@ -90,22 +154,148 @@ impl PlaybackContext {
         }
     }
 
+    pub fn set_crossfade(&self, crossfade: Crossfade) {
+        match self.crossfade.lock() {
+            Ok(mut lock) => *lock = crossfade,
+            Err(err) => *err.into_inner() = crossfade,
+        }
+    }
+
+    pub fn crossfade(&self) -> Crossfade {
+        match self.crossfade.lock() {
+            Ok(lock) => *lock,
+            Err(err) => *err.into_inner(),
+        }
+    }
+
+    pub fn set_replay_gain_mode(&self, mode: ReplayGainMode) {
+        match self.replay_gain_mode.lock() {
+            Ok(mut lock) => *lock = mode,
+            Err(err) => *err.into_inner() = mode,
+        }
+    }
+
+    pub fn replay_gain_mode(&self) -> ReplayGainMode {
+        match self.replay_gain_mode.lock() {
+            Ok(lock) => *lock,
+            Err(err) => *err.into_inner(),
+        }
+    }
+
+    pub fn set_resampler_config(&self, config: ResamplerConfig) {
+        match self.resampler_config.lock() {
+            Ok(mut lock) => *lock = config,
+            Err(err) => *err.into_inner() = config,
+        }
+    }
+
+    pub fn resampler_config(&self) -> ResamplerConfig {
+        match self.resampler_config.lock() {
+            Ok(lock) => *lock,
+            Err(err) => *err.into_inner(),
+        }
+    }
+
+    pub fn set_noise_shaping(&self, noise_shaping: bool) {
+        self.noise_shaping.store(noise_shaping, Ordering::Relaxed)
+    }
+
+    pub fn noise_shaping(&self) -> bool {
+        self.noise_shaping.load(Ordering::Relaxed)
+    }
+
+    /// The samples before `end` in the output buffer were changed, set before they are written
+    pub(crate) fn set_changed_until(&self, end: usize) {
+        self.changed_until.store(end, Ordering::Release)
+    }
+
+    /// The samples from `start` in the output buffer are exactly as they were decoded
+    pub(crate) fn unchanged_from(&self, start: usize) -> bool {
+        // The positions wrap around, reading is never far behind writing
+        start.wrapping_sub(self.changed_until.load(Ordering::Acquire)) as isize >= 0
+    }
+
+    pub fn set_bit_perfect(&self, bit_perfect: bool) {
+        self.bit_perfect.store(bit_perfect, Ordering::Relaxed)
+    }
+
+    pub fn bit_perfect(&self) -> bool {
+        self.bit_perfect.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_supported_sample_rates(&self, sample_rates: Vec<RangeInclusive<usize>>) {
+        match self.supported_sample_rates.lock() {
+            Ok(mut lock) => *lock = sample_rates,
+            Err(err) => *err.into_inner() = sample_rates,
+        }
+    }
+
+    /// The output device can be opened at the sample rate
+    pub fn supports_sample_rate(&self, sample_rate: usize) -> bool {
+        let supported = match self.supported_sample_rates.lock() {
+            Ok(lock) => lock,
+            Err(err) => err.into_inner(),
+        };
+        supported.iter().any(|x| x.contains(&sample_rate))
+    }
+
+    pub(crate) fn set_output_sample_rate(&self, sample_rate: usize) {
+        self.output_sample_rate
+            .store(sample_rate, Ordering::Relaxed)
+    }
+
+    /// The sample rate of the decoded audio, after resampling
+    pub fn output_sample_rate(&self) -> usize {
+        self.output_sample_rate.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_stream_sample_rate(&self, sample_rate: usize) {
+        self.stream_sample_rate
+            .store(sample_rate, Ordering::Relaxed)
+    }
+
+    /// The sample rate the output stream was opened at
+    pub fn stream_sample_rate(&self) -> usize {
+        self.stream_sample_rate.load(Ordering::Relaxed)
+    }
+
     pub(crate) fn set_track(&self, track: PathBuf, length: u64, sample_rate: usize) {
         self.length.store(length, Ordering::Relaxed);
         self.sample_rate.store(sample_rate, Ordering::Relaxed);
         let mut queue = self.lock_queue();
         queue.current_track = Some(track);
+        // The prefetched track is the current track now
+        queue.keep_next_track();
+    }
+
+    /// A copy of the queue with the prefetched track given back,
+    /// so the last track of the history is the current track
+    pub fn queue_snapshot(&self) -> Queue {
+        let mut snapshot = self.lock_queue().clone();
+        snapshot.return_next_track();
+        snapshot
     }
 
     pub fn current_track(&self) -> Option<PathBuf> {
         self.lock_queue().current_track.clone()
     }
+    pub fn playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+    /// Frames left of what is heard, the decoder is ahead by the buffered audio
     pub fn left(&self) -> u64 {
-        self.left.load(Ordering::Relaxed)
+        let left = self.left.load(Ordering::Relaxed) + self.buffered.load(Ordering::Relaxed);
+        left.min(self.length())
     }
+    /// Length of the current track, see [`PlaybackContext::length_is_estimate`]
     pub fn length(&self) -> u64 {
         self.length.load(Ordering::Relaxed)
     }
+    /// The length was estimated and can still change,
+    /// for streams and files that don't have their length
+    pub fn length_is_estimate(&self) -> bool {
+        self.length_estimate.load(Ordering::Relaxed)
+    }
     pub fn sample_rate(&self) -> usize {
         self.sample_rate.load(Ordering::Relaxed)
     }
@@ -0,0 +1,168 @@
+use crate::BuF;
+
+/// Highest sample value the limiter lets through
+const LIMITER_THRESHOLD: BuF = 1.0;
+/// Time in seconds for the limiter to recover from a peak
+const LIMITER_RELEASE_SEC: BuF = 0.05;
+/// Loudness in LUFS that ReplayGain normalises to
+const REPLAY_GAIN_REFERENCE: f32 = -18.0;
+
+/// Which ReplayGain value is used to normalise the loudness
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    /// Every track has the same loudness
+    Track,
+    /// Every album has the same loudness, keeps the differences between tracks of an album
+    Album,
+}
+
+/// A gain in dB with the peak (linear) of the audio it belongs to
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ReplayGain {
+    pub gain: f32,
+    pub peak: Option<f32>,
+}
+
+impl ReplayGain {
+    pub fn new(gain: f32, peak: Option<f32>) -> ReplayGain {
+        ReplayGain { gain, peak }
+    }
+
+    /// Create from the database columns
+    pub fn from_columns(gain: Option<f32>, peak: Option<f32>) -> Option<ReplayGain> {
+        Some(ReplayGain::new(gain?, peak))
+    }
+
+    /// Create from a measured loudness in LUFS and true peak in dBTP
+    pub fn from_loudness(loudness: Option<f32>, true_peak: Option<f32>) -> Option<ReplayGain> {
+        Some(ReplayGain::new(
+            REPLAY_GAIN_REFERENCE - loudness?,
+            true_peak.map(|x| BuF::powf(10.0, x / 20.0)),
+        ))
+    }
+
+    /// The linear gain, lowered if needed so the peak does not clip
+    pub fn linear(&self) -> BuF {
+        let gain = BuF::powf(10.0, self.gain / 20.0);
+        match self.peak {
+            Some(peak) if peak > 0.0 => gain.min(LIMITER_THRESHOLD / peak),
+            _ => gain,
+        }
+    }
+}
+
+/// All ReplayGain values known for a track
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct TrackGain {
+    pub track: Option<ReplayGain>,
+    pub album: Option<ReplayGain>,
+}
+
+impl TrackGain {
+    /// The linear gain for the mode,
+    /// falls back to the other value if the value for the mode is missing
+    pub fn linear(&self, mode: ReplayGainMode) -> BuF {
+        let replay_gain = match mode {
+            ReplayGainMode::Off => None,
+            ReplayGainMode::Track => self.track.or(self.album),
+            ReplayGainMode::Album => self.album.or(self.track),
+        };
+        replay_gain.map_or(1.0, |x| x.linear())
+    }
+}
+
+/// Multiply all samples with the gain
+pub fn apply_gain(data: &mut [BuF], gain: BuF) {
+    if gain == 1.0 {
+        return;
+    }
+    for i in data.iter_mut() {
+        *i *= gain
+    }
+}
+
+/// Peak limiter that keeps the samples from clipping
+///
+/// Reacts instantly to a peak and slowly releases afterwards,
+/// samples below the threshold are not touched when nothing was limited
+pub struct Limiter {
+    gain: BuF,
+    release: BuF,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: usize) -> Limiter {
+        Limiter {
+            gain: 1.0,
+            release: 1.0 - (-1.0 / (LIMITER_RELEASE_SEC * sample_rate.max(1) as BuF)).exp(),
+        }
+    }
+
+    /// The last frame was turned down
+    pub fn is_limiting(&self) -> bool {
+        self.gain < 1.0
+    }
+
+    /// Limit one frame, all channels get the same gain
+    pub fn process(&mut self, frame: &mut [BuF]) {
+        let peak = frame.iter().fold(0.0, |max: BuF, x| max.max(x.abs()));
+        let target = if peak > LIMITER_THRESHOLD {
+            LIMITER_THRESHOLD / peak
+        } else {
+            1.0
+        };
+        if target < self.gain {
+            self.gain = target;
+        } else if self.gain < 1.0 {
+            self.gain = (self.gain + (1.0 - self.gain) * self.release).min(target);
+            if self.gain > 0.9999 && target == 1.0 {
+                self.gain = 1.0;
+            }
+        }
+        apply_gain(frame, self.gain);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gain_reaches_the_reference() {
+        let replay_gain = ReplayGain::from_loudness(Some(-23.0), Some(-6.0)).unwrap();
+        assert_eq!(replay_gain.gain, 5.0);
+        assert!((replay_gain.peak.unwrap() - 0.501).abs() < 0.001);
+    }
+
+    #[test]
+    fn no_gain_without_loudness() {
+        assert_eq!(ReplayGain::from_loudness(None, Some(-6.0)), None);
+        let gain = TrackGain {
+            track: ReplayGain::from_loudness(None, None),
+            album: None,
+        };
+        assert_eq!(gain.linear(ReplayGainMode::Track), 1.0);
+    }
+
+    #[test]
+    fn gain_is_lowered_to_not_clip() {
+        let replay_gain = ReplayGain::from_loudness(Some(-30.0), Some(-6.0)).unwrap();
+        assert!((replay_gain.linear() - 1.0 / replay_gain.peak.unwrap()).abs() < 1e-6);
+        let replay_gain = ReplayGain::from_loudness(Some(-30.0), None).unwrap();
+        assert!((replay_gain.linear() - BuF::powf(10.0, 0.6)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn mode_falls_back_to_the_other_gain() {
+        let gain = TrackGain {
+            track: None,
+            album: Some(ReplayGain::new(-6.0, None)),
+        };
+        let expected = BuF::powf(10.0, -6.0 / 20.0);
+        assert_eq!(gain.linear(ReplayGainMode::Track), expected);
+        assert_eq!(gain.linear(ReplayGainMode::Album), expected);
+        assert_eq!(gain.linear(ReplayGainMode::Off), 1.0);
+    }
+}
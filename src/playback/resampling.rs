@@ -0,0 +1,234 @@
+use rubato::{
+    calculate_cutoff, FftFixedInOut, ResamplerConstructionError, SincFixedIn,
+    SincInterpolationParameters, SincInterpolationType, VecResampler, WindowFunction,
+};
+
+use crate::BuF;
+
+/// Length of the sinc filter of the low-latency profile, the delay is half of it
+const LOW_LATENCY_SINC_LENGTH: usize = 64;
+/// Length of the sinc filter of the high-quality profile
+const HIGH_QUALITY_SINC_LENGTH: usize = 512;
+/// Points between two input samples the sinc filter is calculated for
+const SINC_OVERSAMPLING: usize = 256;
+
+/// The kind of resampler, from the least work to the least aliasing
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ResamplerProfile {
+    /// A short sinc filter with linear interpolation, the top of the audio band is rolled off
+    LowLatency,
+    /// Synchronous resampling with FFTs, flat up to close to the Nyquist frequency
+    #[default]
+    Balanced,
+    /// A long sinc filter with cubic interpolation
+    HighQuality,
+}
+
+/// The window of the sinc filter, a slower rolloff gives a better attenuation
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SincWindow {
+    Hann,
+    Hann2,
+    Blackman,
+    Blackman2,
+    BlackmanHarris,
+    #[default]
+    BlackmanHarris2,
+}
+
+impl From<SincWindow> for WindowFunction {
+    fn from(value: SincWindow) -> Self {
+        match value {
+            SincWindow::Hann => WindowFunction::Hann,
+            SincWindow::Hann2 => WindowFunction::Hann2,
+            SincWindow::Blackman => WindowFunction::Blackman,
+            SincWindow::Blackman2 => WindowFunction::Blackman2,
+            SincWindow::BlackmanHarris => WindowFunction::BlackmanHarris,
+            SincWindow::BlackmanHarris2 => WindowFunction::BlackmanHarris2,
+        }
+    }
+}
+
+/// How the tracks are resampled to the sample rate of the output
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ResamplerConfig {
+    pub profile: ResamplerProfile,
+    /// Frames of the input resampled at once, the FFT resampler rounds it up
+    /// to a multiple that fits both sample rates
+    pub chunk_frames: usize,
+    /// Only used by the sinc profiles, the FFT resampler has its own window
+    pub window: SincWindow,
+}
+
+impl Default for ResamplerConfig {
+    fn default() -> Self {
+        ResamplerConfig::new(ResamplerProfile::default())
+    }
+}
+
+impl ResamplerConfig {
+    /// The profile with the chunk size and window it works best with
+    pub fn new(profile: ResamplerProfile) -> ResamplerConfig {
+        let (chunk_frames, window) = match profile {
+            ResamplerProfile::LowLatency => (128, SincWindow::Blackman2),
+            ResamplerProfile::Balanced => (1024, SincWindow::default()),
+            ResamplerProfile::HighQuality => (2048, SincWindow::BlackmanHarris2),
+        };
+        ResamplerConfig {
+            profile,
+            chunk_frames,
+            window,
+        }
+    }
+
+    pub fn with_chunk_frames(self, chunk_frames: usize) -> ResamplerConfig {
+        ResamplerConfig {
+            chunk_frames,
+            ..self
+        }
+    }
+
+    pub fn with_window(self, window: SincWindow) -> ResamplerConfig {
+        ResamplerConfig { window, ..self }
+    }
+
+    /// The resampler between the sample rates, `None` when they are the same
+    pub(crate) fn build(
+        &self,
+        sample_rate_input: usize,
+        sample_rate_output: usize,
+        channels: usize,
+    ) -> Result<Option<Box<dyn VecResampler<BuF>>>, ResamplerConstructionError> {
+        if sample_rate_input == sample_rate_output {
+            return Ok(None);
+        }
+        let chunk_frames = self.chunk_frames.max(1);
+        let (sinc_length, interpolation) = match self.profile {
+            ResamplerProfile::Balanced => {
+                return Ok(Some(Box::new(FftFixedInOut::new(
+                    sample_rate_input,
+                    sample_rate_output,
+                    chunk_frames,
+                    channels,
+                )?)))
+            }
+            ResamplerProfile::LowLatency => {
+                (LOW_LATENCY_SINC_LENGTH, SincInterpolationType::Linear)
+            }
+            ResamplerProfile::HighQuality => {
+                (HIGH_QUALITY_SINC_LENGTH, SincInterpolationType::Cubic)
+            }
+        };
+        let parameters = SincInterpolationParameters {
+            sinc_len: sinc_length,
+            f_cutoff: calculate_cutoff(sinc_length, self.window.into()),
+            oversampling_factor: SINC_OVERSAMPLING,
+            interpolation,
+            window: self.window.into(),
+        };
+        Ok(Some(Box::new(SincFixedIn::new(
+            sample_rate_output as f64 / sample_rate_input as f64,
+            1.0,
+            parameters,
+            chunk_frames,
+            channels,
+        )?)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    /// Downsampling, so the anti-aliasing filter is needed
+    const SAMPLE_RATE_INPUT: usize = 96000;
+    const SAMPLE_RATE_OUTPUT: usize = 44100;
+    const AMPLITUDE: f64 = 0.5;
+
+    /// Level in dB of a sine after resampling, relative to the input.
+    /// Tones above the Nyquist frequency of the output are measured where they alias to
+    fn level(config: &ResamplerConfig, frequency: f64) -> f64 {
+        let mut resampler = config
+            .build(SAMPLE_RATE_INPUT, SAMPLE_RATE_OUTPUT, 1)
+            .unwrap()
+            .unwrap();
+        let mut input = vec![vec![0.0; resampler.input_frames_max()]];
+        let mut output = vec![vec![0.0; resampler.output_frames_max()]];
+        let mut resampled = vec![];
+        let mut position = 0;
+        while position < SAMPLE_RATE_INPUT / 2 {
+            for (i, sample) in input[0].iter_mut().enumerate() {
+                let phase = 2.0 * PI * frequency * (position + i) as f64;
+                *sample = ((phase / SAMPLE_RATE_INPUT as f64).sin() * AMPLITUDE) as BuF;
+            }
+            let (read, written) = resampler
+                .process_into_buffer(&input, &mut output, None)
+                .unwrap();
+            resampled.extend_from_slice(&output[0][..written]);
+            position += read;
+        }
+        let measured = match frequency > SAMPLE_RATE_OUTPUT as f64 / 2.0 {
+            true => SAMPLE_RATE_OUTPUT as f64 - frequency,
+            false => frequency,
+        };
+        // Skip the delay of the filter, then correlate with the measured frequency
+        let start = SAMPLE_RATE_OUTPUT / 10;
+        let samples = &resampled[start..start + SAMPLE_RATE_OUTPUT / 4];
+        let (mut sine, mut cosine) = (0.0, 0.0);
+        for (i, sample) in samples.iter().enumerate() {
+            let phase = 2.0 * PI * measured * (start + i) as f64 / SAMPLE_RATE_OUTPUT as f64;
+            sine += *sample as f64 * phase.sin();
+            cosine += *sample as f64 * phase.cos();
+        }
+        let amplitude = 2.0 * (sine * sine + cosine * cosine).sqrt() / samples.len() as f64;
+        20.0 * (amplitude / AMPLITUDE).log10()
+    }
+
+    /// Checks the passband up to `passband` of the output Nyquist frequency,
+    /// and the aliasing of tones from `stopband` of it up to the input Nyquist frequency
+    fn check_profile(
+        profile: ResamplerProfile,
+        passband: f64,
+        max_ripple_db: f64,
+        stopband: f64,
+        min_attenuation_db: f64,
+    ) {
+        let config = ResamplerConfig::new(profile);
+        let nyquist_output = SAMPLE_RATE_OUTPUT as f64 / 2.0;
+        let nyquist_input = SAMPLE_RATE_INPUT as f64 / 2.0;
+        for i in 1..=5 {
+            let frequency = nyquist_output * passband * i as f64 / 5.0;
+            let level = level(&config, frequency);
+            assert!(
+                level.abs() < max_ripple_db,
+                "{profile:?}: {level} dB at {frequency} Hz in the passband"
+            );
+        }
+        let low = nyquist_output * stopband;
+        let high = nyquist_input * 0.98;
+        for i in 0..4 {
+            let frequency = low + (high - low) * i as f64 / 3.0;
+            let level = level(&config, frequency);
+            assert!(
+                level < -min_attenuation_db,
+                "{profile:?}: {frequency} Hz aliases at {level} dB"
+            );
+        }
+    }
+
+    #[test]
+    fn low_latency_profile() {
+        check_profile(ResamplerProfile::LowLatency, 0.5, 0.05, 1.2, 80.0);
+    }
+
+    #[test]
+    fn balanced_profile() {
+        check_profile(ResamplerProfile::Balanced, 0.9, 0.05, 1.1, 120.0);
+    }
+
+    #[test]
+    fn high_quality_profile() {
+        check_profile(ResamplerProfile::HighQuality, 0.9, 0.05, 1.1, 120.0);
+    }
+}
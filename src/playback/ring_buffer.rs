@@ -0,0 +1,182 @@
+use std::sync::{
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+    Arc,
+};
+
+use crate::BuF;
+
+/// The samples are stored as their bits, so both sides can use them without a lock
+struct Shared {
+    /// A power of two long, so the index stays in order when the counters wrap around
+    samples: Box<[AtomicU32]>,
+    capacity: usize,
+    /// Samples written, only changed by the producer
+    written: AtomicUsize,
+    /// Samples read, only changed by the consumer
+    read: AtomicUsize,
+    /// The consumer skips everything written before this
+    discard: AtomicUsize,
+}
+
+/// Writes into a ring buffer, from one thread
+pub struct Producer(Arc<Shared>);
+
+/// Reads from a ring buffer, from one thread. Never blocks or allocates
+pub struct Consumer(Arc<Shared>);
+
+/// A bounded single-producer single-consumer ring buffer, all memory is allocated here
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    let capacity = capacity.max(1);
+    let shared = Arc::new(Shared {
+        samples: (0..capacity.next_power_of_two())
+            .map(|_| AtomicU32::new(0))
+            .collect(),
+        capacity,
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        discard: AtomicUsize::new(0),
+    });
+    (Producer(shared.clone()), Consumer(shared))
+}
+
+impl Shared {
+    fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn index(&self, position: usize) -> usize {
+        position & (self.samples.len() - 1)
+    }
+}
+
+impl Producer {
+    /// Samples that can be written without overwriting unread samples
+    pub fn free(&self) -> usize {
+        let written = self.0.written.load(Ordering::Relaxed);
+        let read = self.0.read.load(Ordering::Acquire);
+        self.0.capacity() - written.wrapping_sub(read)
+    }
+
+    /// Position of the next sample that is written, counted from the start and wrapping around
+    pub fn position(&self) -> usize {
+        self.0.written.load(Ordering::Relaxed)
+    }
+
+    /// Samples that were written and not read yet
+    pub fn buffered(&self) -> usize {
+        self.0.capacity() - self.free()
+    }
+
+    /// Write as much of the data as fits, returns the amount written
+    pub fn push_slice(&mut self, data: &[BuF]) -> usize {
+        let written = self.0.written.load(Ordering::Relaxed);
+        let amount = data.len().min(self.free());
+        for (i, sample) in data[..amount].iter().enumerate() {
+            let index = self.0.index(written.wrapping_add(i));
+            self.0.samples[index].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.0
+            .written
+            .store(written.wrapping_add(amount), Ordering::Release);
+        amount
+    }
+
+    /// Everything that was written is skipped by the consumer, for when the output has to change at once
+    pub fn discard(&self) {
+        let written = self.0.written.load(Ordering::Relaxed);
+        self.0.discard.store(written, Ordering::Release);
+    }
+}
+
+impl Consumer {
+    /// Everything that was written has been read or discarded
+    pub fn is_empty(&self) -> bool {
+        let written = self.0.written.load(Ordering::Acquire);
+        let read = self.0.read.load(Ordering::Relaxed);
+        let discard = self.0.discard.load(Ordering::Acquire);
+        written == read || written == discard
+    }
+
+    /// Position of the next sample that is read, counted from the start and wrapping around
+    pub fn position(&self) -> usize {
+        self.0.read.load(Ordering::Relaxed)
+    }
+
+    /// Read as many samples as there are into the data, returns the amount read
+    pub fn pop_slice(&mut self, data: &mut [BuF]) -> usize {
+        let mut read = self.0.read.load(Ordering::Relaxed);
+        let discard = self.0.discard.load(Ordering::Acquire);
+        // The counters wrap around, the discard position is never far behind
+        if (discard.wrapping_sub(read) as isize) > 0 {
+            read = discard;
+        }
+        let written = self.0.written.load(Ordering::Acquire);
+        let amount = data.len().min(written.wrapping_sub(read));
+        for (i, sample) in data[..amount].iter_mut().enumerate() {
+            let index = self.0.index(read.wrapping_add(i));
+            *sample = BuF::from_bits(self.0.samples[index].load(Ordering::Relaxed));
+        }
+        self.0
+            .read
+            .store(read.wrapping_add(amount), Ordering::Release);
+        amount
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pop_all(consumer: &mut Consumer) -> Vec<BuF> {
+        let mut data = vec![0.0; 16];
+        let amount = consumer.pop_slice(&mut data);
+        data.truncate(amount);
+        data
+    }
+
+    #[test]
+    fn wraps_around_the_end() {
+        let (mut producer, mut consumer) = ring_buffer(3);
+        assert_eq!(producer.push_slice(&[1.0, 2.0]), 2);
+        assert_eq!(pop_all(&mut consumer), [1.0, 2.0]);
+        assert_eq!(producer.push_slice(&[3.0, 4.0, 5.0, 6.0]), 3);
+        assert_eq!(producer.free(), 0);
+        assert_eq!(producer.buffered(), 3);
+        assert_eq!(pop_all(&mut consumer), [3.0, 4.0, 5.0]);
+        assert!(consumer.is_empty());
+        assert_eq!(producer.free(), 3);
+    }
+
+    #[test]
+    fn discard_skips_what_was_written() {
+        let (mut producer, mut consumer) = ring_buffer(4);
+        producer.push_slice(&[1.0, 2.0, 3.0]);
+        assert_eq!(consumer.pop_slice(&mut [0.0]), 1);
+        producer.discard();
+        assert!(consumer.is_empty());
+        producer.push_slice(&[4.0]);
+        assert!(!consumer.is_empty());
+        assert_eq!(pop_all(&mut consumer), [4.0]);
+        assert_eq!(consumer.position(), producer.position());
+    }
+
+    #[test]
+    fn positions_wrap_around() {
+        let (mut producer, mut consumer) = ring_buffer(3);
+        let start = usize::MAX - 1;
+        producer.0.written.store(start, Ordering::Relaxed);
+        producer.0.read.store(start, Ordering::Relaxed);
+        producer.0.discard.store(start, Ordering::Relaxed);
+
+        assert_eq!(producer.push_slice(&[1.0, 2.0, 3.0]), 3);
+        assert_eq!(producer.position(), 1);
+        assert_eq!(producer.buffered(), 3);
+        assert_eq!(pop_all(&mut consumer), [1.0, 2.0, 3.0]);
+        assert_eq!(consumer.position(), 1);
+
+        producer.push_slice(&[4.0, 5.0]);
+        producer.discard();
+        producer.push_slice(&[6.0]);
+        assert_eq!(pop_all(&mut consumer), [6.0]);
+    }
+}
@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+use crate::BuF;
+
+use super::crossfade::FadeCurve;
+
+/// Time in seconds the volume is faded out before the sleep timer stops
+const SLEEP_FADE_SEC: u64 = 10;
+
+/// Stops the playback after some time, the volume is faded out at the end
+#[derive(Clone, Copy, Debug)]
+pub struct SleepTimer {
+    /// Frames of the output left
+    left: u64,
+    /// Length of the fade in frames of the output
+    fade: u64,
+}
+
+impl SleepTimer {
+    pub fn new(duration: Duration, sample_rate: usize) -> SleepTimer {
+        let left = duration.as_millis() as u64 * sample_rate as u64 / 1000;
+        SleepTimer {
+            left,
+            fade: (SLEEP_FADE_SEC * sample_rate as u64).min(left),
+        }
+    }
+
+    /// Gain of the next frame, `None` when the time is up
+    pub fn next_gain(&mut self) -> Option<BuF> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        if self.left >= self.fade {
+            return Some(1.0);
+        }
+        let progress = 1.0 - self.left as BuF / self.fade as BuF;
+        Some(FadeCurve::Logarithmic.fade_out(progress))
+    }
+}
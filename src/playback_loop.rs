@@ -1,9 +1,49 @@
-use std::sync::mpsc::Receiver;
+use std::ops::RangeInclusive;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
 
-use cpal::Sample;
+use anyhow::{anyhow, Result};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample};
 use log::{error, info};
 
-use crate::{playback::PlaybackDaemon, queue::queue_items::QueueItem, BuF};
+use crate::{
+    audio_conversion::OutputConverter,
+    playback::{
+        crossfade::Crossfade,
+        playback_context::ArcPlaybackContext,
+        replay_gain::ReplayGainMode,
+        resampling::ResamplerConfig,
+        ring_buffer::{ring_buffer, Consumer, Producer},
+        PlaybackDaemon,
+    },
+    queue::queue_items::QueueItem,
+    BuF,
+};
+
+/// Samples converted at once in the callback, per channel
+const CONVERT_FRAMES: usize = 1024;
+
+/// How far ahead the decode thread works
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BufferConfig {
+    /// Audio that is decoded ahead of the output, more survives a slow disk
+    pub depth: Duration,
+    /// Audio that is decoded at once, the decode thread wakes up this often.
+    /// After a seek or skip the new audio is heard this much later
+    pub latency: Duration,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            depth: Duration::from_millis(500),
+            latency: Duration::from_millis(20),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum PlaybackAction {
@@ -19,67 +59,412 @@ pub enum PlaybackAction {
     FastForward(u64),
     /// Number of seconds to go to in a song
     GoTo(u64),
+    /// Add to the end of the queue
     Que(QueueItem),
+    /// Play after the current track, before the rest of the queue
+    PlayNext(QueueItem),
+    /// Insert into the queue at the index
+    Insert(usize, QueueItem),
+    /// Remove the item at the index from the queue
+    Remove(usize),
+    /// Move the item of the queue at the first index to the second index
+    Move(usize, usize),
+    /// Remove everything that would be played after the current track
+    ClearUpcoming,
+    /// Skip to the next track
+    Next,
+    /// Go to the previous track, or to the start of the track when it played for a while
+    Previous,
     /// Play the first item of the QueueItem and set the rest as the queue
     Play(QueueItem),
     /// Set the volume, 1.0 is default
     SetVolume(BuF),
     /// Change the volume
     ChangeVolume(BuF),
+    /// Set how tracks fade into each other, a zero duration turns it off
+    SetCrossfade(Crossfade),
+    /// Set which ReplayGain is used to normalise the loudness
+    SetReplayGainMode(ReplayGainMode),
+    /// Move the dither noise of integer outputs to high frequencies
+    SetNoiseShaping(bool),
+    /// Play tracks at their own sample rate when the device supports it
+    SetBitPerfect(bool),
+    /// Choose how tracks are resampled, used from the next track on
+    SetResampler(ResamplerConfig),
+    /// Fade out and stop after the duration, `None` turns it off
+    SetSleepTimer(Option<Duration>),
 }
 
-pub fn playback_loop(
-    data: &mut [BuF],
+impl PlaybackAction {
+    /// The action changes what is heard at once, so the audio that was decoded before is dropped
+    fn interrupts(&self) -> bool {
+        matches!(
+            self,
+            PlaybackAction::GoTo(_)
+                | PlaybackAction::FastForward(_)
+                | PlaybackAction::Rewind(_)
+                | PlaybackAction::Play(_)
+                | PlaybackAction::Next
+                | PlaybackAction::Previous
+        )
+    }
+}
+
+/// The part of the playback that runs in the cpal callback,
+/// decoding and resampling happen on a thread of their own
+pub struct PlaybackOutput {
+    consumer: Consumer,
+    playback_context: ArcPlaybackContext,
+    /// Float samples before they are converted to the format of the output
+    samples: Vec<BuF>,
+    converter: OutputConverter,
+    _decode_thread: JoinHandle<()>,
+}
+
+impl PlaybackOutput {
+    /// Start the decode thread, it gets the actions and stops when their sender is dropped
+    pub fn new(
+        playback_daemon: PlaybackDaemon,
+        rx: Receiver<PlaybackAction>,
+        config: BufferConfig,
+    ) -> PlaybackOutput {
+        let playback_context = playback_daemon.get_playback_context();
+        let channels = playback_daemon.channels_output();
+        let frames = |duration: Duration| {
+            ((duration.as_secs_f64() * playback_daemon.sample_rate_output() as f64) as usize).max(1)
+        };
+        let chunk = frames(config.latency) * channels;
+        let (producer, consumer) = ring_buffer((frames(config.depth) * channels).max(2 * chunk));
+        playback_context.set_stream_sample_rate(playback_daemon.sample_rate_output());
+        let decode_thread = std::thread::spawn(move || {
+            decode_loop(playback_daemon, rx, producer, chunk, config.latency)
+        });
+        PlaybackOutput {
+            consumer,
+            playback_context,
+            samples: vec![Sample::EQUILIBRIUM; CONVERT_FRAMES * channels],
+            converter: OutputConverter::new(channels),
+            _decode_thread: decode_thread,
+        }
+    }
+
+    pub fn get_playback_context(&self) -> ArcPlaybackContext {
+        self.playback_context.clone()
+    }
+
+    /// Everything that was decoded has been played
+    fn is_drained(&self) -> bool {
+        self.consumer.is_empty()
+    }
+}
+
+/// An output stream that is opened again when the sample rate of the playback changes,
+/// which happens in bit-perfect mode.
+///
+/// Has to stay on the thread that created it, call [`OutputStream::update`] regularly from there
+pub struct OutputStream {
+    device: cpal::Device,
+    config: cpal::SupportedStreamConfig,
+    output: Arc<Mutex<PlaybackOutput>>,
+    playback_context: ArcPlaybackContext,
+    stream: Option<cpal::Stream>,
+}
+
+impl OutputStream {
+    /// Open and start the stream, other sample rates are opened with the channels
+    /// and sample format of the config
+    pub fn new(
+        device: cpal::Device,
+        config: cpal::SupportedStreamConfig,
+        output: PlaybackOutput,
+    ) -> Result<OutputStream> {
+        let playback_context = output.get_playback_context();
+        playback_context.set_supported_sample_rates(supported_sample_rates(&device, &config)?);
+        let output = Arc::new(Mutex::new(output));
+        let stream = build_shared_stream(&device, &config, output.clone())?;
+        stream.play()?;
+        playback_context.set_stream_sample_rate(config.sample_rate().0 as usize);
+        Ok(OutputStream {
+            device,
+            config,
+            output,
+            playback_context,
+            stream: Some(stream),
+        })
+    }
+
+    /// Open the stream again when the playback changed its sample rate,
+    /// once the audio at the old sample rate is played
+    pub fn update(&mut self) -> Result<()> {
+        let sample_rate = self.playback_context.output_sample_rate();
+        if sample_rate == self.playback_context.stream_sample_rate() {
+            return Ok(());
+        }
+        let drained = match self.output.lock() {
+            Ok(output) => output.is_drained(),
+            Err(err) => err.into_inner().is_drained(),
+        };
+        if !drained {
+            return Ok(());
+        }
+        let config = config_with_sample_rate(&self.device, &self.config, sample_rate)?;
+        // Some devices can only have one stream open
+        self.stream = None;
+        let stream = build_shared_stream(&self.device, &config, self.output.clone())?;
+        stream.play()?;
+        self.stream = Some(stream);
+        self.playback_context.set_stream_sample_rate(sample_rate);
+        info!(target: "rmusic::playback_loop", "Opened the output at {sample_rate} Hz");
+        Ok(())
+    }
+
+    pub fn get_playback_context(&self) -> ArcPlaybackContext {
+        self.playback_context.clone()
+    }
+}
+
+/// The sample rates the device can play with the channels and sample format of the config
+fn supported_sample_rates(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+) -> Result<Vec<RangeInclusive<usize>>> {
+    Ok(device
+        .supported_output_configs()?
+        .filter(|x| {
+            x.channels() == config.channels() && x.sample_format() == config.sample_format()
+        })
+        .map(|x| x.min_sample_rate().0 as usize..=x.max_sample_rate().0 as usize)
+        .collect())
+}
+
+/// The config at another sample rate, with the same channels and sample format
+fn config_with_sample_rate(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    sample_rate: usize,
+) -> Result<cpal::SupportedStreamConfig> {
+    let rate = cpal::SampleRate(sample_rate as u32);
+    device
+        .supported_output_configs()?
+        .find(|x| {
+            x.channels() == config.channels()
+                && x.sample_format() == config.sample_format()
+                && (x.min_sample_rate()..=x.max_sample_rate()).contains(&rate)
+        })
+        .map(|x| x.with_sample_rate(rate))
+        .ok_or(anyhow!("The output can't be opened at {sample_rate} Hz"))
+}
+
+/// The cpal callback, it only copies the decoded samples, applies the volume
+/// and converts them to the sample format of the output
+pub fn playback_loop<T: SizedSample + FromSample<BuF>>(
+    data: &mut [T],
     _callback: &cpal::OutputCallbackInfo,
-    playback_daemon: &mut PlaybackDaemon,
-    rx: &Receiver<PlaybackAction>,
+    output: &mut PlaybackOutput,
+) {
+    let playing = output.playback_context.playing();
+    let volume_level = output.playback_context.volume_level();
+    output
+        .converter
+        .set_noise_shaping(output.playback_context.noise_shaping());
+    // In parts, so nothing is allocated
+    for part in data.chunks_mut(output.samples.len()) {
+        let samples = &mut output.samples[..part.len()];
+        let read = match playing {
+            true => output.consumer.pop_slice(samples),
+            false => 0,
+        };
+        // Samples exactly as they were decoded are converted without dither, so they stay the same.
+        // Checked after reading, a changed chunk is announced before it is written
+        let start = output.consumer.position().wrapping_sub(read);
+        let unchanged = volume_level == 1.0 && output.playback_context.unchanged_from(start);
+        output.converter.set_dither(!unchanged);
+        for i in samples[..read].iter_mut() {
+            *i *= volume_level;
+        }
+        for i in samples[read..].iter_mut() {
+            *i = Sample::EQUILIBRIUM;
+        }
+        output.converter.convert(samples, part);
+    }
+}
+
+/// Open an output stream in the sample format of the config,
+/// use the default config of the device to play in its native format
+pub fn build_output_stream(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    output: PlaybackOutput,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    build_shared_stream(device, config, Arc::new(Mutex::new(output)))
+}
+
+/// The output is shared, so it can be moved into a stream at another sample rate
+fn build_shared_stream(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    output: Arc<Mutex<PlaybackOutput>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    match config.sample_format() {
+        SampleFormat::I8 => build_stream::<i8>(device, config, output),
+        SampleFormat::I16 => build_stream::<i16>(device, config, output),
+        SampleFormat::I32 => build_stream::<i32>(device, config, output),
+        SampleFormat::I64 => build_stream::<i64>(device, config, output),
+        SampleFormat::U8 => build_stream::<u8>(device, config, output),
+        SampleFormat::U16 => build_stream::<u16>(device, config, output),
+        SampleFormat::U32 => build_stream::<u32>(device, config, output),
+        SampleFormat::U64 => build_stream::<u64>(device, config, output),
+        SampleFormat::F32 => build_stream::<f32>(device, config, output),
+        SampleFormat::F64 => build_stream::<f64>(device, config, output),
+        _ => Err(cpal::BuildStreamError::StreamConfigNotSupported),
+    }
+}
+
+fn build_stream<T: SizedSample + FromSample<BuF>>(
+    device: &cpal::Device,
+    config: &cpal::SupportedStreamConfig,
+    output: Arc<Mutex<PlaybackOutput>>,
+) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    device.build_output_stream(
+        &config.config(),
+        // The lock is only taken elsewhere while the stream is changed, then silence is fine
+        move |data: &mut [T], info| match output.try_lock() {
+            Ok(mut output) => playback_loop(data, info, &mut output),
+            Err(_) => data.fill(T::EQUILIBRIUM),
+        },
+        |err| error!("Error in output stream: {err}"),
+        None,
+    )
+}
+
+/// Keep the ring buffer filled with chunks of decoded audio, and handle the actions
+fn decode_loop(
+    mut playback_daemon: PlaybackDaemon,
+    rx: Receiver<PlaybackAction>,
+    mut producer: Producer,
+    chunk: usize,
+    latency: Duration,
 ) {
-    while let Ok(status) = rx.try_recv() {
-        info!(target: "rmusic::playback_loop", "Received: {:?}", status);
-        match status {
-            PlaybackAction::Playing => playback_daemon.playing = true,
-            PlaybackAction::Paused => playback_daemon.playing = false,
-            PlaybackAction::PlayPause => playback_daemon.playing = !playback_daemon.playing,
-            PlaybackAction::GoTo(target) => playback_daemon
-                .goto(target * playback_daemon.sample_rate_input() as u64)
-                .unwrap_or_else(|err| error!("Error in Stream: {:?}", err)),
-            PlaybackAction::FastForward(amount) => {
-                let current = playback_daemon.current_length() - playback_daemon.left();
-                let target = current + amount * playback_daemon.sample_rate_input() as u64;
-                let goto = if target <= playback_daemon.current_length() {
-                    target
-                } else {
-                    //TODO: replace with next track
-                    playback_daemon.current_length() - 1
-                };
-                playback_daemon
-                    .goto(goto)
-                    .unwrap_or_else(|err| error!("Error in Stream: {:?}", err))
+    let playback_context = playback_daemon.get_playback_context();
+    playback_context.set_playing(playback_daemon.playing);
+    let mut data = vec![Sample::EQUILIBRIUM; chunk];
+    loop {
+        // Sleep until there is room for a chunk, or an action comes in
+        match rx.recv_timeout(latency / 2) {
+            Ok(status) => handle_action(status, &mut playback_daemon, &producer),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        for status in rx.try_iter() {
+            handle_action(status, &mut playback_daemon, &producer);
+        }
+        // After a change of the sample rate, wait until the output is opened at the new rate
+        while playback_daemon.playing
+            && producer.free() >= data.len()
+            && playback_context.output_sample_rate() == playback_context.stream_sample_rate()
+        {
+            // Audio at the old sample rate is never in the same chunk as audio at the new rate
+            let amount = playback_daemon
+                .samples_until_rate_change()
+                .map_or(data.len(), |x| x.min(data.len()));
+            playback_daemon
+                .fill(&mut data[..amount])
+                .unwrap_or_else(|err| {
+                    error!("Error in Stream: {:?}", err);
+                });
+            if !playback_daemon.filled_unchanged() {
+                playback_context.set_changed_until(producer.position().wrapping_add(amount));
             }
-            PlaybackAction::Rewind(amount) => {
-                let current = playback_daemon.current_length() - playback_daemon.left();
-                let amount = amount * playback_daemon.sample_rate_input() as u64;
-                let goto = current.saturating_sub(amount);
-                playback_daemon
-                    .goto(goto)
-                    .unwrap_or_else(|err| error!("Error in Stream: {:?}", err))
+            producer.push_slice(&data[..amount]);
+            for status in rx.try_iter() {
+                handle_action(status, &mut playback_daemon, &producer);
             }
-            //TODO: Change this to include the flatten option
-            PlaybackAction::Play(item) => playback_daemon
-                .play(item, true)
-                .unwrap_or_else(|err| error!("Error in Stream: {:?}", err)),
-            PlaybackAction::SetVolume(volume) => playback_daemon.set_volume(volume),
-            PlaybackAction::ChangeVolume(change) => playback_daemon.change_volume(change),
-            _ => unimplemented!(),
+        }
+        playback_daemon.update_buffered(producer.buffered());
+        // When the queue ends the output stops after what was decoded is played
+        if playback_daemon.playing {
+            playback_context.set_playing(true);
+        } else if producer.buffered() == 0 {
+            playback_context.set_playing(false);
         }
     }
-    if playback_daemon.playing {
-        playback_daemon.fill(data).unwrap_or_else(|err| {
-            error!("Error in Stream: {:?}", err);
-        });
-    } else {
-        for i in data.iter_mut() {
-            *i = Sample::EQUILIBRIUM;
+    info!(target: "rmusic::playback_loop", "Stopped decoding");
+}
+
+/// Apply an action on the decode thread
+fn handle_action(
+    status: PlaybackAction,
+    playback_daemon: &mut PlaybackDaemon,
+    producer: &Producer,
+) {
+    info!(target: "rmusic::playback_loop", "Received: {:?}", status);
+    if status.interrupts() {
+        producer.discard();
+    }
+    let was_playing = playback_daemon.playing;
+    match status {
+        PlaybackAction::Playing => playback_daemon.playing = true,
+        PlaybackAction::Paused => playback_daemon.playing = false,
+        PlaybackAction::PlayPause => playback_daemon.playing = !playback_daemon.playing,
+        PlaybackAction::GoTo(target) => playback_daemon
+            .goto(target * playback_daemon.sample_rate_input() as u64)
+            .unwrap_or_else(|err| error!("Error in Stream: {:?}", err)),
+        PlaybackAction::FastForward(amount) => {
+            let current = playback_daemon.current_length() - playback_daemon.left();
+            let target = current + amount * playback_daemon.sample_rate_input() as u64;
+            let goto = if target <= playback_daemon.current_length() {
+                target
+            } else {
+                //TODO: replace with next track
+                playback_daemon.current_length().saturating_sub(1)
+            };
+            playback_daemon
+                .goto(goto)
+                .unwrap_or_else(|err| error!("Error in Stream: {:?}", err))
         }
+        PlaybackAction::Rewind(amount) => {
+            let current = playback_daemon.current_length() - playback_daemon.left();
+            let amount = amount * playback_daemon.sample_rate_input() as u64;
+            let goto = current.saturating_sub(amount);
+            playback_daemon
+                .goto(goto)
+                .unwrap_or_else(|err| error!("Error in Stream: {:?}", err))
+        }
+        //TODO: Change this to include the flatten option
+        PlaybackAction::Play(item) => playback_daemon
+            .play(item, true)
+            .unwrap_or_else(|err| error!("Error in Stream: {:?}", err)),
+        PlaybackAction::SetVolume(volume) => playback_daemon.set_volume(volume),
+        PlaybackAction::ChangeVolume(change) => playback_daemon.change_volume(change),
+        PlaybackAction::SetCrossfade(crossfade) => playback_daemon.set_crossfade(crossfade),
+        PlaybackAction::SetReplayGainMode(mode) => playback_daemon.set_replay_gain_mode(mode),
+        PlaybackAction::SetNoiseShaping(noise_shaping) => {
+            playback_daemon.set_noise_shaping(noise_shaping)
+        }
+        PlaybackAction::SetBitPerfect(bit_perfect) => playback_daemon.set_bit_perfect(bit_perfect),
+        PlaybackAction::SetResampler(config) => playback_daemon.set_resampler(config),
+        PlaybackAction::SetSleepTimer(duration) => playback_daemon.set_sleep_timer(duration),
+        PlaybackAction::Que(item) => playback_daemon.que(item),
+        PlaybackAction::PlayNext(item) => playback_daemon.edit_queue(|queue| queue.play_next(item)),
+        PlaybackAction::Insert(index, item) => {
+            playback_daemon.edit_queue(|queue| queue.insert_queue_item(index, item))
+        }
+        PlaybackAction::Remove(index) => playback_daemon.edit_queue(|queue| {
+            queue.remove_queue_item(index);
+        }),
+        PlaybackAction::Move(from, to) => playback_daemon.edit_queue(|queue| {
+            queue.move_queue_item(from, to);
+        }),
+        PlaybackAction::ClearUpcoming => playback_daemon.clear_upcoming(),
+        PlaybackAction::Next => playback_daemon
+            .next()
+            .unwrap_or_else(|err| error!("Error in Stream: {:?}", err)),
+        PlaybackAction::Previous => playback_daemon
+            .previous()
+            .unwrap_or_else(|err| error!("Error in Stream: {:?}", err)),
+    }
+    // A pause silences the output at once, the decode loop starts it again
+    if was_playing && !playback_daemon.playing {
+        playback_daemon.get_playback_context().set_playing(false);
     }
 }
@@ -4,30 +4,35 @@ use std::{
     path::PathBuf,
 };
 
-const DEPTH_LIMIT: usize = 10;
+pub(crate) const DEPTH_LIMIT: usize = 10;
 
+mod edit;
+mod history;
 pub mod queue_items;
 mod select_track;
 
-use log::warn;
 // use entity::{artist, release, track, track_location};
 use queue_items::{QueueItem, QueueTrack};
-use rand::thread_rng;
 // pub use select_track::get_track_from_item;
 // use select_track::get_track_from_list;
 
 /// Struct that will play things next
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Queue {
     pub(crate) queue_items: VecDeque<QueueItem>,
-    played_items: VecDeque<QueueItem>,
-    max_history: usize,
+    pub(crate) played_items: VecDeque<QueueItem>,
+    pub(crate) max_history: usize,
     pub queue_options: QueueOptions,
+    /// Play the current track again instead of the next one
     pub repeat_current: bool,
     pub(crate) current_track: Option<PathBuf>,
-    next_up: VecDeque<QueueItem>,
+    pub(crate) next_up: VecDeque<QueueItem>,
+    /// Tracks that were gone back from with `previous_track`, played before anything else
+    pub(crate) forward: VecDeque<QueueTrack>,
+    /// The queue before the last `next_track`, until that track plays
+    pub(crate) before_next: Option<Box<history::BeforeNext>>,
     // nothing to do with next_up
-    next_id: usize,
+    pub(crate) next_id: usize,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -35,23 +40,79 @@ pub struct QueueOptions {
     pub shuffle_type: ShuffleType,
     pub stop_condition: StopCondition,
     pub selected: Option<usize>,
+    /// What was played since the list started, to check the stop condition
+    pub progress: StopProgress,
 }
 
 impl Default for QueueOptions {
     fn default() -> Self {
         Self {
             shuffle_type: ShuffleType::None,
-            stop_condition: StopCondition::None,
+            stop_condition: StopCondition::EndOfList,
             selected: None,
+            progress: StopProgress::default(),
         }
     }
 }
 
 impl QueueOptions {
-    /// This is the same as default
-    fn new() -> Self {
-        Self::default()
+    /// The stop condition says no more tracks should be played from this list
+    pub fn stop_reached(&self) -> bool {
+        match self.stop_condition {
+            StopCondition::AmountTracks(amount) => self.progress.tracks >= amount,
+            StopCondition::Time(time) => self.progress.time >= time,
+            StopCondition::EndOfList | StopCondition::None => false,
+        }
     }
+
+    /// The list starts over at the end instead of stopping, only when a stop condition
+    /// other than the default `EndOfList` was set
+    pub fn loops(&self) -> bool {
+        self.stop_condition != StopCondition::EndOfList
+    }
+
+    /// Start the list from the beginning, the shuffle weights are kept
+    pub fn restart(&mut self) {
+        self.selected = None;
+        self.progress = StopProgress::default();
+    }
+
+    /// Make the options valid for a list of this length,
+    /// used when the list lost items it had before
+    pub(crate) fn fit_to(&mut self, length: usize) {
+        let fits = match &self.shuffle_type {
+            ShuffleType::None | ShuffleType::TrueRandom => true,
+            ShuffleType::WeightedRandom(weights) | ShuffleType::WeightedDefault(weights) => {
+                weights.len() == length
+            }
+            ShuffleType::WeightedRandomWithDefault(weights, default_weights) => {
+                weights.len() == length && default_weights.len() == length
+            }
+        };
+        if !fits {
+            self.shuffle_type = ShuffleType::new_weighted_random(length);
+        }
+        if self.selected.is_some_and(|x| x >= length) {
+            self.restart();
+        }
+    }
+
+    /// Count a track that is played from this list
+    pub(crate) fn count_played(&mut self, track: &QueueTrack) {
+        self.progress.tracks += 1;
+        self.progress.time += track.track().duration.max(0) as u64 * 1000;
+    }
+}
+
+/// What was played from a list since it started
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct StopProgress {
+    /// Amount of tracks
+    pub tracks: usize,
+    /// Length of the tracks in ms
+    pub time: u64,
+    /// Amount of items picked, a shuffled list ends after picking as many items as it has
+    pub picked: usize,
 }
 
 #[derive(Clone, PartialEq, Debug, Default)]
@@ -68,6 +129,10 @@ pub enum ShuffleType {
     WeightedRandomWithDefault(Vec<usize>, Vec<usize>),
 }
 
+/// When a list stops giving tracks, checked at every level of the queue
+///
+/// A finished list inside another list gives the turn back to its parent,
+/// when the queue itself is finished the playback stops
 #[derive(Clone, PartialEq, Debug, Default)]
 pub enum StopCondition {
     #[default]
@@ -75,9 +140,13 @@ pub enum StopCondition {
     EndOfList,
     /// Loop at the end of the list
     None,
-    /// Stop after playing this amount of tracks
+    /// Stop after playing this amount of tracks, loops until then
     AmountTracks(usize),
-    /// Stop playing after this the time in mil elapsed
+    /// Stop playing after this the time in mil elapsed, loops until then
+    ///
+    /// No new track is started after the time has elapsed,
+    /// use the sleep timer of the [`PlaybackDaemon`](crate::playback::PlaybackDaemon)
+    /// to stop in the middle of a track
     Time(u64),
 }
 
@@ -154,11 +223,13 @@ impl Queue {
         &mut self,
         queue_item: QueueItem,
         flatten: bool,
-    ) -> Option<PathBuf> {
+    ) -> Option<QueueTrack> {
         self.clear_queue();
+        self.forward.clear();
         self.append_queue_item(queue_item, flatten);
-        self.current_track = self.next_track();
-        self.current_track.clone()
+        let track = self.next_track();
+        self.current_track = track.as_ref().map(|x| x.location().to_path_buf());
+        track
     }
 
     // Remove everything that is in the queue
@@ -174,6 +245,7 @@ impl Queue {
 
     pub fn clear_history(&mut self) {
         self.played_items.clear();
+        self.forward.clear();
     }
 
     pub fn reset_queue(&mut self) {
@@ -214,7 +286,7 @@ impl Queue {
     where
         I: Into<QueueItem>,
     {
-        let item: QueueItem = item.into();
+        let item = self.with_id(item.into());
         if flatten {
             let tracks = item.flatten();
             for track in tracks {
@@ -256,6 +328,8 @@ impl Default for Queue {
             played_items: VecDeque::new(),
             queue_options: QueueOptions::default(),
             next_up: Default::default(),
+            forward: VecDeque::new(),
+            before_next: None,
         }
     }
 }
@@ -0,0 +1,119 @@
+use super::{Queue, QueueItem, ShuffleType};
+
+impl Queue {
+    /// Play the item after the current track, before the rest of the queue.
+    /// Items added this way are played in the order they were added
+    pub fn play_next<I>(&mut self, item: I)
+    where
+        I: Into<QueueItem>,
+    {
+        let item = self.with_id(item.into());
+        self.next_up.push_back(item);
+    }
+
+    /// Insert the item in the queue at the index, or at the end if the index is too big
+    pub fn insert_queue_item<I>(&mut self, index: usize, item: I)
+    where
+        I: Into<QueueItem>,
+    {
+        let item = self.with_id(item.into());
+        let index = index.min(self.queue_items.len());
+        self.queue_items.insert(index, item);
+        for weights in weights(&mut self.queue_options.shuffle_type) {
+            weights.insert(index.min(weights.len()), 1);
+        }
+        let options = &mut self.queue_options;
+        options.selected = options.selected.map(|x| if x >= index { x + 1 } else { x });
+    }
+
+    /// Remove the item at the index from the queue
+    ///
+    /// When the selected item is removed, the item after it is played next
+    pub fn remove_queue_item(&mut self, index: usize) -> Option<QueueItem> {
+        let item = self.queue_items.remove(index)?;
+        for weights in weights(&mut self.queue_options.shuffle_type) {
+            if index < weights.len() {
+                weights.remove(index);
+            }
+        }
+        let options = &mut self.queue_options;
+        options.selected = match options.selected {
+            Some(x) if x > index => Some(x - 1),
+            Some(x) if x == index => {
+                // Select the item before it, without continuing it
+                let before = index.checked_sub(1);
+                if let Some(item) = before.and_then(|x| self.queue_items.get_mut(x)) {
+                    item.restart();
+                }
+                before
+            }
+            selected => selected,
+        };
+        Some(item)
+    }
+
+    /// Move the item at `from` so it ends up at `to`, returns false if `from` does not exist
+    pub fn move_queue_item(&mut self, from: usize, to: usize) -> bool {
+        let Some(item) = self.queue_items.remove(from) else {
+            return false;
+        };
+        let to = to.min(self.queue_items.len());
+        self.queue_items.insert(to, item);
+        for weights in weights(&mut self.queue_options.shuffle_type) {
+            if from < weights.len() {
+                let weight = weights.remove(from);
+                weights.insert(to.min(weights.len()), weight);
+            }
+        }
+        let options = &mut self.queue_options;
+        options.selected = options.selected.map(|x| {
+            if x == from {
+                return to;
+            }
+            let x = if x > from { x - 1 } else { x };
+            if x >= to {
+                x + 1
+            } else {
+                x
+            }
+        });
+        true
+    }
+
+    /// Remove everything that would be played after the current track.
+    /// A shuffled queue only keeps the selected item
+    pub fn clear_upcoming(&mut self) {
+        self.next_up.clear();
+        self.forward.clear();
+        let keep = match self.queue_options.selected {
+            Some(index) if self.queue_options.shuffle_type == ShuffleType::None => index + 1,
+            Some(index) => {
+                self.move_queue_item(index, 0);
+                1
+            }
+            None => 0,
+        };
+        while self.queue_items.len() > keep {
+            self.remove_queue_item(self.queue_items.len() - 1);
+        }
+    }
+
+    /// Give the item and its children unique ids, so they can be found in the history
+    pub(super) fn with_id(&mut self, mut item: QueueItem) -> QueueItem {
+        self.next_id = item.set_id_rec(self.next_id);
+        item
+    }
+}
+
+/// The weights of the shuffle, they have the same length as the list
+fn weights(shuffle: &mut ShuffleType) -> Vec<&mut Vec<usize>> {
+    match shuffle {
+        ShuffleType::None | ShuffleType::TrueRandom => vec![],
+        ShuffleType::WeightedRandom(weights) | ShuffleType::WeightedDefault(weights) => {
+            vec![weights]
+        }
+        ShuffleType::WeightedRandomWithDefault(weights, default_weights) => {
+            vec![weights, default_weights]
+        }
+    }
+}
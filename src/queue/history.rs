@@ -0,0 +1,145 @@
+use std::collections::VecDeque;
+
+use super::{queue_items::QueueTrack, Queue, QueueItem, QueueOptions};
+
+/// What `next_track` changes, kept so the track can be given back
+#[derive(Debug, Clone)]
+pub(crate) struct BeforeNext {
+    queue_items: VecDeque<QueueItem>,
+    queue_options: QueueOptions,
+    next_up: VecDeque<QueueItem>,
+    played_items: VecDeque<QueueItem>,
+    forward: VecDeque<QueueTrack>,
+}
+
+impl Queue {
+    /// Go back to the track before the current track in the history,
+    /// the current track is played again when going forward
+    ///
+    /// Returns `None` when there is no track before the current one
+    pub fn previous_track(&mut self) -> Option<QueueTrack> {
+        let current = take_history(&mut self.played_items, false)?;
+        match last_history(&self.played_items).cloned() {
+            Some(previous) => {
+                self.forward.push_front(current);
+                self.current_track = Some(previous.location().to_path_buf());
+                Some(previous)
+            }
+            None => {
+                self.played_items.push_back(current.into());
+                None
+            }
+        }
+    }
+
+    /// Give back what the last `next_track` took before it was played,
+    /// the queue is as it was before with the items that were appended since
+    ///
+    /// Returns `false` when there is nothing to give back
+    pub(crate) fn return_next_track(&mut self) -> bool {
+        let Some(before) = self.before_next.take() else {
+            return false;
+        };
+        let start = before.queue_items.len().min(self.queue_items.len());
+        let appended: Vec<QueueItem> = self.queue_items.drain(start..).collect();
+        self.queue_items = before.queue_items;
+        self.queue_items.extend(appended);
+        self.queue_options = before.queue_options;
+        self.next_up = before.next_up;
+        self.played_items = before.played_items;
+        self.forward = before.forward;
+        true
+    }
+
+    /// The track of the last `next_track` is played (or skipped), it can't be given back anymore
+    pub(crate) fn keep_next_track(&mut self) {
+        self.before_next = None;
+    }
+
+    pub(super) fn before_next(&self) -> BeforeNext {
+        BeforeNext {
+            queue_items: self.queue_items.clone(),
+            queue_options: self.queue_options.clone(),
+            next_up: self.next_up.clone(),
+            played_items: self.played_items.clone(),
+            forward: self.forward.clone(),
+        }
+    }
+
+    /// Take the first of the tracks that were gone back from
+    pub(super) fn next_forward(&mut self) -> Option<QueueTrack> {
+        let track = self.forward.pop_front()?;
+        self.add_history(track.clone().into());
+        Some(track)
+    }
+
+    /// Tracks that were gone back from, they are played before anything else
+    pub fn forward_items(&self) -> &VecDeque<QueueTrack> {
+        &self.forward
+    }
+
+    /// The last track in the history, this is the current track while it is playing
+    pub fn last_played(&self) -> Option<&QueueTrack> {
+        last_history(&self.played_items)
+    }
+
+    pub fn max_history(&self) -> usize {
+        self.max_history
+    }
+
+    /// Set the amount of tracks kept in the history, the oldest tracks are removed
+    pub fn set_max_history(&mut self, max_history: usize) {
+        self.max_history = max_history;
+        self.trim_history();
+    }
+
+    pub(super) fn add_history(&mut self, item: QueueItem) {
+        self.played_items.push_back(item);
+        self.trim_history();
+    }
+
+    /// Remove the oldest tracks until the history has at most `max_history` tracks
+    pub(super) fn trim_history(&mut self) {
+        let mut count: usize = self.played_items.iter().map(|x| x.count() as usize).sum();
+        while count > self.max_history && take_history(&mut self.played_items, true).is_some() {
+            count -= 1;
+        }
+    }
+}
+
+/// The last track that was added to the history
+fn last_history(history: &VecDeque<QueueItem>) -> Option<&QueueTrack> {
+    history.iter().rev().find_map(|item| match item {
+        QueueItem::Track(track) => Some(track),
+        QueueItem::Playlist(playlist) => last_history(&playlist.playlist_items),
+        QueueItem::Album(album) => album.tracks.back(),
+    })
+}
+
+/// Remove the last (or oldest) track from the history, also from inside playlists and albums.
+/// Lists that become empty are removed as well
+fn take_history(history: &mut VecDeque<QueueItem>, oldest: bool) -> Option<QueueTrack> {
+    loop {
+        let mut item = if oldest {
+            history.pop_front()?
+        } else {
+            history.pop_back()?
+        };
+        let track = match &mut item {
+            QueueItem::Track(track) => return Some(track.clone()),
+            QueueItem::Playlist(playlist) => take_history(&mut playlist.playlist_items, oldest),
+            QueueItem::Album(album) if oldest => album.tracks.pop_front(),
+            QueueItem::Album(album) => album.tracks.pop_back(),
+        };
+        if !item.is_empty() {
+            if oldest {
+                history.push_front(item);
+            } else {
+                history.push_back(item);
+            }
+        }
+        if track.is_some() {
+            return track;
+        }
+    }
+}
@@ -8,6 +8,7 @@ use log::{error, warn};
 use crate::{
     database::{context::ContextError, select::PlaylistItemType, Library},
     models::{Playlist, Release, Track, TrackLocation},
+    playback::replay_gain::{ReplayGain, TrackGain},
     struct_in_enum,
 };
 
@@ -17,6 +18,11 @@ use super::{switch_shuffle, QueueOptions, ShuffleError, ShuffleType, DEPTH_LIMIT
 pub struct QueueTrack {
     track: Track,
     location: PathBuf,
+    /// Index of the audio track in the file
+    audio_track: usize,
+    /// The track is part of a release that should be played without gaps
+    gapless: bool,
+    album_gain: Option<ReplayGain>,
 }
 
 pub(crate) trait FromDB<T>: Sized + Into<QueueItem> {
@@ -25,7 +31,13 @@ pub(crate) trait FromDB<T>: Sized + Into<QueueItem> {
 
 impl QueueTrack {
     pub fn new(track: Track, location: PathBuf) -> QueueTrack {
-        QueueTrack { track, location }
+        QueueTrack {
+            track,
+            location,
+            audio_track: 0,
+            gapless: false,
+            album_gain: None,
+        }
     }
 
     pub fn track(&self) -> &Track {
@@ -34,15 +46,41 @@ impl QueueTrack {
     pub fn location(&self) -> &Path {
         &self.location
     }
+    pub fn audio_track(&self) -> usize {
+        self.audio_track
+    }
+    pub fn gapless(&self) -> bool {
+        self.gapless
+    }
+    /// The measured loudness is used before the ReplayGain tags
+    pub fn gain(&self) -> TrackGain {
+        TrackGain {
+            track: ReplayGain::from_loudness(self.track.loudness, self.track.true_peak).or(
+                ReplayGain::from_columns(self.track.replay_gain, self.track.replay_peak),
+            ),
+            album: self.album_gain,
+        }
+    }
 }
 
 impl FromDB<Track> for QueueTrack {
     fn from_db(track: Track, library: &mut Library) -> Result<QueueTrack, ContextError> {
         let locations = library.models_related::<Track, TrackLocation>(&track)?;
+        let release = library.model_related::<Release, Track>(&track)?;
         for location in locations {
+            let audio_track = location.audio_track as usize;
             let location = PathBuf::from(location.path);
             if location.is_file() {
-                return Ok(QueueTrack::new(track, location));
+                let mut queue_track = QueueTrack::new(track, location);
+                queue_track.audio_track = audio_track;
+                if let Some(release) = release {
+                    queue_track.gapless = release.gapless;
+                    queue_track.album_gain =
+                        ReplayGain::from_loudness(release.loudness, release.true_peak).or(
+                            ReplayGain::from_columns(release.replay_gain, release.replay_peak),
+                        );
+                }
+                return Ok(queue_track);
             }
         }
         Err(ContextError::NoResult)
@@ -315,6 +353,25 @@ impl QueueItem {
         }
     }
 
+    /// Has nothing left to play, because it is empty or its stop condition is reached
+    pub fn is_finished(&self) -> bool {
+        let stop_reached = match self {
+            QueueItem::Track(_) => false,
+            QueueItem::Playlist(playlist) => playlist.queue_option.stop_reached(),
+            QueueItem::Album(album) => album.queue_option.stop_reached(),
+        };
+        stop_reached || self.is_empty()
+    }
+
+    /// Start the item from the beginning the next time it is played
+    pub fn restart(&mut self) {
+        match self {
+            QueueItem::Track(_) => (),
+            QueueItem::Playlist(playlist) => playlist.queue_option.restart(),
+            QueueItem::Album(album) => album.queue_option.restart(),
+        }
+    }
+
     pub fn get_selected(&self) -> Option<QueueTrack> {
         match self {
             QueueItem::Track(track) => Some(track.clone()),
@@ -427,6 +484,11 @@ mod tests {
                 duration: 2000,
                 artist_id: 0,
                 release_id: 0,
+                replay_gain: None,
+                replay_peak: None,
+                loudness: None,
+                loudness_range: None,
+                true_peak: None,
             },
             PathBuf::from(format!("/path/to/track_{}.mp3", id)),
         )
@@ -446,6 +508,12 @@ mod tests {
                 date: NaiveDate::default(),
                 publisher_id: None,
                 artist_id: 0,
+                gapless: false,
+                replay_gain: None,
+                replay_peak: None,
+                loudness: None,
+                loudness_range: None,
+                true_peak: None,
             },
             tracks,
             Default::default(),
@@ -473,7 +541,7 @@ mod tests {
 
         // For a QueueItem::Album, its ID should be set, and the next ID should be initial_id + 1
         if let QueueItem::Album(album) = &album_item {
-            assert_eq!(*album.id(), initial_id);
+            assert_eq!(album.id(), initial_id);
         } else {
             panic!("Expected QueueItem::Album");
         }
@@ -491,7 +559,7 @@ mod tests {
 
         // For a QueueItem::Playlist, its ID should be set, and the next ID should be initial_id + 1
         if let QueueItem::Playlist(playlist) = &playlist_item {
-            assert_eq!(*playlist.id(), initial_id);
+            assert_eq!(playlist.id(), initial_id);
         } else {
             panic!("Expected QueueItem::Playlist");
         }
@@ -505,7 +573,7 @@ mod tests {
         let next_id = album_item.set_id(initial_id);
 
         if let QueueItem::Album(album) = &album_item {
-            assert_eq!(*album.id(), initial_id);
+            assert_eq!(album.id(), initial_id);
         } else {
             panic!("Expected QueueItem::Album");
         }
@@ -528,7 +596,7 @@ mod tests {
 
         // The playlist itself should get the initial ID
         if let QueueItem::Playlist(playlist) = &playlist_item {
-            assert_eq!(*playlist.id(), initial_id);
+            assert_eq!(playlist.id(), initial_id);
         } else {
             panic!("Expected QueueItem::Playlist");
         }
@@ -575,22 +643,22 @@ mod tests {
 
         // Verify IDs
         if let QueueItem::Playlist(root_pl) = &root_playlist_item {
-            assert_eq!(*root_pl.id(), 100);
+            assert_eq!(root_pl.id(), 100);
 
             // Item 1: Album (should be 101)
             if let QueueItem::Album(album) = &root_pl.playlist_items[1] {
-                assert_eq!(*album.id(), 101);
+                assert_eq!(album.id(), 101);
             } else {
                 panic!("Expected Album at index 1");
             }
 
             // Item 2: Inner Playlist (should be 102)
             if let QueueItem::Playlist(inner_pl) = &root_pl.playlist_items[2] {
-                assert_eq!(*inner_pl.id(), 102);
+                assert_eq!(inner_pl.id(), 102);
 
                 // Inner Playlist Item 1: Album (should be 103)
                 if let QueueItem::Album(album) = &inner_pl.playlist_items[1] {
-                    assert_eq!(*album.id(), 103);
+                    assert_eq!(album.id(), 103);
                     // Check album tracks remain original
                 } else {
                     panic!("Expected Album at index 1 of inner playlist");
@@ -613,7 +681,7 @@ mod tests {
         let next_id = empty_playlist_item.set_id_rec(initial_id);
 
         if let QueueItem::Playlist(pl) = &empty_playlist_item {
-            assert_eq!(*pl.id(), initial_id);
+            assert_eq!(pl.id(), initial_id);
         } else {
             panic!("Expected QueueItem::Playlist");
         }
@@ -637,7 +705,7 @@ mod tests {
 
         // For an Album, set_id_rec behaves like set_id (sets its ID, returns incremented ID)
         if let QueueItem::Album(album) = &album_item {
-            assert_eq!(*album.id(), initial_id);
+            assert_eq!(album.id(), initial_id);
         } else {
             panic!("Expected QueueItem::Album");
         }
@@ -1,7 +1,7 @@
 use log::{error, warn};
 use rand::distributions::{Distribution, WeightedError, WeightedIndex};
 
-use std::{collections::VecDeque, path::PathBuf};
+use std::{collections::VecDeque, fmt::Display};
 
 use crate::queue::DEPTH_LIMIT;
 
@@ -9,13 +9,32 @@ use super::{queue_items::QueueTrack, Queue, QueueItem, QueueOptions, ShuffleType
 
 impl Queue {
     //TODO: make sure all shuffle types work
-    pub(crate) fn next_track(&mut self) -> Option<PathBuf> {
-        // Case: Repeat Current
-        if self.repeat_current && self.current_track.is_some() {
-            return self.current_track.clone();
+    /// Select the next track
+    ///
+    /// Does not look at `repeat_current`, the [`PlaybackDaemon`](crate::playback::PlaybackDaemon)
+    /// replays the current track itself
+    ///
+    /// Returns `None` when the stop condition of the queue is reached,
+    /// the queue starts from the beginning when it is asked again
+    pub(crate) fn next_track(&mut self) -> Option<QueueTrack> {
+        let before_next = self.before_next();
+        let track = self.select_next();
+        self.before_next = Some(Box::new(before_next));
+        track
+    }
+
+    fn select_next(&mut self) -> Option<QueueTrack> {
+        // Going forward after going back replays the tracks, they were already counted
+        if let Some(track) = self.next_forward() {
+            return Some(track);
+        }
+        if self.queue_options.stop_reached() {
+            self.queue_options.restart();
+            return None;
         }
         // Case: Tracks in self.next_up
-        if !self.next_up.iter().all(|x| x.is_empty()) {
+        self.next_up.retain(|x| !x.is_finished());
+        if !self.next_up.is_empty() {
             fn zero(_: usize, _: &mut QueueOptions) -> usize {
                 0
             }
@@ -25,30 +44,28 @@ impl Queue {
                 &mut self.played_items,
                 &mut self.queue_options,
             )?;
-            return Some(track.location().into());
+            self.trim_history();
+            return Some(track);
         }
         // Case: Tracks in self.queue_items
         // Get them with the right randomization, don't remove them
-
-        if !self.queue_items.iter().all(|x| x.is_empty()) {
-            fn decide(
-                length: usize,
-                options: &mut QueueOptions,
-            ) -> Result<Option<usize>, SelectError> {
-                get_random(length, options, &mut rand::thread_rng())
-            }
-            match recurse(decide, &mut self.queue_items, &mut self.queue_options) {
-                Ok(Some(track)) => {
-                    return Some(track.location().into())
-                },
-                Ok(None) => return None,
-                Err(err) => {
-                    error!("{:?}", err);
-                    return None
+        match next_in_list(
+            &mut self.queue_items,
+            &mut self.queue_options,
+            &mut rand::thread_rng(),
+            0,
+        ) {
+            Ok(track) => {
+                if let Some(track) = &track {
+                    self.add_history(track.clone().into());
                 }
+                track
+            }
+            Err(err) => {
+                error!("{err}");
+                None
             }
         }
-        None
     }
 }
 
@@ -72,9 +89,11 @@ fn double_recurse_internal(
         warn!("Reached Depth limit in next_up");
         return None;
     }
+    // Finished items have nothing left to play
+    source.retain(|x| !x.is_finished());
     let chosen = decide(source.len(), options);
-    match source.get(chosen)? {
-        QueueItem::Track(track) => Some(move_track(source, history, track.clone(), chosen)),
+    let track = match source.get(chosen)? {
+        QueueItem::Track(track) => move_track(source, history, track.clone(), chosen),
         QueueItem::Playlist(playlist) => {
             // Check if it exists in history, else add it to history, recurs into it
             let id = playlist.id();
@@ -87,10 +106,10 @@ fn double_recurse_internal(
             };
             let history_id = find_id(history, id, chosen, create_index);
 
-            let (source_list, options) = get_playlist_item(source, chosen)?;
+            let (source_list, child_options) = get_playlist_item(source, chosen)?;
             let (history_list, _) = get_playlist_item(history, history_id)?;
 
-            double_recurse_internal(decide, source_list, history_list, options, depth + 1)
+            double_recurse_internal(decide, source_list, history_list, child_options, depth + 1)?
         }
         QueueItem::Album(album) => {
             // Check if it exists in history, else add it to history, recurs into it
@@ -104,14 +123,17 @@ fn double_recurse_internal(
             };
             let history_id = find_id(history, id, chosen, create_index);
 
-            let (source_list, options) = get_album_item(source, chosen)?;
+            let (source_list, child_options) = get_album_item(source, chosen)?;
             let (history_list, _) = get_album_item(history, history_id)?;
 
-            let chosen = decide(source_list.len(), options);
-            let track = source_list.get(chosen)?.clone();
-            Some(move_track(source_list, history_list, track, chosen))
+            let album_chosen = decide(source_list.len(), child_options);
+            let track = source_list.get(album_chosen)?.clone();
+            child_options.count_played(&track);
+            move_track(source_list, history_list, track, album_chosen)
         }
-    }
+    };
+    options.count_played(&track);
+    Some(track)
 }
 
 /// Remove queue_track from source, and add it to history, also return it
@@ -130,64 +152,117 @@ where
     track
 }
 
-fn recurse(
-    decide: fn(usize, &mut QueueOptions) -> Result<Option<usize>, SelectError>,
-    source: &mut VecDeque<QueueItem>,
-    options: &mut QueueOptions,
-) -> Result<Option<QueueTrack>, SelectError> {
-    recurse_internal(decide, source, options, 0)
+/// Something that a list in the queue can contain
+trait ListItem {
+    /// The next track of this item, `None` if the item is finished
+    fn next_track<R: RandTrack>(
+        &mut self,
+        rng: &mut R,
+        depth: usize,
+    ) -> Result<Option<QueueTrack>, SelectError>;
+    /// The item gave tracks and is not finished, it is continued before a new item is picked
+    fn is_started(&self) -> bool;
+    /// Start the item from the beginning
+    fn restart(&mut self);
 }
 
-fn recurse_internal(
-    decide: fn(usize, &mut QueueOptions) -> Result<Option<usize>, SelectError>,
-    source: &mut VecDeque<QueueItem>,
+impl ListItem for QueueTrack {
+    fn next_track<R: RandTrack>(
+        &mut self,
+        _: &mut R,
+        _: usize,
+    ) -> Result<Option<QueueTrack>, SelectError> {
+        Ok(Some(self.clone()))
+    }
+
+    fn is_started(&self) -> bool {
+        false
+    }
+
+    fn restart(&mut self) {}
+}
+
+impl ListItem for QueueItem {
+    fn next_track<R: RandTrack>(
+        &mut self,
+        rng: &mut R,
+        depth: usize,
+    ) -> Result<Option<QueueTrack>, SelectError> {
+        match self {
+            QueueItem::Track(queue_track) => queue_track.next_track(rng, depth),
+            QueueItem::Playlist(queue_playlist) => next_in_list(
+                &mut queue_playlist.playlist_items,
+                &mut queue_playlist.queue_option,
+                rng,
+                depth + 1,
+            ),
+            QueueItem::Album(queue_album) => next_in_list(
+                &mut queue_album.tracks,
+                &mut queue_album.queue_option,
+                rng,
+                depth + 1,
+            ),
+        }
+    }
+
+    fn is_started(&self) -> bool {
+        match self {
+            QueueItem::Track(_) => false,
+            QueueItem::Playlist(queue_playlist) => queue_playlist.queue_option.selected.is_some(),
+            QueueItem::Album(queue_album) => queue_album.queue_option.selected.is_some(),
+        }
+    }
+
+    fn restart(&mut self) {
+        QueueItem::restart(self)
+    }
+}
+
+/// Get the next track of a list, without removing anything
+///
+/// The selected item is continued until it is finished, only then a new item is picked.
+/// When the list is finished it is restarted, so it can be played again later
+fn next_in_list<T, R>(
+    source: &mut VecDeque<T>,
     options: &mut QueueOptions,
+    rng: &mut R,
     depth: usize,
-) -> Result<Option<QueueTrack>, SelectError> {
+) -> Result<Option<QueueTrack>, SelectError>
+where
+    T: ListItem,
+    R: RandTrack,
+{
     if depth > DEPTH_LIMIT {
         return Err(SelectError::MaxDepthReached);
     }
-    let chosen = match decide(source.len(), options)? {
-        Some(index) => index,
-        // can't do anything with this
-        None => return Ok(None),
-    };
-    let item = match source.get_mut(chosen) {
-        Some(item) => item,
-        //WARN: Error?
-        None => return Ok(None),
-    };
-    match item {
-        QueueItem::Track(queue_track) => Ok(Some(queue_track.clone())),
-        QueueItem::Playlist(queue_playlist) => {
-            // recurse
-            let source = &mut queue_playlist.playlist_items;
-            if source.is_empty() {
-                return Ok(None);
-            }
-            let options = &mut queue_playlist.queue_option;
-            for _ in 0..DEPTH_LIMIT {
-                let result = recurse_internal(decide, source, options, depth + 1)?;
-                // On None we retry
-                if let Some(track) = result {
-                    return Ok(Some(track));
-                }
+    if options.stop_reached() {
+        options.restart();
+        return Ok(None);
+    }
+    // Continue in the selected item
+    if let Some(item) = options.selected.and_then(|index| source.get_mut(index)) {
+        if item.is_started() {
+            if let Some(track) = item.next_track(rng, depth)? {
+                options.count_played(&track);
+                return Ok(Some(track));
             }
-            Ok(None)
         }
-        QueueItem::Album(queue_album) => {
-            let source = &mut queue_album.tracks;
-            let options = &mut queue_album.queue_option;
-            if source.is_empty() {
-                return Ok(None);
-            }
-            let chosen = decide(source.len(), options)?;
-            match chosen {
-                Some(index) => Ok(source.get(index).cloned()),
-                None => Ok(None),
-            }
+    }
+    // Every item gets one chance, so a list of finished items ends
+    for _ in 0..=source.len() {
+        let Some(item) = get_random(source.len(), options, rng)?.and_then(|x| source.get_mut(x))
+        else {
+            break;
+        };
+        options.progress.picked += 1;
+        item.restart();
+        if let Some(track) = item.next_track(rng, depth)? {
+            options.count_played(&track);
+            return Ok(Some(track));
         }
     }
+    options.restart();
+    Ok(None)
 }
 
 fn get_album_item(
@@ -250,6 +325,16 @@ enum SelectError {
     MaxDepthReached,
 }
 
+impl Display for SelectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectError::Weight(err) => write!(f, "Could not pick a weighted item: {err}"),
+            SelectError::SizeError => write!(f, "The weights don't match the length of the list"),
+            SelectError::MaxDepthReached => write!(f, "The playlists are nested too deep"),
+        }
+    }
+}
+
 impl From<WeightedError> for SelectError {
     fn from(value: WeightedError) -> Self {
         SelectError::Weight(value)
@@ -266,6 +351,16 @@ where
     R: RandTrack,
 {
     check_weight_length(&options.shuffle_type, list_len)?;
+    if list_len == 0 {
+        return Ok(None);
+    }
+    // A shuffled list has no end, so it stops after as many items as it has
+    if options.shuffle_type != ShuffleType::None
+        && !options.loops()
+        && options.progress.picked >= list_len
+    {
+        return Ok(None);
+    }
     let chosen = match &mut options.shuffle_type {
         ShuffleType::None => match options.selected {
             Some(index) => {
@@ -273,6 +368,8 @@ where
                 let next = index + 1;
                 if next < list_len {
                     Some(next)
+                } else if options.loops() {
+                    Some(0)
                 } else {
                     None
                 }
@@ -318,7 +415,7 @@ fn increase_weights(weights: &mut [usize], chosen: usize) -> usize {
 }
 
 fn check_weight_length(shuffle: &ShuffleType, list_len: usize) -> Result<(), SelectError> {
-    fn check(list: &Vec<usize>, list_len: usize) -> Result<(), SelectError> {
+    fn check(list: &[usize], list_len: usize) -> Result<(), SelectError> {
         if list.len() != list_len {
             Err(SelectError::SizeError)
         } else {
@@ -357,3 +454,121 @@ where
         Ok(chosen)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::models::{Release, Track};
+    use crate::queue::queue_items::QueueAlbum;
+    use crate::queue::StopCondition;
+
+    /// A track of a minute
+    fn dummy_queue_track(id: i32) -> QueueTrack {
+        QueueTrack::new(
+            Track {
+                id,
+                name: format!("Track {}", id),
+                date: NaiveDate::default(),
+                number: 0,
+                duration: 60,
+                artist_id: 0,
+                release_id: 0,
+                replay_gain: None,
+                replay_peak: None,
+                loudness: None,
+                loudness_range: None,
+                true_peak: None,
+            },
+            PathBuf::from(format!("/path/to/track_{}.mp3", id)),
+        )
+    }
+
+    fn dummy_queue_album(id: i32, num_tracks: usize) -> QueueAlbum {
+        let tracks = (0..num_tracks)
+            .map(|i| dummy_queue_track(id * 100 + i as i32))
+            .collect();
+        QueueAlbum::new(
+            Release {
+                id,
+                name: format!("Album {}", id),
+                release_type: None,
+                date: NaiveDate::default(),
+                publisher_id: None,
+                artist_id: 0,
+                gapless: false,
+                replay_gain: None,
+                replay_peak: None,
+                loudness: None,
+                loudness_range: None,
+                true_peak: None,
+            },
+            tracks,
+            Default::default(),
+        )
+    }
+
+    /// The ids of the tracks the queue gives until it stops
+    fn played_ids(queue: &mut Queue, max: usize) -> Vec<i32> {
+        let mut ids = vec![];
+        while let Some(track) = queue.next_track() {
+            ids.push(track.track().id);
+            if ids.len() >= max {
+                break;
+            }
+        }
+        ids
+    }
+
+    fn queue_of_tracks(ids: &[i32]) -> Queue {
+        let mut queue = Queue::new();
+        for id in ids {
+            queue.append_queue_item(dummy_queue_track(*id), false);
+        }
+        queue
+    }
+
+    #[test]
+    fn stops_at_end_of_queue() {
+        let mut queue = queue_of_tracks(&[1, 2, 3]);
+        assert_eq!(played_ids(&mut queue, 10), vec![1, 2, 3]);
+        // Asked again it starts from the beginning
+        assert_eq!(played_ids(&mut queue, 1), vec![1]);
+    }
+
+    #[test]
+    fn finished_album_hands_back_to_parent() {
+        let mut queue = Queue::new();
+        queue.append_queue_item(dummy_queue_album(1, 2), false);
+        queue.append_queue_item(dummy_queue_track(7), false);
+        queue.append_queue_item(dummy_queue_album(2, 1), false);
+        assert_eq!(played_ids(&mut queue, 10), vec![100, 101, 7, 200]);
+    }
+
+    #[test]
+    fn stops_after_amount_of_tracks() {
+        let mut queue = queue_of_tracks(&[1, 2]);
+        queue.queue_options.stop_condition = StopCondition::AmountTracks(5);
+        assert_eq!(played_ids(&mut queue, 10), vec![1, 2, 1, 2, 1]);
+    }
+
+    #[test]
+    fn album_stops_after_amount_of_tracks() {
+        let mut album = dummy_queue_album(1, 3);
+        album.queue_option.stop_condition = StopCondition::AmountTracks(2);
+        let mut queue = Queue::new();
+        queue.append_queue_item(album, false);
+        queue.append_queue_item(dummy_queue_track(7), false);
+        assert_eq!(played_ids(&mut queue, 10), vec![100, 101, 7]);
+    }
+
+    #[test]
+    fn stops_after_time() {
+        let mut queue = queue_of_tracks(&[1, 2]);
+        // Two and a half minutes, the third track starts before the time is reached
+        queue.queue_options.stop_condition = StopCondition::Time(150_000);
+        assert_eq!(played_ids(&mut queue, 10), vec![1, 2, 1]);
+    }
+}
@@ -54,6 +54,54 @@ diesel::table! {
         date -> Date,
         publisher_id -> Nullable<Integer>,
         artist_id -> Integer,
+        gapless -> Bool,
+        replay_gain -> Nullable<Float>,
+        replay_peak -> Nullable<Float>,
+        loudness -> Nullable<Float>,
+        loudness_range -> Nullable<Float>,
+        true_peak -> Nullable<Float>,
+    }
+}
+
+diesel::table! {
+    saved_queue (id) {
+        id -> Integer,
+        options_id -> Integer,
+        repeat_current -> Bool,
+        max_history -> Integer,
+        next_id -> BigInt,
+        current_track -> Nullable<Text>,
+        position -> BigInt,
+    }
+}
+
+diesel::table! {
+    saved_queue_items (id) {
+        id -> Integer,
+        list -> Integer,
+        parent_id -> Nullable<Integer>,
+        number -> Integer,
+        item_type -> Integer,
+        queue_id -> BigInt,
+        track_id -> Nullable<Integer>,
+        release_id -> Nullable<Integer>,
+        playlist_id -> Nullable<Integer>,
+        options_id -> Nullable<Integer>,
+    }
+}
+
+diesel::table! {
+    saved_queue_options (id) {
+        id -> Integer,
+        shuffle_type -> Integer,
+        weights -> Text,
+        default_weights -> Text,
+        stop_condition -> Integer,
+        stop_value -> BigInt,
+        selected -> Nullable<Integer>,
+        progress_tracks -> Integer,
+        progress_time -> BigInt,
+        progress_picked -> Integer,
     }
 }
 
@@ -66,12 +114,18 @@ diesel::table! {
         duration -> Integer,
         artist_id -> Integer,
         release_id -> Integer,
+        replay_gain -> Nullable<Float>,
+        replay_peak -> Nullable<Float>,
+        loudness -> Nullable<Float>,
+        loudness_range -> Nullable<Float>,
+        true_peak -> Nullable<Float>,
     }
 }
 
 diesel::table! {
-    track_locations (path) {
+    track_locations (path, audio_track) {
         path -> Text,
+        audio_track -> Integer,
         track_id -> Integer,
     }
 }
@@ -82,6 +136,11 @@ diesel::joinable!(playlist_items -> releases (item_release_id));
 diesel::joinable!(playlist_items -> tracks (item_track_id));
 diesel::joinable!(releases -> artists (artist_id));
 diesel::joinable!(releases -> publishers (publisher_id));
+diesel::joinable!(saved_queue -> saved_queue_options (options_id));
+diesel::joinable!(saved_queue_items -> playlists (playlist_id));
+diesel::joinable!(saved_queue_items -> releases (release_id));
+diesel::joinable!(saved_queue_items -> saved_queue_options (options_id));
+diesel::joinable!(saved_queue_items -> tracks (track_id));
 diesel::joinable!(tracks -> artists (artist_id));
 diesel::joinable!(tracks -> releases (release_id));
 diesel::joinable!(track_locations -> tracks (track_id));
@@ -93,6 +152,9 @@ diesel::allow_tables_to_appear_in_same_query!(
     playlist_items,
     publishers,
     releases,
+    saved_queue,
+    saved_queue_items,
+    saved_queue_options,
     tracks,
     track_locations,
 );